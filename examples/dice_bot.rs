@@ -0,0 +1,88 @@
+//! A server with two small plugins: a dice roller answering `!roll NdM` in
+//! any chat, and a greeter. The same dice can be rolled with `/roll NdM`,
+//! a command added to the server's own.
+//!
+//!     cargo run --example dice_bot -- 127.0.0.1:8080
+
+extern crate chat_np1;
+
+use std::time::{SystemTime, UNIX_EPOCH};
+use chat_np1::chatserver::{Action, Command, Context, Permission, Plugin, ServerBuilder};
+
+const USAGE: &str = "Usage: !roll NdM, with N up to 20 and M from 2 to 1000";
+
+struct Dice {
+    seed: u64,
+}
+
+impl Dice {
+    /// xorshift; good enough for dice.
+    fn next(&mut self, sides: u64) -> u64 {
+        self.seed ^= self.seed << 13;
+        self.seed ^= self.seed >> 7;
+        self.seed ^= self.seed << 17;
+        self.seed % sides + 1
+    }
+
+    /// Rolls dice as `spec` ("NdM") says, one six-sided die by default.
+    fn roll(&mut self, spec: &str) -> Option<Vec<u64>> {
+        let (count, sides) = spec.split_once('d').unwrap_or(("1", "6"));
+        match (count.parse::<u64>().unwrap_or(1), sides.parse::<u64>()) {
+            (count @ 1..=20, Ok(sides @ 2..=1000)) =>
+                Some((0..count).map(|_| self.next(sides)).collect()),
+            _ => None,
+        }
+    }
+}
+
+fn announce(user: &str, rolls: &[u64]) -> String {
+    format!("{} rolled {:?} = {}", user, rolls, rolls.iter().sum::<u64>())
+}
+
+impl Plugin for Dice {
+    fn on_chat(&mut self, ctx: &mut Context, user: &str, room: &str,
+               contents: &mut String) -> Action {
+        let spec = match contents.strip_prefix("!roll") {
+            Some(spec) => spec.trim(),
+            None => return Action::Continue,
+        };
+        match self.roll(spec) {
+            Some(rolls) => {
+                ctx.say(room, "dice", announce(user, &rolls));
+                Action::Continue
+            },
+            None => {
+                ctx.reply(USAGE);
+                Action::Block
+            },
+        }
+    }
+}
+
+struct Greeter;
+
+impl Plugin for Greeter {
+    fn on_connect(&mut self, ctx: &mut Context, user: &str) {
+        ctx.reply(format!("Welcome, {}! Try !roll 2d6 in a chat.", user));
+    }
+}
+
+fn main() {
+    let addr = std::env::args().nth(1).unwrap_or_else(|| "127.0.0.1:8080".to_owned());
+    let seed = SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0) | 1;
+    let mut dice = Dice { seed: seed.rotate_left(32) | 1 };
+    let roll = Command::new("roll", "[dice]", "roll dice, e.g. 2d6", Permission::Member,
+                            move |ctx, call| {
+        let rolls = dice.roll(call.arg(0)).ok_or_else(|| USAGE.replace('!', "/"))?;
+        let room = call.room.as_ref().expect("members always have a room");
+        ctx.say(room, "dice", announce(&call.user, &rolls));
+        Ok(())
+    });
+    ServerBuilder::new()
+        .plugin(Dice { seed })
+        .plugin(Greeter)
+        .command(roll)
+        .start(addr);
+}
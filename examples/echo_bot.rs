@@ -0,0 +1,60 @@
+//! A headless client that sits in Chat1, repeats whatever follows `!echo`
+//! and answers private messages with how many it has seen. Given a
+//! directory, it logs its chats there.
+//!
+//!     cargo run --example echo_bot -- 127.0.0.1:8080 [LOG DIR]
+
+extern crate chat_np1;
+
+use chat_np1::chatclient::{ChatConnection, ChatLog};
+use chat_np1::chatclient::bot::{self, Bot};
+use chat_np1::message::FailureCode;
+use chat_np1::transport::TcpConnector;
+
+#[derive(Default)]
+struct Echo {
+    privates: usize,
+    log: Option<String>,
+}
+
+impl Bot for Echo {
+    fn on_connect(&mut self, chat: &mut ChatConnection) {
+        if let Some(dir) = self.log.take() {
+            if let Err(e) = chat.log_to(ChatLog::new(dir)) {
+                eprintln!("Not logging: {}", e);
+            }
+        }
+        let _ = chat.join_chat("Chat1".to_owned());
+    }
+
+    fn on_message(&mut self, chat: &mut ChatConnection, _room: &str, user: &str, text: &str) {
+        if let Some(rest) = text.strip_prefix("!echo ") {
+            let _ = chat.send_public_message(format!("{} said: {}", user, rest));
+        }
+    }
+
+    fn on_private(&mut self, chat: &mut ChatConnection, from: &str, _text: &str) {
+        self.privates += 1;
+        let reply = format!("I've had {} private messages so far", self.privates);
+        let _ = chat.send_private(from.to_owned(), reply);
+    }
+
+    fn on_failure(&mut self, _chat: &mut ChatConnection, _code: FailureCode, reason: &str) {
+        eprintln!("Server refused: {}", reason);
+    }
+
+    fn on_disconnect(&mut self, _chat: &mut ChatConnection, reason: &str) {
+        eprintln!("{}", reason);
+    }
+}
+
+fn main() {
+    let mut args = std::env::args().skip(1);
+    let addr = args.next().unwrap_or_else(|| "127.0.0.1:8080".to_owned());
+    let connector = TcpConnector::new(addr.as_str(), None).expect("invalid address");
+    let mut echo = Echo { log: args.next(), ..Echo::default() };
+    if let Err(e) = bot::run("echo", connector, &mut echo) {
+        eprintln!("Couldn't connect: {}", e);
+        std::process::exit(1);
+    }
+}
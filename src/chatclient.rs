@@ -1,143 +1,488 @@
-use std::io::{BufReader, BufRead, Write};
-use std::net::{ToSocketAddrs, TcpStream};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::net::ToSocketAddrs;
 use std::sync::mpsc;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::thread;
-use message::Message;
+use std::time::Duration;
+use message::{FailureCode, Message};
+use tls::ClientTls;
+use transport::{Connector, TcpConnector, Transport};
+
+pub mod bot;
+mod event;
+mod log;
+
+pub use self::event::ClientEvent;
+pub use self::log::{ChatLog, LogFormat};
+use self::event::Translator;
+
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+/// How long a request waits for the server's answer.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Requests waiting for an answer, by id; filled in by the listening
+/// thread.
+type Pending = Arc<Mutex<HashMap<String, mpsc::Sender<Result<(), (FailureCode, String)>>>>>;
+
+/// The log, if any, shared with the listening thread.
+type SharedLog = Arc<Mutex<Option<ChatLog>>>;
 
-#[derive(Debug)]
 pub struct ChatConnection {
     username: String,
     pub chat_name: String,
-    socket: TcpStream,
-    thread: thread::JoinHandle<()>
+    /// Sending handle of the current transport; replaced on every reconnect.
+    socket: Arc<Mutex<Box<dyn Transport>>>,
+    terminate: Arc<AtomicBool>,
+    pending: Pending,
+    next_request: u64,
+    log: SharedLog,
+    thread: Option<thread::JoinHandle<()>>
+}
+
+/// Why a request didn't go through.
+#[derive(Debug)]
+pub enum RequestError {
+    /// The server refused it, for this reason.
+    Refused(FailureCode, String),
+    /// It couldn't be sent, or no answer came in time.
+    Io(io::Error),
+}
+
+impl fmt::Display for RequestError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RequestError::Refused(_, ref reason) => f.write_str(reason),
+            RequestError::Io(ref e) => e.fmt(f),
+        }
+    }
+}
+
+impl Error for RequestError {}
+
+impl From<io::Error> for RequestError {
+    fn from(e: io::Error) -> RequestError {
+        RequestError::Io(e)
+    }
+}
+
+impl fmt::Debug for ChatConnection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ChatConnection")
+            .field("username", &self.username)
+            .field("chat_name", &self.chat_name)
+            .finish()
+    }
+}
+
+/// What the listening thread needs to know to bring the connection back
+/// after it drops.
+struct Session {
+    username: String,
+    connector: Box<dyn Connector>,
+    token: Option<String>,
+    chat_name: Option<String>,
 }
 
 impl ChatConnection {
+    /// Connects and starts listening in the background. What happens is
+    /// sent through `callback_channel` as `ClientEvent`s, converted into
+    /// whatever event type the caller waits on.
     pub fn connect<S: Into<String>,
-                   A: ToSocketAddrs>(username: S,
-                                     addr: A,
-                                     callback_channel: mpsc::Sender<Message>,
-                                     terminate: Arc<AtomicBool>)     -> ChatConnection {
-        let mut socket = TcpStream::connect(addr).expect("Failed connecting to chat");
+                   A: ToSocketAddrs,
+                   T: From<ClientEvent> + Send + 'static>(username: S,
+                                                          addr: A,
+                                                          callback_channel: mpsc::Sender<T>,
+                                                          terminate: Arc<AtomicBool>)
+                                                          -> ChatConnection {
+        let connector = TcpConnector::new(addr, None).expect("Failed resolving chat address");
+        Self::connect_with(username, connector, callback_channel, terminate)
+    }
+
+    /// Same as `connect`, over TLS.
+    pub fn connect_tls<S: Into<String>,
+                       A: ToSocketAddrs,
+                       T: From<ClientEvent> + Send + 'static>(username: S,
+                                                              addr: A,
+                                                              tls: ClientTls,
+                                                              callback_channel: mpsc::Sender<T>,
+                                                              terminate: Arc<AtomicBool>)
+                                                              -> ChatConnection {
+        let connector = TcpConnector::new(addr, Some(tls))
+            .expect("Failed resolving chat address");
+        Self::connect_with(username, connector, callback_channel, terminate)
+    }
+
+    /// Same as `connect`, over whatever transport `connector` opens, e.g. a
+    /// Unix socket or an in-process channel to a server in this program.
+    pub fn connect_with<S: Into<String>,
+                        C: Connector + 'static,
+                        T: From<ClientEvent> + Send + 'static>(username: S,
+                                                               connector: C,
+                                                               callback_channel: mpsc::Sender<T>,
+                                                               terminate: Arc<AtomicBool>)
+                                                               -> ChatConnection {
+        Self::open(username, connector, callback_channel, terminate)
+            .expect("Failed connecting to chat")
+    }
+
+    /// `connect_with`, returning the error instead of panicking.
+    pub fn open<S: Into<String>,
+                C: Connector + 'static,
+                T: From<ClientEvent> + Send + 'static>(username: S,
+                                                       connector: C,
+                                                       callback_channel: mpsc::Sender<T>,
+                                                       terminate: Arc<AtomicBool>)
+                                                       -> io::Result<ChatConnection> {
+        let mut transport = connector.connect()?;
         let username = username.into();
-        socket.write(&Message::init_user(username.clone()).into_bytes())
-            .unwrap();
-        let read_socket = socket.try_clone().unwrap();
-        let thread = Self::start_listening(callback_channel,read_socket, terminate);
-        ChatConnection {
+        transport.send(&Message::init_user(username.clone()))?;
+        let reader = transport.try_clone()?;
+        let socket = Arc::new(Mutex::new(transport));
+        let pending = Pending::default();
+        let log = SharedLog::default();
+        let session = Session {
+            username: username.clone(),
+            connector: Box::new(connector),
+            token: None,
+            chat_name: None,
+        };
+        let thread = Self::start_listening(callback_channel,
+                                           reader,
+                                           socket.clone(),
+                                           pending.clone(),
+                                           log.clone(),
+                                           session,
+                                           terminate.clone());
+        Ok(ChatConnection {
             username,
             chat_name: "".to_owned(),
             socket,
-            thread,
-        }        
-    }
-
-    pub fn start_listening(sender: mpsc::Sender<Message>,
-                           socket:TcpStream,
-                           terminate: Arc<AtomicBool>) -> thread::JoinHandle<()> {
-        let sender = sender.clone();
-        thread::spawn(move || {            
-            let mut buf = Vec::new();
-            let mut reader = BufReader::new(socket);
-            let mut read_bytes = 0;
+            terminate,
+            pending,
+            next_request: 0,
+            log,
+            thread: Some(thread),
+        })
+    }
+
+    fn start_listening<T: From<ClientEvent> + Send + 'static>(sender: mpsc::Sender<T>,
+                       mut reader: Box<dyn Transport>,
+                       write_socket: Arc<Mutex<Box<dyn Transport>>>,
+                       pending: Pending,
+                       log: SharedLog,
+                       mut session: Session,
+                       terminate: Arc<AtomicBool>) -> thread::JoinHandle<()> {
+        thread::spawn(move || {
+            let mut translator = Translator::default();
             'listen: loop {
-                buf.clear();
-                match reader.read_until(b'\n', &mut buf) {
-                    Ok(b) => read_bytes+=b,
-                    Err(e) => {
-                        eprintln!("{}", e);
-                        terminate.store(true, Ordering::Relaxed)
+                let read = reader.recv();
+                if terminate.load(Ordering::Relaxed) {
+                    break 'listen;
+                }
+                let lost = match read {
+                    Ok(None) => "Connection with server lost".to_owned(),
+                    Ok(Some(message)) => {
+                        if Self::answer_request(&pending, &message) {
+                            continue;
+                        }
+                        let terminated = matches!(message, Message::ConnectionTermination(_));
+                        let events = translator.translate(message.clone(),
+                                                          session.chat_name.as_deref());
+                        Self::track_session(&mut session, &write_socket, message);
+                        if let Some(ref log) = *log.lock().unwrap() {
+                            events.iter().for_each(|e| log.event(e));
+                        }
+                        for event in events {
+                            if sender.send(event.into()).is_err() {
+                                break 'listen;
+                            }
+                        }
+                        if terminated {
+                            break 'listen;
+                        }
+                        continue;
                     },
+                    Err(ref e) if e.kind() == io::ErrorKind::InvalidData => {
+                        let event = ClientEvent::Error {
+                            code: FailureCode::InvalidMessage,
+                            reason: format!("Failed parsing message: {}", e),
+                        };
+                        if sender.send(event.into()).is_err() {
+                            break 'listen;
+                        }
+                        continue;
+                    },
+                    Err(e) => e.to_string(),
                 };
-                if read_bytes > 0 {
-                    let message = Message::from_bytes(&buf)
-                        .expect("Failed parsing message");
-                    sender.send(message)
-                        .expect("Failed sending message through channel");
-                };
-                let terminated = terminate.load(Ordering::Relaxed);
-                if terminated {               
+                let left = translator.flush();
+                if let (Some(ref log), Some(ref left)) = (&*log.lock().unwrap(), &left) {
+                    log.event(left);
+                }
+                let events = left.into_iter()
+                    .chain(Some(ClientEvent::Disconnected { reason: lost, reconnecting: true }));
+                for event in events {
+                    if sender.send(event.into()).is_err() {
+                        break 'listen;
+                    }
+                }
+                match Self::reconnect(&session, &write_socket, &terminate) {
+                    Some(r) => reader = r,
+                    None => break 'listen,
+                }
+                if sender.send(ClientEvent::Reconnected.into()).is_err() {
                     break 'listen;
                 }
             }
         })
     }
-      
-    pub fn send_private_message(&mut self, contents: String) {
-        let to = contents.split(' ').nth(0).unwrap()[1..].to_owned();
+
+    /// Hands the server's answer to the request waiting for it, returning
+    /// whether `message` was one.
+    fn answer_request(pending: &Pending, message: &Message) -> bool {
+        let (id, answer) = match *message {
+            Message::Done(ref id) => (id, Ok(())),
+            Message::Failure(code, ref reason, ref id) if !id.is_empty() =>
+                (id, Err((code, reason.clone()))),
+            _ => return false,
+        };
+        match pending.lock().unwrap().remove(id) {
+            Some(waiting) => {
+                let _ = waiting.send(answer);
+                true
+            },
+            // Gave up waiting; a late failure still gets reported.
+            None => answer.is_ok(),
+        }
+    }
+
+    /// Keeps track of the resume token and current chat as they go by.
+    fn track_session(session: &mut Session,
+                     write_socket: &Arc<Mutex<Box<dyn Transport>>>,
+                     message: Message) {
+        match message {
+            Message::Session(token) => {
+                let resumed = session.token.as_ref() == Some(&token);
+                session.token = Some(token);
+                if !resumed {
+                    if let Some(ref chat_name) = session.chat_name {
+                        let login = Message::login(session.username.clone(),
+                                                   chat_name.clone());
+                        let _ = write_socket.lock().unwrap().send(&login);
+                    }
+                }
+            },
+            Message::Joined(c) => session.chat_name = Some(c),
+            Message::Logout(_, ref c) if c.is_empty() || session.chat_name.as_ref() == Some(c) =>
+                session.chat_name = None,
+            _ => (),
+        }
+    }
+
+    /// Connects again with exponential backoff, then either resumes the
+    /// previous session or starts a new one. Gives up only when asked to
+    /// terminate.
+    fn reconnect(session: &Session,
+                 write_socket: &Arc<Mutex<Box<dyn Transport>>>,
+                 terminate: &Arc<AtomicBool>) -> Option<Box<dyn Transport>> {
+        let mut backoff = INITIAL_BACKOFF;
+        loop {
+            if terminate.load(Ordering::Relaxed) {
+                return None;
+            }
+            thread::park_timeout(backoff);
+            if terminate.load(Ordering::Relaxed) {
+                return None;
+            }
+            backoff = ::std::cmp::min(backoff * 2, MAX_BACKOFF);
+
+            let mut transport = match session.connector.connect() {
+                Ok(t) => t,
+                Err(_) => continue,
+            };
+            let handshake = match session.token {
+                Some(ref token) => Message::resume(session.username.clone(),
+                                                   token.clone()),
+                None => Message::init_user(session.username.clone()),
+            };
+            if transport.send(&handshake).is_err() {
+                continue;
+            }
+            let reader = match transport.try_clone() {
+                Ok(r) => r,
+                Err(_) => continue,
+            };
+            *write_socket.lock().unwrap() = transport;
+            return Some(reader);
+        }
+    }
+
+    /// Sends "@user contents", as typed in the client, to `user`: the
+    /// contents alone, without the "@user" in front.
+    pub fn send_private_message(&mut self, line: String) -> io::Result<()> {
+        let (to, contents) = line.strip_prefix('@')
+            .and_then(|l| l.split_once(' '))
+            .filter(|&(to, contents)| !to.is_empty() && !contents.trim().is_empty())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput,
+                                          "Private messages go as @user text"))?;
+        self.send_private(to.to_owned(), contents.trim_start().to_owned())
+    }
+
+    pub fn send_private(&mut self, to: String, contents: String) -> io::Result<()> {
         let message = Message::private_message(self.username.clone(),
-                                               to,
-                                               contents);
-        self.send_to_server(message);
+                                               to.clone(),
+                                               contents.clone());
+        self.send_to_server(message)?;
+        if let Some(ref log) = *self.log.lock().unwrap() {
+            log.sent_private(&to, &contents);
+        }
+        Ok(())
     }
 
-    pub fn send_public_message(&mut self, contents: String) {
-        if self.chat_name == "" {
-            println!("Must join chat to send messages");
-            return;
+    pub fn send_public_message(&mut self, contents: String) -> io::Result<()> {
+        if self.chat_name.is_empty() {
+            return Err(not_in_chat("Must join chat to send messages"));
         }
         let message = Message::chat_message(self.username.clone(),
                                            self.chat_name.clone(),
-                                           contents);
-        self.send_to_server(message);
+                                           contents.clone());
+        self.send_to_server(message)?;
+        if let Some(ref log) = *self.log.lock().unwrap() {
+            log.sent(&self.chat_name, &contents);
+        }
+        Ok(())
     }
 
-    pub fn request_groups(&mut self) {
+    pub fn request_groups(&mut self) -> io::Result<()> {
         let message = Message::ListGroups(self.username.clone());
-        self.send_to_server(message);
+        self.send_to_server(message)
     }
 
-    pub fn request_clients(&mut self) {
-        if self.chat_name == "" {
-            println!("Must join chat to see who's online");
-            return;
+    pub fn request_clients(&mut self) -> io::Result<()> {
+        if self.chat_name.is_empty() {
+            return Err(not_in_chat("Must join chat to see who's online"));
         }
         let message = Message::ListUsers(self.username.clone(),
                                          self.chat_name.clone());
-        self.send_to_server(message);
+        self.send_to_server(message)
     }
 
-    pub fn create_chat(&mut self, chat_name: String) {
+    /// These wait for the server's answer to say whether they worked.
+    pub fn create_chat(&mut self, chat_name: String) -> Result<(), RequestError> {
         let message = Message::NewChat(self.username.clone(),
                                        chat_name);
-        self.send_to_server(message);
+        self.request(message)
     }
 
-    pub fn join_chat(&mut self, chat_name: String) {
-        if &self.chat_name != "" {
-            self.leave_chat();
-        }
+    /// Leaves the current chat only once in the new one, so a failed join
+    /// leaves the user where they were.
+    pub fn join_chat(&mut self, chat_name: String) -> Result<(), RequestError> {
+        let previous = self.chat_name.clone();
         let message = Message::Login(self.username.clone(), chat_name);
-        self.send_to_server(message);
+        self.request(message)?;
+        if previous.is_empty() {
+            return Ok(());
+        }
+        let message = Message::logout(self.username.clone(), previous);
+        self.request(message)
     }
 
-    pub fn leave_chat(&mut self) {
-        if &self.chat_name == "" {
-            println!("Must be in chat to leave");
-            return
+    pub fn leave_chat(&mut self) -> Result<(), RequestError> {
+        if self.chat_name.is_empty() {
+            return Err(not_in_chat("Must be in chat to leave").into());
         }
         let message = Message::logout(self.username.clone(), self.chat_name.clone());
-        self.send_to_server(message);
+        self.request(message)
     }
 
-    pub fn kick(&mut self, target: String) {
+    pub fn kick(&mut self, target: String) -> Result<(), RequestError> {
         let message = Message::kick_user(self.username.clone(),
                                          self.chat_name.clone(),
                                          target);
-        self.send_to_server(message);
+        self.request(message)
+    }
+
+    /// Sends `message` tagged with a new id, and waits for the answer the
+    /// server tags with the same id.
+    pub fn request(&mut self, message: Message) -> Result<(), RequestError> {
+        self.next_request += 1;
+        let id = self.next_request.to_string();
+        let (waiting, answer) = mpsc::channel();
+        self.pending.lock().unwrap().insert(id.clone(), waiting);
+        let result = match self.send_to_server(Message::request(id.clone(), message)) {
+            Ok(()) => match answer.recv_timeout(REQUEST_TIMEOUT) {
+                Ok(answer) => answer.map_err(|(code, reason)| RequestError::Refused(code, reason)),
+                Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut,
+                                             "No answer from server").into()),
+            },
+            Err(e) => Err(e.into()),
+        };
+        self.pending.lock().unwrap().remove(&id);
+        result
+    }
+
+    /// Sends a slash command, without the slash, for the server to run.
+    pub fn send_command(&mut self, line: String) -> io::Result<()> {
+        let message = Message::command(self.username.clone(), self.chat_name.clone(), line);
+        self.send_to_server(message)
     }
 
-    fn send_to_server(&mut self, m: Message) {
-        self.socket.write(&m.into_bytes()).unwrap();
-        self.socket.flush().unwrap();
+    /// Starts logging this user's chats and private messages as `log`
+    /// says, from now on; fails if its directory can't be made.
+    pub fn log_to(&mut self, mut log: ChatLog) -> io::Result<()> {
+        log.open(&self.username)?;
+        *self.log.lock().unwrap() = Some(log);
+        Ok(())
     }
+
+    pub fn username(&self) -> &str {
+        &self.username
+    }
+
+    /// Makes the listening thread stop; the connection closes when dropped.
+    pub fn quit(&self) {
+        self.terminate.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_quitting(&self) -> bool {
+        self.terminate.load(Ordering::Relaxed)
+    }
+
+    /// Keeps `chat_name` in step with what the server says.
+    pub fn track(&mut self, event: &ClientEvent) {
+        match *event {
+            ClientEvent::Joined(ref c) => self.chat_name = c.clone(),
+            ClientEvent::Left(ref c) | ClientEvent::Kicked(ref c) if *c == self.chat_name =>
+                self.chat_name.clear(),
+            _ => (),
+        }
+    }
+
+    fn send_to_server(&mut self, m: Message) -> io::Result<()> {
+        m.check_fields()?;
+        self.socket.lock().unwrap().send(&m)
+            .map_err(|_| io::Error::new(io::ErrorKind::NotConnected,
+                                        "Not connected to server, message not sent"))
+    }
+}
+
+fn not_in_chat(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, reason)
 }
 
 impl Drop for ChatConnection {
     fn drop(&mut self) {
         let username = self.username.clone();
-        self.send_to_server(Message::ConnectionTermination(username));
+        let _ = self.send_to_server(Message::ConnectionTermination(username));
+        self.terminate.store(true, Ordering::Relaxed);
+        let _ = self.socket.lock().unwrap().shutdown();
+        if let Some(thread) = self.thread.take() {
+            thread.thread().unpark();
+            let _ = thread.join();
+        }
     }
 }
@@ -0,0 +1,84 @@
+//! Headless clients: implement `Bot` and hand it to `run`, which connects,
+//! calls the hooks as things happen and returns once the connection is
+//! done with. Nothing is printed; what a bot logs is up to it.
+
+use std::io;
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+use message::FailureCode;
+use transport::Connector;
+use super::{ChatConnection, ClientEvent};
+
+/// Every hook does nothing by default. `chat` is the bot's own
+/// connection, for answering; its `chat_name` is kept up to date.
+pub trait Bot {
+    /// Called once connected, e.g. to join a chat.
+    fn on_connect(&mut self, _chat: &mut ChatConnection) {}
+
+    /// A message in the bot's chat, from someone else.
+    fn on_message(&mut self, _chat: &mut ChatConnection, _room: &str, _user: &str,
+                  _text: &str) {}
+
+    fn on_private(&mut self, _chat: &mut ChatConnection, _from: &str, _text: &str) {}
+
+    /// Something the server said to the bot alone, like a command's answer.
+    fn on_notice(&mut self, _chat: &mut ChatConnection, _text: &str) {}
+
+    fn on_join(&mut self, _chat: &mut ChatConnection, _room: &str) {}
+
+    fn on_leave(&mut self, _chat: &mut ChatConnection, _room: &str) {}
+
+    fn on_kicked(&mut self, _chat: &mut ChatConnection, _room: &str) {}
+
+    /// Something the bot asked for was refused.
+    fn on_failure(&mut self, _chat: &mut ChatConnection, _code: FailureCode, _reason: &str) {}
+
+    /// The connection dropped; it is brought back on its own, after which
+    /// `on_reconnect` is called.
+    fn on_disconnect(&mut self, _chat: &mut ChatConnection, _reason: &str) {}
+
+    fn on_reconnect(&mut self, _chat: &mut ChatConnection) {}
+}
+
+/// Connects as `username` and runs `bot` until the server ends the
+/// connection or the bot calls `ChatConnection::quit`.
+pub fn run<S, C, B>(username: S, connector: C, bot: &mut B) -> io::Result<()>
+    where S: Into<String>, C: Connector + 'static, B: Bot
+{
+    let (sender, events) = mpsc::channel();
+    let terminate = Arc::new(AtomicBool::new(false));
+    let mut chat = ChatConnection::open(username, connector, sender, terminate)?;
+    bot.on_connect(&mut chat);
+    for event in events.iter() {
+        if chat.is_quitting() {
+            break;
+        }
+        chat.track(&event);
+        if dispatch(bot, &mut chat, event) {
+            break;
+        }
+        if chat.is_quitting() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Calls the hook for `event`, returning whether the server is done with
+/// the bot.
+fn dispatch<B: Bot>(bot: &mut B, chat: &mut ChatConnection, event: ClientEvent) -> bool {
+    match event {
+        ClientEvent::Notice(text) => bot.on_notice(chat, &text),
+        ClientEvent::Message { room, user, text } => bot.on_message(chat, &room, &user, &text),
+        ClientEvent::Private { from, text } => bot.on_private(chat, &from, &text),
+        ClientEvent::Joined(room) => bot.on_join(chat, &room),
+        ClientEvent::Left(room) => bot.on_leave(chat, &room),
+        ClientEvent::Kicked(room) => bot.on_kicked(chat, &room),
+        ClientEvent::Error { code, reason } => bot.on_failure(chat, code, &reason),
+        ClientEvent::Disconnected { reconnecting: false, .. } => return true,
+        ClientEvent::Disconnected { reason, .. } => bot.on_disconnect(chat, &reason),
+        ClientEvent::Reconnected => bot.on_reconnect(chat),
+    }
+    false
+}
@@ -0,0 +1,80 @@
+use message::{FailureCode, Message};
+
+/// Everything a `ChatConnection` reports, with the protocol's quirks
+/// already sorted out.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ClientEvent {
+    /// Something the server said to this user alone, like the answer to a
+    /// command.
+    Notice(String),
+    Message { room: String, user: String, text: String },
+    Private { from: String, text: String },
+    Joined(String),
+    Left(String),
+    Kicked(String),
+    /// The server refused something this user asked for. `code` says what
+    /// went wrong, `reason` is the server's own words for it.
+    Error { code: FailureCode, reason: String },
+    /// The connection dropped. Unless the server ended it, it is brought
+    /// back on its own and `Reconnected` follows.
+    Disconnected { reason: String, reconnecting: bool },
+    Reconnected,
+}
+
+/// Turns the server's messages into events. Both kicks and moving to a
+/// new chat come as a `Logout` naming no chat, told apart only by the
+/// notice that follows a kick, so that `Logout` is held until the next
+/// message.
+#[derive(Default)]
+pub struct Translator {
+    left: Option<String>,
+}
+
+impl Translator {
+    /// `chat` is the chat the user was in before `message`.
+    pub fn translate(&mut self, message: Message, chat: Option<&str>) -> Vec<ClientEvent> {
+        use message::Message::*;
+        let mut events = Vec::new();
+        if let Some(room) = self.left.take() {
+            match message {
+                ChatMessage(ref u, ref c, ref m)
+                    if is_notice(u, c) && m == "Kicked from chat!" => {
+                    return vec![ClientEvent::Kicked(room)];
+                },
+                _ => events.push(ClientEvent::Left(room)),
+            }
+        }
+        events.extend(match message {
+            ChatMessage(u, c, m) => Some(match is_notice(&u, &c) {
+                true => ClientEvent::Notice(m),
+                false => ClientEvent::Message { room: c, user: u, text: m },
+            }),
+            PrivateMessage(from, _, text) => Some(ClientEvent::Private { from, text }),
+            Joined(room) => Some(ClientEvent::Joined(room)),
+            Logout(_, room) => match room.is_empty() {
+                true => {
+                    self.left = chat.map(|c| c.to_owned());
+                    None
+                },
+                false => Some(ClientEvent::Left(room)),
+            },
+            Failure(code, reason, _) => Some(ClientEvent::Error { code, reason }),
+            ConnectionTermination(_) => Some(ClientEvent::Disconnected {
+                reason: "Connection with server terminated".to_owned(),
+                reconnecting: false,
+            }),
+            _ => None,
+        });
+        events
+    }
+
+    /// What was held back, for when no message is coming after it.
+    pub fn flush(&mut self) -> Option<ClientEvent> {
+        self.left.take().map(ClientEvent::Left)
+    }
+}
+
+/// How the server sends messages meant for one user only.
+fn is_notice(user: &str, chat: &str) -> bool {
+    user == "Server" && chat == "(SERVER)"
+}
@@ -0,0 +1,278 @@
+//! Chat logs kept by the client: a file for each chat and each user
+//! private messages went to or came from, under a directory of the
+//! connection's own user. Lines are timestamped in UTC, as plain text
+//! (`[2024-05-01 13:45:07] <ana> hi`) or as JSON, one object a line.
+
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+use super::ClientEvent;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogFormat {
+    /// `name.log`, for reading.
+    Text,
+    /// `name.jsonl`, for programs.
+    JsonLines,
+}
+
+/// Where and how to log, handed to `ChatConnection::log_to`.
+#[derive(Clone, Debug)]
+pub struct ChatLog {
+    dir: PathBuf,
+    format: LogFormat,
+    max_size: Option<u64>,
+    keep: usize,
+    /// Whose logs these are, filled in by the connection.
+    username: String,
+}
+
+/// Whose file a line goes in.
+enum Conversation<'a> {
+    Chat(&'a str),
+    Private(&'a str),
+}
+
+impl ChatLog {
+    /// Logs into `dir` as plain text, letting the files grow.
+    pub fn new<P: Into<PathBuf>>(dir: P) -> ChatLog {
+        ChatLog {
+            dir: dir.into(),
+            format: LogFormat::Text,
+            max_size: None,
+            keep: 0,
+            username: String::new(),
+        }
+    }
+
+    pub fn format(mut self, format: LogFormat) -> ChatLog {
+        self.format = format;
+        self
+    }
+
+    /// Once a file would grow past `max_size` bytes it is moved aside to
+    /// `name.log.1`, the one before that to `name.log.2` and so on, keeping
+    /// `keep` of them; with `keep` at 0 it is just started over.
+    pub fn rotate(mut self, max_size: u64, keep: usize) -> ChatLog {
+        self.max_size = Some(max_size);
+        self.keep = keep;
+        self
+    }
+
+    /// Makes the directory for `username`'s logs.
+    pub(super) fn open(&mut self, username: &str) -> io::Result<()> {
+        self.username = username.to_owned();
+        fs::create_dir_all(self.dir.join(file_name(username)))
+    }
+
+    /// Writes down what `event` says, if it belongs in a chat's log.
+    pub(super) fn event(&self, event: &ClientEvent) {
+        match *event {
+            ClientEvent::Message { ref room, ref user, ref text } =>
+                self.said(Conversation::Chat(room), user, text),
+            ClientEvent::Private { ref from, ref text } =>
+                self.said(Conversation::Private(from), from, text),
+            ClientEvent::Joined(ref room) => self.happened(room, "joined"),
+            ClientEvent::Left(ref room) => self.happened(room, "left"),
+            ClientEvent::Kicked(ref room) => self.happened(room, "kicked"),
+            _ => (),
+        }
+    }
+
+    /// Writes down a message this user sent to `room`.
+    pub(super) fn sent(&self, room: &str, text: &str) {
+        self.said(Conversation::Chat(room), &self.username, text);
+    }
+
+    /// Writes down a private message this user sent to `to`.
+    pub(super) fn sent_private(&self, to: &str, text: &str) {
+        self.said(Conversation::Private(to), &self.username, text);
+    }
+
+    fn said(&self, conversation: Conversation, user: &str, text: &str) {
+        let time = now();
+        let line = match (self.format, &conversation) {
+            (LogFormat::Text, _) => format!("[{}] <{}> {}", timestamp(time), user, text),
+            (LogFormat::JsonLines, &Conversation::Chat(room)) => json!({
+                "time": time,
+                "type": "message",
+                "chat": room,
+                "username": user,
+                "contents": text,
+            }).to_string(),
+            (LogFormat::JsonLines, &Conversation::Private(other)) => {
+                let to = if user == other { &self.username } else { other };
+                json!({
+                    "time": time,
+                    "type": "private",
+                    "from": user,
+                    "to": to,
+                    "contents": text,
+                }).to_string()
+            },
+        };
+        self.write(conversation, &line);
+    }
+
+    /// `what` this user did in `room`: joined, left or was kicked.
+    fn happened(&self, room: &str, what: &str) {
+        let time = now();
+        let line = match self.format {
+            LogFormat::Text => format!("[{}] -- {} {}", timestamp(time), self.username, what),
+            LogFormat::JsonLines => json!({
+                "time": time,
+                "type": what,
+                "chat": room,
+                "username": self.username,
+            }).to_string(),
+        };
+        self.write(Conversation::Chat(room), &line);
+    }
+
+    /// Appends `line`, rotating first if it wouldn't fit. A log that can't
+    /// be written is not worth stopping the chat for.
+    fn write(&self, conversation: Conversation, line: &str) {
+        let extension = match self.format {
+            LogFormat::Text => "log",
+            LogFormat::JsonLines => "jsonl",
+        };
+        let name = match conversation {
+            Conversation::Chat(room) => file_name(room),
+            Conversation::Private(user) => format!("@{}", file_name(user)),
+        };
+        let path = self.dir.join(file_name(&self.username))
+            .join(format!("{}.{}", name, extension));
+        if let Some(max) = self.max_size {
+            let size = fs::metadata(&path).map(|m| m.len()).unwrap_or(0);
+            if size > 0 && size + line.len() as u64 + 1 > max {
+                self.rotate_file(&path);
+            }
+        }
+        if let Ok(mut file) = OpenOptions::new().create(true).append(true).open(&path) {
+            let _ = writeln!(file, "{}", line);
+        }
+    }
+
+    fn rotate_file(&self, path: &PathBuf) {
+        let numbered = |n: usize| {
+            let mut name = path.clone().into_os_string();
+            name.push(format!(".{}", n));
+            PathBuf::from(name)
+        };
+        if self.keep == 0 {
+            let _ = fs::remove_file(path);
+            return;
+        }
+        let _ = fs::remove_file(numbered(self.keep));
+        for n in (1..self.keep).rev() {
+            let _ = fs::rename(numbered(n), numbered(n + 1));
+        }
+        let _ = fs::rename(path, numbered(1));
+    }
+}
+
+/// `name` made safe to be a file name: anything but letters, digits, `-`,
+/// `_` and `.` becomes `_`, so that no name can reach outside the log
+/// directory or pass for a private conversation.
+fn file_name(name: &str) -> String {
+    let safe: String = name.chars()
+        .map(|c| if c.is_alphanumeric() || c == '-' || c == '_' || c == '.' { c } else { '_' })
+        .collect();
+    match safe.trim_matches('.').is_empty() {
+        true => safe.replace('.', "_"),
+        false => safe,
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// `time`, in seconds since the epoch, as `YYYY-MM-DD hh:mm:ss` in UTC.
+fn timestamp(time: u64) -> String {
+    let (days, secs) = (time / 86_400, time % 86_400);
+    // Civil date from days since 1970-01-01, in 400 year eras counted from
+    // a March 1st, so that the leap day falls at the end of each year.
+    let days = days as i64 + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days - era * 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524
+                       - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month + 2) / 5 + 1;
+    let month = if month < 10 { month + 3 } else { month - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            year, month, day, secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process;
+
+    /// A fresh log directory of this test's own.
+    fn scratch(test: &str) -> PathBuf {
+        let dir = ::std::env::temp_dir()
+            .join(format!("chat_np1-log-{}-{}", process::id(), test));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn read(path: PathBuf) -> String {
+        fs::read_to_string(path).unwrap_or_default()
+    }
+
+    #[test]
+    fn timestamps_are_civil_dates() {
+        assert_eq!(timestamp(0), "1970-01-01 00:00:00");
+        assert_eq!(timestamp(1_714_571_107), "2024-05-01 13:45:07");
+        // Leap days: 2000 has one, 2100 does not.
+        assert_eq!(timestamp(951_782_400), "2000-02-29 00:00:00");
+        assert_eq!(timestamp(1_709_251_199), "2024-02-29 23:59:59");
+        assert_eq!(timestamp(1_709_251_200), "2024-03-01 00:00:00");
+        assert_eq!(timestamp(4_107_542_399), "2100-02-28 23:59:59");
+        assert_eq!(timestamp(4_107_542_400), "2100-03-01 00:00:00");
+        assert_eq!(timestamp(1_735_689_599), "2024-12-31 23:59:59");
+    }
+
+    #[test]
+    fn files_rotate_once_a_line_wouldnt_fit() {
+        let dir = scratch("rotate");
+        let mut log = ChatLog::new(&dir).rotate(10, 2);
+        log.open("ana").unwrap();
+        let path = dir.join("ana").join("Chat1.log");
+        let numbered = |n: usize| dir.join("ana").join(format!("Chat1.log.{}", n));
+        // Exactly at the limit still fits.
+        log.write(Conversation::Chat("Chat1"), "aaaa");
+        log.write(Conversation::Chat("Chat1"), "bbbb");
+        assert_eq!(read(path.clone()), "aaaa\nbbbb\n");
+        log.write(Conversation::Chat("Chat1"), "c");
+        assert_eq!(read(path.clone()), "c\n");
+        assert_eq!(read(numbered(1)), "aaaa\nbbbb\n");
+        // A line bigger than the limit still goes in a file of its own.
+        log.write(Conversation::Chat("Chat1"), "dddddddddddd");
+        log.write(Conversation::Chat("Chat1"), "e");
+        assert_eq!(read(path.clone()), "e\n");
+        assert_eq!(read(numbered(1)), "dddddddddddd\n");
+        assert_eq!(read(numbered(2)), "c\n");
+        assert!(!numbered(3).exists());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn keeping_none_starts_over() {
+        let dir = scratch("keep-none");
+        let mut log = ChatLog::new(&dir).rotate(6, 0);
+        log.open("ana").unwrap();
+        log.write(Conversation::Private("bob"), "hello");
+        log.write(Conversation::Private("bob"), "again");
+        assert_eq!(read(dir.join("ana").join("@bob.log")), "again\n");
+        assert!(!dir.join("ana").join("@bob.log.1").exists());
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -1,309 +1,1103 @@
-use std::net::{ToSocketAddrs, TcpListener, TcpStream};
-use std::sync::mpsc;
-use std::thread;
-use std::sync::{Arc, Mutex};
-use std::collections::HashMap;
-use std::io::{BufReader, BufRead, Write};
-use message::Message;
-
-type Sender = mpsc::Sender<Message>;
-type Receiver = mpsc::Receiver<Message>;
-type Connections = Arc<Mutex<HashMap<String, UserConnection>>>;
-type Groups = Arc<Mutex<HashMap<String,Vec<String>>>>;
-
-lazy_static! {
-    static ref CONNECTIONS: Connections = Default::default();
-    static ref GROUPS: Groups = Default::default();
-}
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::{self, Write};
+#[cfg(unix)]
+use std::path::Path;
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use mio::{Events, Interest, Poll, Token, Waker};
+use mio::net::TcpListener;
+use ring::rand::{SecureRandom, SystemRandom};
+use message::{FailureCode, Message};
+use transport::{self, ChannelTransport, Connector, Transport};
+
+mod admin;
+mod builder;
+mod codec;
+mod command;
+mod connection;
+mod event;
+mod http;
+mod irc;
+mod link;
+mod plugin;
+mod ratelimit;
+mod script;
+mod stream;
+mod webhook;
+mod websocket;
+
+pub use self::builder::{ServerBuilder, SlowConsumerPolicy};
+pub use self::command::{Call, Command, Permission};
+pub use self::plugin::{Action, Context, Plugin};
+pub use self::ratelimit::{Escalation, MessageKind, Rate};
+pub use self::script::Scripts;
+use self::builder::Config;
+use self::codec::{Codec, Inbound, Protocol};
+use self::connection::{peer_name, Connection};
+use self::event::RoomEvent;
+use self::irc::valid_room;
+use self::link::Outbound;
+use self::ratelimit::{TokenBucket, UserLimiter, Verdict};
+use self::stream::{Listener, Stream};
+use self::webhook::Webhooks;
+
+/// How long a dropped connection keeps its session (and its place in the
+/// chats) waiting for the client to resume it.
+const RESUME_GRACE: Duration = Duration::from_secs(60);
+/// Maximum number of messages kept for a detached session.
+const MAX_MISSED: usize = 200;
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+/// Woken up for in-process clients, which have no socket to poll.
+const WAKER: Token = Token(0);
 
 pub fn start_server<A: ToSocketAddrs>(addr: A) {
-    let listener = TcpListener::bind(addr)
-        .expect("Falha ao criar listener nesse endereço");
-    let (comm_snd, comm_rcv) = mpsc::channel();
-    GROUPS.lock().unwrap().insert("Chat1".into(), Vec::new());
-    let _ = thread::spawn(move || communicate_messages(comm_rcv));
-    listen_messages(listener, comm_snd);
+    ServerBuilder::new().start(addr);
 }
 
-fn listen_messages(listener: TcpListener,
-                   comm_channel: Sender) {
-    for stream in listener.incoming() {
-        let stream = stream.expect("Failed getting stream in listen_messages");
-        let mut reader = BufReader::new(stream.try_clone().unwrap());
-        let mut buf = Vec::new();
-        loop {
-            let mut read_bytes = 0;
-            match reader.read_until(b'\n', &mut buf) {
-                Ok(b) => read_bytes = b,
-                Err(e) => eprintln!("{}", e),
-            };
-            if read_bytes > 0 {
-                match Message::from_bytes(&buf) {
-                    Ok(Message::InitUser(username)) => new_client(username,
-                                                       stream,
-                                                       comm_channel.clone()),
-                    _ => eprintln!("Somehow got non-init message from new connection"),
-                }
-                break;
-            }
-        }
-    }
+/// A user known to the server, whether or not it currently has a socket.
+struct Session {
+    token: String,
+    connection: Option<Token>,
+    detached_since: Option<Instant>,
+    missed: VecDeque<Message>,
+    limiter: UserLimiter,
 }
 
-fn new_client(username: String,
-              stream: TcpStream,
-              comm_channel: Sender) {
-    println!("New client");
-    let user = UserConnection::new(username.clone(), stream, comm_channel);
-    //send response
-    let contents = "Following groups available, type /join [GROUP] to join".to_owned();
-    
-    CONNECTIONS.lock().unwrap()
-        .insert(username.clone(), user);
-    
-    send_chat_message_to_user(username.clone(), contents);
-    let contents = groups_list();
-    send_chat_message_to_user(username.clone(), contents);
+/// A chat message as kept in a room's recent history.
+struct HistoryEntry {
+    username: String,
+    contents: String,
+    /// Seconds since the Unix epoch.
+    time: u64,
 }
 
-///Retorna uma String com todos os grupos disponíveis separados por " | "
-#[inline]
-fn groups_list() -> String {
-    GROUPS.lock().unwrap().keys()
-        .fold(String::new(),
-              |acc, ref x| format!("{} | {}", x, acc))
+/// The `Request` being handled, so that what it gets back can name it.
+struct CurrentRequest {
+    user: String,
+    id: String,
+    failed: bool,
 }
 
-#[inline]
-fn users_in_group(group_name: String) -> String {
-    GROUPS.lock().unwrap().get(&group_name).unwrap().iter()
-        .fold(String::from("Online: "),
-              |acc, ref x| format!("{} {},", acc, x))
+/// Opens in-process connections to a running server; see
+/// `Server::local_connector`.
+#[derive(Clone)]
+pub struct LocalConnector {
+    new_ends: mpsc::Sender<ChannelTransport>,
+    waker: Arc<Waker>,
 }
 
-fn list_users(message: Message) {
-    if let Message::ListUsers(username, group_name) = message {
-        let online = users_in_group(group_name);
-        send_chat_message_to_user(username, online);
+impl LocalConnector {
+    /// Same as `connect`, keeping the channel's own type, whose raw bytes
+    /// can be written (see `ChannelTransport::write_bytes`).
+    pub fn connect_channel(&self) -> io::Result<ChannelTransport> {
+        let (client, server) = transport::pair();
+        self.new_ends.send(server)
+            .map_err(|_| io::Error::from(io::ErrorKind::ConnectionRefused))?;
+        self.waker.wake()?;
+        Ok(client)
     }
 }
 
-fn communicate_messages(receiver: Receiver) {
-    loop {
-        let message = receiver.recv().unwrap();
-        println!("{:?}", message);
-        use self::Message::*;
-        match message {
-            ListGroups(u) => send_chat_message_to_user(u, groups_list()),
-            m @ ListUsers(_,_) => list_users(m),
-            m @ Login(_,_) => login(m),
-            m @ ChatMessage(_,_,_) => chat_message(m),
-            m @ PrivateMessage(_,_,_) => private_message(m),
-            m @ Logout(_,_) => logout(m),
-            m @ NewChat(_,_) => create_group(m),
-            m @ KickUser(_,_,_) => kick_user(m),
-            ConnectionTermination(u) => terminate_connection(u),
-            _ => (),
-        }
-    }    
+impl Connector for LocalConnector {
+    fn connect(&self) -> io::Result<Box<dyn Transport>> {
+        Ok(Box::new(self.connect_channel()?))
+    }
 }
 
+/// Owns every socket and all chat state; runs on a single thread and only
+/// wakes up when a socket is ready or a timer is due. Built by
+/// `ServerBuilder::build`.
+pub struct Server {
+    config: Config,
+    poll: Poll,
+    listeners: HashMap<Token, (Listener, Protocol)>,
+    waker: Arc<Waker>,
+    new_ends: mpsc::Sender<ChannelTransport>,
+    new_ends_rcv: mpsc::Receiver<ChannelTransport>,
+    /// In-process connections with something to read.
+    ready: Arc<Mutex<Vec<Token>>>,
+    connections: HashMap<Token, Connection>,
+    sessions: HashMap<String, Session>,
+    groups: HashMap<String, Vec<String>>,
+    topics: HashMap<String, String>,
+    /// The last `Config::history_size` chat messages of each room.
+    history: HashMap<String, VecDeque<HistoryEntry>>,
+    /// Usernames that may not log in.
+    banned: HashSet<String>,
+    webhooks: Webhooks,
+    /// Authenticated links, by the name of the server on the other end.
+    links: HashMap<String, Token>,
+    /// Users of other servers, and the link they are reached through.
+    remote_users: HashMap<String, Token>,
+    outbound: Vec<Outbound>,
+    plugins: Vec<Box<dyn Plugin>>,
+    commands: Vec<Command>,
+    request: Option<CurrentRequest>,
+    /// Only for rooms that exist, and only while they have been busy.
+    room_buckets: HashMap<String, TokenBucket>,
+    /// Flood records of users who left while muted or with recent strikes,
+    /// so that coming back doesn't wipe them.
+    flood_records: HashMap<String, UserLimiter>,
+    connections_per_ip: HashMap<IpAddr, usize>,
+    pending_handshakes: usize,
+    next_token: usize,
+    /// Connections whose write interest or open state may have changed.
+    dirty: Vec<Token>,
+}
 
-fn login(message: Message) {
-    if let Message::Login(username, group_name) = message {
-        let mut groups = GROUPS.lock().unwrap();
-        let mut group = match groups.get_mut(&group_name) {
-            Some(v) => v,
-            None => {
-                failure_message(username, "No such chat");
-                return;
+impl Server {
+    fn new(config: Config) -> io::Result<Server> {
+        let poll = Poll::new()?;
+        let waker = Arc::new(Waker::new(poll.registry(), WAKER)?);
+        let (new_ends, new_ends_rcv) = mpsc::channel();
+        let webhooks = Webhooks::start(&config.webhooks)?;
+        let outbound = config.links.iter().cloned().map(Outbound::new).collect();
+        Ok(Server {
+            config,
+            poll,
+            listeners: HashMap::new(),
+            waker,
+            new_ends,
+            new_ends_rcv,
+            ready: Default::default(),
+            connections: HashMap::new(),
+            sessions: HashMap::new(),
+            groups: HashMap::new(),
+            topics: HashMap::new(),
+            history: HashMap::new(),
+            banned: HashSet::new(),
+            webhooks,
+            links: HashMap::new(),
+            remote_users: HashMap::new(),
+            outbound,
+            plugins: Vec::new(),
+            commands: command::builtins(),
+            request: None,
+            room_buckets: HashMap::new(),
+            flood_records: HashMap::new(),
+            connections_per_ip: HashMap::new(),
+            pending_handshakes: 0,
+            next_token: WAKER.0 + 1,
+            dirty: Vec::new(),
+        })
+    }
+
+    /// Accepts TCP clients on `addr`, over TLS if the builder was given a
+    /// certificate.
+    pub fn listen<A: ToSocketAddrs>(&mut self, addr: A) -> io::Result<()> {
+        let listener = Listener::Tcp(TcpListener::bind(resolve(addr)?)?);
+        self.add_listener(listener, Protocol::Native)
+    }
+
+    /// Accepts browser clients on `addr`, speaking JSON over WebSocket (or
+    /// secure WebSocket, when TLS is set up).
+    pub fn listen_websocket<A: ToSocketAddrs>(&mut self, addr: A) -> io::Result<()> {
+        let listener = Listener::Tcp(TcpListener::bind(resolve(addr)?)?);
+        self.add_listener(listener, Protocol::WebSocket)
+    }
+
+    /// Accepts IRC clients on `addr`. Their nicknames are usernames and
+    /// `#name` is the chat `name`, so they share chats with everyone else.
+    pub fn listen_irc<A: ToSocketAddrs>(&mut self, addr: A) -> io::Result<()> {
+        let listener = Listener::Tcp(TcpListener::bind(resolve(addr)?)?);
+        self.add_listener(listener, Protocol::Irc)
+    }
+
+    /// Serves the HTTP/JSON admin API on `addr` (see `admin.rs`), never over
+    /// TLS. Anyone who can reach it can kick and ban, so keep it on a
+    /// loopback address or set `ServerBuilder::admin_token`.
+    pub fn listen_admin<A: ToSocketAddrs>(&mut self, addr: A) -> io::Result<()> {
+        let listener = Listener::Tcp(TcpListener::bind(resolve(addr)?)?);
+        self.add_listener(listener, Protocol::Http)
+    }
+
+    /// Accepts links from other servers on `addr` (see `link.rs`); needs
+    /// `ServerBuilder::link_secret`. Never over TLS.
+    pub fn listen_links<A: ToSocketAddrs>(&mut self, addr: A) -> io::Result<()> {
+        if self.config.link_secret.is_none() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "accepting links needs a link secret"));
+        }
+        let listener = Listener::Tcp(TcpListener::bind(resolve(addr)?)?);
+        self.add_listener(listener, Protocol::Link)
+    }
+
+    /// Accepts clients on a Unix domain socket at `path`.
+    #[cfg(unix)]
+    pub fn listen_unix<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        let listener = Listener::bind_unix(path.as_ref())?;
+        self.add_listener(listener, Protocol::Native)
+    }
+
+    fn add_listener(&mut self, mut listener: Listener, protocol: Protocol) -> io::Result<()> {
+        let token = Token(self.next_token);
+        self.next_token += 1;
+        self.poll.registry().register(listener.source(), token, Interest::READABLE)?;
+        self.listeners.insert(token, (listener, protocol));
+        Ok(())
+    }
+
+    /// Connects clients living in the same process, without any socket.
+    /// Works from any thread, before or after `run` is called.
+    pub fn local_connector(&self) -> LocalConnector {
+        LocalConnector {
+            new_ends: self.new_ends.clone(),
+            waker: self.waker.clone(),
+        }
+    }
+
+    pub fn run(&mut self) -> io::Result<()> {
+        let mut events = Events::with_capacity(1024);
+        let mut last_sweep = Instant::now();
+        self.connect_links();
+        self.update_connections();
+        loop {
+            if let Err(e) = self.poll.poll(&mut events, Some(SWEEP_INTERVAL)) {
+                if e.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(e);
+            }
+            for event in events.iter() {
+                match event.token() {
+                    WAKER => self.local_events(),
+                    token if self.listeners.contains_key(&token) => self.accept(token),
+                    token => {
+                        if event.is_writable() {
+                            if let Some(conn) = self.connections.get_mut(&token) {
+                                conn.flush();
+                            }
+                            self.dirty.push(token);
+                        }
+                        if event.is_readable() {
+                            self.read_from(token);
+                        }
+                    },
+                }
+            }
+            self.update_connections();
+            if last_sweep.elapsed() >= SWEEP_INTERVAL {
+                self.expire_sessions();
+                self.expire_handshakes();
+                self.forget_floods();
+                self.connect_links();
+                self.update_connections();
+                last_sweep = Instant::now();
             }
+        }
+    }
+
+    fn accept(&mut self, listener: Token) {
+        loop {
+            let (accepted, protocol) = match self.listeners.get(&listener) {
+                Some(&(ref l, protocol)) => (l.accept(), protocol),
+                None => return,
+            };
+            match accepted {
+                Ok((socket, addr)) => {
+                    self.adopt(socket, addr, protocol);
+                },
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                Err(e) => {
+                    eprintln!("Failed accepting connection: {}", e);
+                    return;
+                },
+            }
+        }
+    }
+
+    /// Picks up new in-process clients and reads from those with something
+    /// to say.
+    fn local_events(&mut self) {
+        while let Ok(end) = self.new_ends_rcv.try_recv() {
+            self.adopt(Stream::Channel(end), None, Protocol::Native);
+        }
+        let ready = ::std::mem::take(&mut *self.ready.lock().unwrap());
+        for token in ready {
+            self.read_from(token);
+        }
+    }
+
+    fn adopt(&mut self, mut socket: Stream, addr: Option<SocketAddr>, protocol: Protocol)
+             -> Option<Token> {
+        let ip = addr.map(|a| a.ip());
+        if let Some(reason) = self.refusal_reason(ip) {
+            println!("Refusing connection from {}: {}", peer_name(addr), reason.text());
+            // Best effort: the socket is fresh, so this should fit in
+            // its buffer, and it gets closed right after anyway.
+            let _ = socket.write_all(&Codec::new(protocol).refusal(reason));
+            return None;
+        }
+        let token = Token(self.next_token);
+        self.next_token += 1;
+        if let Err(e) = self.poll.registry()
+            .register(&mut socket, token, Interest::READABLE) {
+            eprintln!("Failed registering connection: {}", e);
+            return None;
+        }
+        if let Stream::Channel(ref end) = socket {
+            let ready = self.ready.clone();
+            let waker = self.waker.clone();
+            end.on_readable(move || {
+                ready.lock().unwrap().push(token);
+                let _ = waker.wake();
+            });
+        }
+        // Only TCP goes through TLS; the other transports never leave the
+        // machine, and neither should the admin API. Links authenticate
+        // on their own.
+        let tls = match socket {
+            Stream::Tcp(_) if matches!(protocol, Protocol::Native | Protocol::WebSocket |
+                                                 Protocol::Irc) => self.config.tls.as_ref(),
+            _ => None,
+        };
+        let conn = match Connection::new(socket, addr, self.config.max_queued_messages,
+                                         tls, protocol) {
+            Ok(c) => c,
+            Err(e) => {
+                eprintln!("Failed setting up connection: {}", e);
+                return None;
+            },
         };
-        if !group.contains(&username) {
-            CONNECTIONS.lock().expect("login con lock").get_mut(&username).expect("login con get")
-                .send_to_user(Message::Joined(group_name.clone()));
-            group.push(username);
+        if let Some(ip) = ip {
+            *self.connections_per_ip.entry(ip).or_insert(0) += 1;
+        }
+        self.pending_handshakes += 1;
+        self.connections.insert(token, conn);
+        if protocol == Protocol::Link {
+            self.link_hello(token);
+        }
+        Some(token)
+    }
+
+    fn refusal_reason(&self, ip: Option<IpAddr>) -> Option<FailureCode> {
+        if self.connections.len() >= self.config.max_connections {
+            Some(FailureCode::ServerFull)
+        } else if self.pending_handshakes >= self.config.max_pending_handshakes {
+            Some(FailureCode::TooManyPending)
+        } else if ip.and_then(|ip| self.connections_per_ip.get(&ip)).cloned().unwrap_or(0)
+            >= self.config.max_connections_per_ip {
+            Some(FailureCode::TooManyFromAddress)
         } else {
-            failure_message(username, "Already in this chat");
+            None
         }
     }
-}
 
-fn chat_message(message: Message) {
-    let repass = message.clone();
-    if let Message::ChatMessage(username, chat_name, _) = message {
-        let mut groups = GROUPS.lock().unwrap();
-        let mut group = match groups.get_mut(&chat_name) {
-            Some(v) => v,
-            None => {
-                failure_message(username, "Must join a chat");
-                return;
+    /// Drops sockets that connected but never said who they are.
+    fn expire_handshakes(&mut self) {
+        let timeout = self.config.handshake_timeout;
+        let expired: Vec<Token> = self.connections.iter()
+            .filter(|&(_, c)| !c.handshake_done && c.opened.elapsed() > timeout)
+            .map(|(t, _)| *t)
+            .collect();
+        for token in expired {
+            self.close_connection(token);
+        }
+    }
+
+    fn read_from(&mut self, token: Token) {
+        let messages = match self.connections.get_mut(&token) {
+            Some(conn) => conn.read_messages(),
+            None => return,
+        };
+        for inbound in messages {
+            let username = match self.connections.get(&token) {
+                Some(conn) => conn.username.clone(),
+                None => return,
+            };
+            match (inbound, username) {
+                (Inbound::Message(message), Some(u))
+                    if message.sender().is_some_and(|s| s != u) => self.impostor(&u, message),
+                (Inbound::Message(Message::Request(id, message)), Some(u)) =>
+                    self.request(u, id, *message),
+                (Inbound::Message(message), Some(u)) => if self.admit(&u, &message) {
+                    self.communicate_message(message)
+                },
+                (Inbound::Message(message), None) => self.handshake(token, message),
+                (Inbound::Irc(line), _) => self.irc_command(token, line),
+                (Inbound::Http(request), _) => self.admin_request(token, request),
+                (Inbound::Link(line), _) => self.link_line(token, line),
             }
+        }
+        self.dirty.push(token);
+    }
+
+    /// Refuses a message that names someone other than the user `username`
+    /// logged in as.
+    fn impostor(&mut self, username: &str, message: Message) {
+        let id = match message {
+            Message::Request(id, _) => id,
+            _ => String::new(),
         };
-        let mut connections = CONNECTIONS.lock().unwrap();
-        for user in group.iter_mut() {
-            if user != &username {
-                connections.get_mut(user).unwrap().send_to_user(repass.clone());
+        let reason = "Messages must carry your own username";
+        self.send_to_user(username, Message::Failure(FailureCode::InvalidMessage,
+                                                     reason.to_owned(),
+                                                     id));
+    }
+
+    /// Handles `message` like any other, then answers `Done` unless a
+    /// failure was sent back for it.
+    fn request(&mut self, username: String, id: String, message: Message) {
+        self.request = Some(CurrentRequest { user: username.clone(), id, failed: false });
+        if self.admit(&username, &message) {
+            self.communicate_message(message);
+        }
+        if let Some(request) = self.request.take() {
+            if !request.failed {
+                self.send_to_user(&username, Message::Done(request.id));
             }
         }
-    }    
-}
+    }
 
-fn private_message(message: Message) {
-    let repass = message.clone();
-    if let Message::PrivateMessage(from, to, _) = message {
-        let mut connections = CONNECTIONS.lock().unwrap();
-        match connections.get_mut(&to) {
-            Some(u) => u.send_to_user(repass),
-            None => failure_message(from, "No such user"),
+    /// Applies the flood limits to a message from `username`, answering
+    /// with a failure (or dropping the user) when it goes over.
+    fn admit(&mut self, username: &str, message: &Message) -> bool {
+        let kind = match MessageKind::of(message) {
+            Some(k) => k,
+            None => return true,
+        };
+        let limits = &self.config.rate_limits;
+        let verdict = match self.sessions.get_mut(username) {
+            Some(s) => s.limiter.check(kind, limits),
+            None => return false,
+        };
+        match verdict {
+            Verdict::Allowed => (),
+            Verdict::Limited => {
+                self.fail(username, FailureCode::RateLimited);
+                return false;
+            },
+            Verdict::Muted(left) => {
+                let contents = format!("Muted for flooding, {} seconds left",
+                                       left.as_secs_f64().ceil());
+                self.failure_message(username, FailureCode::Muted, contents);
+                return false;
+            },
+            Verdict::Disconnect => {
+                println!("Disconnecting {} for flooding", username);
+                self.terminate_connection(username.to_owned(), "Flooding");
+                return false;
+            },
         }
-    }    
-}
+        let room = match *message {
+            Message::ChatMessage(_, ref chat_name, _) if self.groups.contains_key(chat_name) =>
+                Some(chat_name),
+            _ => None,
+        };
+        if let (Some(chat_name), Some(rate)) = (room, limits.per_room) {
+            let allowed = self.room_buckets.entry(chat_name.clone())
+                .or_insert_with(|| TokenBucket::new(rate))
+                .try_take();
+            if !allowed {
+                self.fail(username, FailureCode::ChatBusy);
+                return false;
+            }
+        }
+        true
+    }
 
-fn logout(message: Message) {
-    if let Message::Logout(username, group_name) = message {
-        GROUPS.lock().unwrap().get_mut(&group_name).unwrap()
-            .retain(|x| x != &username);
-        CONNECTIONS.lock().unwrap().get_mut(&username).unwrap()
-            .send_to_user(Message::Logout(username,group_name));
+    /// Drops the flood state nothing depends on anymore: records whose
+    /// mute and strikes are over, and room buckets that have refilled.
+    fn forget_floods(&mut self) {
+        let escalation = self.config.rate_limits.escalation;
+        self.flood_records.retain(|_, limiter| !limiter.is_clean(&escalation));
+        let groups = &self.groups;
+        self.room_buckets.retain(|room, bucket| groups.contains_key(room) && !bucket.is_full());
     }
-}
 
-fn create_group(message: Message) {
-    if let Message::NewChat(username, chat_name) = message {
-        let mut groups = GROUPS.lock().unwrap();
-        if groups.contains_key(&chat_name) {
-            failure_message(username, "Chat already exists".to_owned());
+    fn handshake(&mut self, token: Token, message: Message) {
+        let username = match message {
+            Message::InitUser(ref u) | Message::Resume(ref u, _) => u.clone(),
+            _ => String::new(),
+        };
+        if self.refuse_login(token, &username) {
             return;
+        }
+        match message {
+            Message::InitUser(username) => self.new_client(username, token),
+            Message::Resume(username, session_token) =>
+                self.resume_client(username, session_token, token),
+            _ => {
+                eprintln!("Somehow got non-init message from new connection");
+                self.close_connection(token);
+            },
+        }
+    }
+
+    /// Turns the socket away if `username` may not log in here; returns
+    /// whether it did.
+    fn refuse_login(&mut self, token: Token, username: &str) -> bool {
+        let reason = if self.banned.contains(username) {
+            FailureCode::Banned
+        } else if self.remote_users.contains_key(username) {
+            FailureCode::NameTaken
         } else {
-            for group in groups.values_mut() {
-                group.retain(|x| x != &username);
+            return false;
+        };
+        println!("Refusing {}: {}", username, reason.text());
+        if let Some(conn) = self.connections.get_mut(&token) {
+            conn.send(&Message::failure(reason, reason.text()), self.config.slow_consumer);
+            conn.closing = true;
+        }
+        self.dirty.push(token);
+        true
+    }
+
+    fn new_client(&mut self, username: String, token: Token) {
+        self.open_session(username.clone(), token);
+        //send response
+        let contents = "Following groups available, type /join [GROUP] to join".to_owned();
+        self.send_chat_message_to_user(&username, contents);
+        let contents = self.groups_list();
+        self.send_chat_message_to_user(&username, contents);
+        self.notify_plugins(&username, |p, ctx| p.on_connect(ctx, &username));
+    }
+
+    /// Starts a fresh session for `username` on this socket, replacing any
+    /// previous one.
+    fn open_session(&mut self, username: String, token: Token) {
+        let session_token = match new_token() {
+            Some(t) => t,
+            None => return self.close_connection(token),
+        };
+        println!("New client");
+        // A new session doesn't get a clean slate with the flood limits.
+        let limiter = match self.sessions.remove(&username) {
+            Some(old) => {
+                if let Some(old_token) = old.connection {
+                    self.send_termination(old_token, username.clone());
+                }
+                old.limiter
+            },
+            None => self.flood_records.remove(&username).unwrap_or_default(),
+        };
+        let session = Session {
+            token: session_token,
+            connection: None,
+            detached_since: None,
+            missed: VecDeque::new(),
+            limiter,
+        };
+        self.sessions.insert(username.clone(), session);
+        self.share(&json!({ "type": "user", "user": username }), None);
+        self.attach(username, token);
+    }
+
+    /// Reattaches a new socket to a session that lost its connection, sending
+    /// back everything that was addressed to it in the meantime. Unknown or
+    /// mismatched tokens get a brand new session instead.
+    fn resume_client(&mut self, username: String, session_token: String, token: Token) {
+        let old_token = match self.sessions.get(&username) {
+            Some(s) if s.token == session_token => s.connection,
+            _ => return self.new_client(username, token),
+        };
+        println!("Resumed client");
+        if let Some(old_token) = old_token {
+            self.close_connection(old_token);
+        }
+        self.attach(username, token);
+    }
+
+    fn attach(&mut self, username: String, token: Token) {
+        let (session_token, missed) = match self.sessions.get_mut(&username) {
+            Some(s) => {
+                s.connection = Some(token);
+                s.detached_since = None;
+                (s.token.clone(), s.missed.drain(..).collect::<Vec<_>>())
+            },
+            None => return,
+        };
+        if let Some(conn) = self.connections.get_mut(&token) {
+            conn.username = Some(username.clone());
+            if !conn.handshake_done {
+                conn.handshake_done = true;
+                self.pending_handshakes -= 1;
             }
-            groups.insert(chat_name.clone(),Vec::new());
-            groups.get_mut(&chat_name).unwrap().push(username.clone());
-            CONNECTIONS.lock().unwrap().get_mut(&username).unwrap()
-                .send_to_user(Message::logout("",""));
-            CONNECTIONS.lock().unwrap().get_mut(&username).unwrap()
-                .send_to_user(Message::Joined(chat_name));
-            
-            send_chat_message_to_user(username, "Created new group and moved to it!");
+        }
+        self.send_to_user(&username, Message::Session(session_token));
+        for m in missed {
+            self.send_to_user(&username, m);
+        }
+    }
+
+    ///Retorna uma String com todos os grupos disponíveis separados por " | "
+    fn groups_list(&self) -> String {
+        self.groups.keys()
+            .fold(String::new(),
+                  |acc, x| format!("{} | {}", x, acc))
+    }
+
+    fn users_in_group(&self, group_name: &str) -> Option<String> {
+        self.groups.get(group_name).map(|group| {
+            group.iter()
+                .fold(String::from("Online: "),
+                      |acc, x| format!("{} {},", acc, x))
+        })
+    }
+
+    fn list_users(&mut self, message: Message) {
+        if let Message::ListUsers(username, group_name) = message {
+            match self.users_in_group(&group_name) {
+                Some(online) => self.send_chat_message_to_user(&username, online),
+                None => self.fail(&username, FailureCode::NoSuchChat),
+            }
+        }
+    }
+
+    fn communicate_message(&mut self, message: Message) {
+        println!("{:?}", message);
+        use self::Message::*;
+        match message {
+            ListGroups(u) => {
+                let groups = self.groups_list();
+                self.send_chat_message_to_user(&u, groups)
+            },
+            m @ ListUsers(_,_) => self.list_users(m),
+            m @ Login(_,_) => self.login(m),
+            m @ ChatMessage(_,_,_) => {
+                self.chat_message(m);
+            },
+            m @ PrivateMessage(_,_,_) => self.private_message(m),
+            m @ Logout(_,_) => self.logout(m),
+            m @ NewChat(_,_) => self.create_group(m),
+            m @ KickUser(_,_,_) => self.kick_user(m),
+            Command(u, c, line) => self.run_command(u, c, line),
+            ConnectionTermination(u) => self.terminate_connection(u, "Quit"),
+            _ => (),
+        }
+    }
+
+    fn login(&mut self, message: Message) {
+        if let Message::Login(username, group_name) = message {
+            match self.groups.get(&group_name) {
+                Some(group) if group.contains(&username) => {
+                    self.fail(&username, FailureCode::AlreadyInChat);
+                    return;
+                },
+                Some(_) => (),
+                None => {
+                    self.fail(&username, FailureCode::NoSuchChat);
+                    return;
+                },
+            }
+            let ctx = match self.ask_join(&username, &group_name) {
+                Some(ctx) => ctx,
+                None => return,
+            };
+            if let Some(group) = self.groups.get_mut(&group_name) {
+                group.push(username.clone());
+            }
+            self.send_to_user(&username, Message::Joined(group_name.clone()));
+            self.announce(RoomEvent::Joined { user: username, room: group_name });
+            self.answer(ctx);
+        }
+    }
+
+    /// Whether the message went out, or was refused or blocked.
+    fn chat_message(&mut self, message: Message) -> bool {
+        if let Message::ChatMessage(username, chat_name, mut contents) = message {
+            if !self.groups.contains_key(&chat_name) {
+                self.fail(&username, FailureCode::NotInChat);
+                return false;
+            }
+            let (action, ctx) = self.run_plugins(&username, |p, ctx| {
+                p.on_chat(ctx, &username, &chat_name, &mut contents)
+            });
+            if action == Action::Continue {
+                self.relay_chat(Message::ChatMessage(username.clone(), chat_name, contents),
+                                None);
+            }
+            self.answer(ctx);
+            if action == Action::Block {
+                self.fail_request(&username, FailureCode::Blocked);
+            }
+            return action == Action::Continue;
+        }
+        false
+    }
+
+    /// Hands a chat message to the chat's members here and on the other
+    /// linked servers, except the link it came from.
+    fn relay_chat(&mut self, message: Message, from_link: Option<Token>) {
+        let repass = message.clone();
+        if let Message::ChatMessage(username, chat_name, contents) = message {
+            let members = self.groups.get(&chat_name).cloned().unwrap_or_default();
+            for user in members.iter().filter(|u| *u != &username) {
+                self.send_to_user(user, repass.clone());
+            }
+            self.share(&json!({
+                "type": "message",
+                "user": username,
+                "room": chat_name,
+                "contents": contents,
+            }), from_link);
+            self.webhooks.message(&chat_name, &username, &contents);
+            self.remember(chat_name, username, contents);
         }
     }
-}
 
-fn kick_user(message: Message) {
-    if let Message::KickUser(from, chat_name, target) = message {
-        let mut groups = GROUPS.lock().unwrap();
-        let mut group = groups.get_mut(&chat_name).unwrap();
-        if group[0] != from {
-            failure_message(from, "Not admin in this chat");
+    fn remember(&mut self, room: String, username: String, contents: String) {
+        let size = self.config.history_size;
+        if size == 0 {
             return;
         }
-        match CONNECTIONS.lock().unwrap().get_mut(&target) {
-            Some(u) => {
-                group.retain(|x| x != &target);
-                u.send_to_user(Message::logout("",""));
-                u.send_to_user(Message::chat_message("Server",
-                                                     "(SERVER)",
-                                                     "Kicked from chat!"));
+        let time = SystemTime::now().duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let history = self.history.entry(room).or_default();
+        if history.len() >= size {
+            history.pop_front();
+        }
+        history.push_back(HistoryEntry { username, contents, time });
+    }
+
+    fn private_message(&mut self, message: Message) {
+        if let Message::PrivateMessage(from, to, mut contents) = message {
+            if !self.known_user(&to) {
+                self.fail(&from, FailureCode::NoSuchUser);
+                return;
+            }
+            let (action, ctx) = self.run_plugins(&from, |p, ctx| {
+                p.on_private(ctx, &from, &to, &mut contents)
+            });
+            if action == Action::Block {
+                self.answer(ctx);
+                return self.fail_request(&from, FailureCode::Blocked);
+            }
+            if self.sessions.contains_key(&to) {
+                self.send_to_user(&to, Message::PrivateMessage(from, to.clone(), contents));
+            } else if let Some(&link) = self.remote_users.get(&to) {
+                let line = json!({
+                    "type": "private",
+                    "from": from,
+                    "to": to,
+                    "contents": contents,
+                });
+                self.link_send(link, &line);
+            }
+            self.answer(ctx);
+        }
+    }
+
+    fn logout(&mut self, message: Message) {
+        if let Message::Logout(username, group_name) = message {
+            let was_member = self.leave_group(&username, &group_name);
+            self.send_to_user(&username.clone(), Message::Logout(username.clone(),
+                                                                 group_name.clone()));
+            if was_member {
+                self.announce(RoomEvent::Left { user: username, room: group_name });
+            }
+        }
+    }
+
+    fn create_group(&mut self, message: Message) {
+        if let Message::NewChat(username, chat_name) = message {
+            if self.groups.contains_key(&chat_name) {
+                self.fail(&username, FailureCode::ChatExists);
+            } else if !valid_room(&chat_name) {
+                self.fail(&username, FailureCode::InvalidName);
+            } else if let Some(ctx) = self.ask_join(&username, &chat_name) {
+                for room in self.rooms_of(&username) {
+                    self.leave_group(&username, &room);
+                    self.announce(RoomEvent::Left { user: username.clone(), room });
+                }
+                self.groups.insert(chat_name.clone(), vec![username.clone()]);
+                self.webhooks.created(&chat_name, &username);
+                self.send_to_user(&username, Message::logout("",""));
+                self.send_to_user(&username, Message::Joined(chat_name.clone()));
+                self.announce(RoomEvent::Joined { user: username.clone(), room: chat_name });
+
+                self.send_chat_message_to_user(&username, "Created new group and moved to it!");
+                self.answer(ctx);
+            }
+        }
+    }
+
+    fn kick_user(&mut self, message: Message) {
+        if let Message::KickUser(from, chat_name, target) = message {
+            let is_admin = match self.groups.get(&chat_name) {
+                Some(group) => group.first() == Some(&from),
+                None => false,
+            };
+            if !is_admin {
+                self.fail(&from, FailureCode::NotAdmin);
+                return;
+            }
+            if self.known_user(&target) {
+                self.kick(from, chat_name, target);
+            } else {
+                self.fail(&from, FailureCode::NoSuchMember);
+            }
+        }
+    }
+
+    fn kick(&mut self, by: String, room: String, target: String) {
+        if self.leave_group(&target, &room) {
+            self.announce(RoomEvent::Kicked { by, user: target.clone(), room });
+        }
+        self.tell_kicked(&target);
+    }
+
+    fn tell_kicked(&mut self, target: &str) {
+        self.send_to_user(target, Message::logout("",""));
+        self.send_to_user(target, Message::chat_message("Server",
+                                                         "(SERVER)",
+                                                         "Kicked from chat!"));
+    }
+
+    fn expire_sessions(&mut self) {
+        let expired: Vec<String> = self.sessions.iter()
+            .filter(|&(_, s)| s.detached_since.is_some_and(|t| t.elapsed() > RESUME_GRACE))
+            .map(|(u, _)| u.clone())
+            .collect();
+        for username in expired {
+            println!("Session of {} expired", username);
+            self.terminate_connection(username, "Connection lost");
+        }
+    }
+
+    fn terminate_connection(&mut self, username: String, reason: &str) {
+        if let Some(session) = self.sessions.remove(&username) {
+            if let Some(token) = session.connection {
+                self.send_termination(token, username.clone());
+            }
+            self.share(&json!({ "type": "quit", "user": username, "reason": reason }), None);
+            self.notify_plugins(&username, |p, ctx| p.on_disconnect(ctx, &username));
+            if !session.limiter.is_clean(&self.config.rate_limits.escalation) {
+                self.flood_records.insert(username.clone(), session.limiter);
+            }
+        }
+        let rooms = self.rooms_of(&username);
+        for room in &rooms {
+            self.leave_group(&username, room);
+        }
+        if !rooms.is_empty() {
+            self.announce(RoomEvent::Quit {
+                user: username,
+                rooms,
+                reason: reason.to_owned(),
+            });
+        }
+    }
+
+    /// Whether `username` is connected here or on a linked server.
+    fn known_user(&self, username: &str) -> bool {
+        self.sessions.contains_key(username) || self.remote_users.contains_key(username)
+    }
+
+    fn rooms_of(&self, username: &str) -> Vec<String> {
+        self.groups.iter()
+            .filter(|&(_, members)| members.iter().any(|m| m == username))
+            .map(|(room, _)| room.clone())
+            .collect()
+    }
+
+    /// Takes `username` out of a chat, returning whether it was in it.
+    fn leave_group(&mut self, username: &str, room: &str) -> bool {
+        match self.groups.get_mut(room) {
+            Some(group) => {
+                let before = group.len();
+                group.retain(|x| x != username);
+                group.len() != before
             },
-            None => failure_message(from, "No such user in this chat")
+            None => false,
         }
     }
-}
 
-fn terminate_connection(username: String) {
-    let connection = CONNECTIONS.lock().expect("terminate connections lock").remove(&username)
-        .expect("terminate connections remove");
-    drop(connection);
-    let mut groups = GROUPS.lock().expect("terminate groups lock");
-    for group in groups.values_mut() {
-        group.retain(|x| x != &username);
+    /// Tells everyone concerned by `event` about it, as far as their
+    /// protocol can show it. People who left are told too.
+    fn announce(&mut self, event: RoomEvent) {
+        if let RoomEvent::Left { ref user, ref room } |
+               RoomEvent::Kicked { ref user, ref room, .. } = event {
+            if self.sessions.contains_key(user) {
+                self.notify_plugins(user, |p, ctx| p.on_leave(ctx, user, room));
+            }
+        }
+        self.share_event(&event);
+        self.announce_locally(event);
     }
-}
 
-fn send_chat_message_to_user<S: Into<String>>(username: String, contents: S) {
-    CONNECTIONS.lock().expect("send chat lock").get_mut(&username).expect("send chat get")
-                .send_to_user(
-                    Message::chat_message("Server".to_owned(),
-                                          "(SERVER)".to_owned(),
-                                          contents.into()))
-}
+    /// Same as `announce`, for events that already went to linked servers.
+    fn announce_locally(&mut self, event: RoomEvent) {
+        self.webhooks.announce(&event);
+        let mut recipients: Vec<String> = match event {
+            RoomEvent::Joined { ref room, .. } | RoomEvent::Topic { ref room, .. } =>
+                self.groups.get(room).cloned().unwrap_or_default(),
+            RoomEvent::Left { ref user, ref room } |
+            RoomEvent::Kicked { ref user, ref room, .. } => {
+                let mut members = self.groups.get(room).cloned().unwrap_or_default();
+                members.push(user.clone());
+                members
+            },
+            RoomEvent::Quit { ref rooms, .. } => rooms.iter()
+                .filter_map(|r| self.groups.get(r))
+                .flat_map(|members| members.iter().cloned())
+                .collect(),
+        };
+        recipients.sort();
+        recipients.dedup();
+        for user in recipients {
+            let token = match self.sessions.get(&user).and_then(|s| s.connection) {
+                Some(t) => t,
+                None => continue,
+            };
+            if let Some(conn) = self.connections.get_mut(&token) {
+                if !conn.send_event(&event, self.config.slow_consumer) {
+                    println!("Disconnecting slow consumer {}", user);
+                }
+                self.dirty.push(token);
+            }
+        }
+    }
 
+    fn send_chat_message_to_user<S: Into<String>>(&mut self, username: &str, contents: S) {
+        self.send_to_user(username,
+                          Message::chat_message("Server".to_owned(),
+                                                "(SERVER)".to_owned(),
+                                                contents.into()))
+    }
 
-fn failure_message<S: Into<String>>(username: String, contents: S) {
-    let m = Message::failure(contents);
-    CONNECTIONS.lock().expect("failure connections lock")
-        .get_mut(&username).expect("get mut failure").send_to_user(m);
-}
+    /// Tells `username` something was refused, in the usual words.
+    fn fail(&mut self, username: &str, code: FailureCode) {
+        self.failure_message(username, code, code.text());
+    }
 
-#[derive(Debug)]
-struct UserConnection {
-    username: String,
-    socket: TcpStream,
-    thread: thread::JoinHandle<()>,
-    thread_comm: mpsc::Sender<()>,
-}
+    /// Tells `username` their request was refused, if one of theirs is being
+    /// handled; with none, nobody is waiting for an answer.
+    fn fail_request(&mut self, username: &str, code: FailureCode) {
+        if self.request.as_ref().is_some_and(|r| r.user == username) {
+            self.fail(username, code);
+        }
+    }
 
-impl UserConnection {
-    fn new(username: String,
-           socket: TcpStream,
-           callback_channel: Sender) -> UserConnection {
-        let stream_clone = socket.try_clone()
-            .expect("Failed cloning tcpstream");
-        let (thread, thread_comm) = Self::start_listening(callback_channel,
-                                                          stream_clone);
-        UserConnection {
-            username,
-            socket,
-            thread,
-            thread_comm,
-        }
-    }
-
-    fn start_listening(sender: Sender, socket: TcpStream)
-                       -> (thread::JoinHandle<()>, mpsc::Sender<()>) {
-        let sender = sender.clone();
-        let (snd, rcv) = mpsc::channel();
-        let snd_clone = snd.clone();
-        (thread::spawn(move || {            
-            let mut buf = Vec::new();
-            let mut reader = BufReader::new(socket);
-            let mut read_bytes = 0;
-            'listen: loop {
-                buf.clear();
-                match reader.read_until(b'\n', &mut buf) {
-                    Ok(b) => read_bytes+=b,
-                    Err(_) => {
-                        snd.send(()).expect("listen thread comm send");
-                    },
-                };
-                if read_bytes > 0 {
-                    let message = Message::from_bytes(&buf)
-                        .expect("Failed parsing message");
-                    sender.send(message)
-                        .expect("Failed sending from UserConnection thread");
-                };
-                match rcv.try_recv() {
-                    Ok(()) => break 'listen,
-                    Err(_) => (),
-                };
+    /// Tells `username` something was refused, naming the request it was
+    /// if one is being handled.
+    fn failure_message<S: Into<String>>(&mut self, username: &str, code: FailureCode,
+                                        contents: S) {
+        let id = match self.request {
+            Some(ref mut r) if r.user == username => {
+                r.failed = true;
+                r.id.clone()
+            },
+            _ => String::new(),
+        };
+        self.send_to_user(username, Message::Failure(code, contents.into(), id));
+    }
+
+    /// Delivers a message to a user's socket, or keeps it for later if the
+    /// user is currently disconnected.
+    fn send_to_user(&mut self, username: &str, m: Message) {
+        let session = match self.sessions.get_mut(username) {
+            Some(s) => s,
+            None => return,
+        };
+        let connections = &mut self.connections;
+        match session.connection.and_then(|t| connections.get_mut(&t).map(|c| (t, c))) {
+            Some((token, conn)) => {
+                if !conn.send(&m, self.config.slow_consumer) {
+                    println!("Disconnecting slow consumer {}", username);
+                }
+                self.dirty.push(token);
+            },
+            None => {
+                if session.missed.len() == MAX_MISSED {
+                    session.missed.pop_front();
+                }
+                session.missed.push_back(m);
+            },
+        }
+    }
+
+    fn send_termination(&mut self, token: Token, username: String) {
+        if let Some(conn) = self.connections.get_mut(&token) {
+            conn.username = None;
+            conn.send(&Message::termination(username), SlowConsumerPolicy::DropOldest);
+        }
+        self.close_connection(token);
+    }
+
+    /// Closes sockets that failed or hung up and updates write interest for
+    /// the ones that still have queued output.
+    fn update_connections(&mut self) {
+        let mut dirty = ::std::mem::take(&mut self.dirty);
+        dirty.sort();
+        dirty.dedup();
+        for token in dirty {
+            let registry = self.poll.registry();
+            let closed = match self.connections.get_mut(&token) {
+                Some(conn) if !conn.finished() => {
+                    let interest = if conn.wants_write() {
+                        Interest::READABLE | Interest::WRITABLE
+                    } else {
+                        Interest::READABLE
+                    };
+                    registry.reregister(&mut conn.socket, token, interest).is_err()
+                },
+                Some(_) => true,
+                None => false,
+            };
+            if closed {
+                self.close_connection(token);
             }
-        }), snd_clone)
+        }
     }
 
-    fn send_to_user(&mut self, m: Message) {
-        self.socket.write(&m.into_bytes()).expect("send write");
-        self.socket.flush().expect("send flush");
+    /// Drops a socket. If a user was attached to it, the session stays
+    /// around for `RESUME_GRACE` in case the client comes back.
+    fn close_connection(&mut self, token: Token) {
+        let mut conn = match self.connections.remove(&token) {
+            Some(c) => c,
+            None => return,
+        };
+        conn.flush();
+        let _ = self.poll.registry().deregister(&mut conn.socket);
+        if !conn.handshake_done {
+            self.pending_handshakes -= 1;
+        }
+        if let Some(ip) = conn.addr.map(|a| a.ip()) {
+            let remaining = match self.connections_per_ip.get_mut(&ip) {
+                Some(count) => {
+                    *count -= 1;
+                    *count
+                },
+                None => 0,
+            };
+            if remaining == 0 {
+                self.connections_per_ip.remove(&ip);
+            }
+        }
+        self.link_closed(token);
+        let can_resume = conn.can_resume();
+        if let Some(username) = conn.username {
+            let detached = match self.sessions.get_mut(&username) {
+                Some(session) if session.connection == Some(token) => {
+                    println!("Lost connection with {}", username);
+                    session.connection = None;
+                    session.detached_since = Some(Instant::now());
+                    true
+                },
+                _ => false,
+            };
+            // Nothing to resume with on protocols that have no session token.
+            if detached && !can_resume {
+                self.terminate_connection(username, "Connection closed");
+            }
+        }
     }
 }
 
-impl Drop for UserConnection {
-    fn drop(&mut self) {
-        let username = self.username.clone();
-        self.send_to_user(Message::termination(username));
-        self.thread_comm.send(()).expect("drop thread comm send");
-    }
+fn resolve<A: ToSocketAddrs>(addr: A) -> io::Result<SocketAddr> {
+    addr.to_socket_addrs()?.next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no address to listen on"))
+}
+
+/// A session token nobody can guess, or `None` if the system has no
+/// randomness to give.
+fn new_token() -> Option<String> {
+    let mut bytes = [0u8; 16];
+    SystemRandom::new().fill(&mut bytes).ok()?;
+    Some(link::hex(&bytes))
 }
 
 // struct Group {
@@ -314,6 +1108,6 @@ impl Drop for UserConnection {
 // impl Group {
 //     fn new<S: Into<String>>(name: S) -> Group {
 //         let name = name.into();
-        
+
 //     }
 // }
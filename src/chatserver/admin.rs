@@ -0,0 +1,244 @@
+//! The HTTP/JSON admin API, for scripts and CI jobs that want to talk to
+//! the server without speaking its protocol. Every answer is JSON; errors
+//! look like `{"error": "..."}`.
+//!
+//! - `GET /rooms`: every room with its members, admin and topic
+//! - `GET /rooms/{room}`: one room
+//! - `GET /rooms/{room}/history?limit=N`: its last chat messages, oldest first
+//! - `POST /rooms/{room}/messages` with `{"username", "contents"}`: posts as
+//!   a bot by that name
+//! - `DELETE /rooms/{room}/members/{user}`: kicks `user` out of the room
+//! - `GET /bans`, `PUT /bans/{user}`, `DELETE /bans/{user}`: lists, bans
+//!   (disconnecting the user) and unbans
+
+use mio::Token;
+use ring::hmac;
+use ring::rand::SystemRandom;
+use serde_json::Value;
+use message::{FailureCode, Message, MAX_FIELD};
+use super::Server;
+use super::http::{self, percent_decode, Request};
+
+type Response = (u16, Value);
+
+fn error(status: u16, message: &str) -> Response {
+    (status, json!({ "error": message }))
+}
+
+/// Whether `given` is the admin token, taking as long whatever it is: the
+/// two are compared as MACs under a throwaway key, which ring checks in
+/// constant time, so timing tells nothing about how close a guess was.
+fn token_matches(expected: &str, given: &str) -> bool {
+    let key = match hmac::Key::generate(hmac::HMAC_SHA256, &SystemRandom::new()) {
+        Ok(k) => k,
+        Err(_) => return false,
+    };
+    let tag = hmac::sign(&key, given.as_bytes());
+    hmac::verify(&key, expected.as_bytes(), tag.as_ref()).is_ok()
+}
+
+impl Server {
+    pub(super) fn admin_request(&mut self, token: Token, request: Request) {
+        let (status, body) = self.admin_route(&request);
+        println!("Admin {} {}: {}", request.method, request.path, status);
+        if let Some(conn) = self.connections.get_mut(&token) {
+            conn.send_raw(http::response(status, &body), self.config.slow_consumer);
+            conn.closing = true;
+        }
+        self.dirty.push(token);
+    }
+
+    fn admin_route(&mut self, request: &Request) -> Response {
+        if let Some(ref expected) = self.config.admin_token {
+            let given = request.header("authorization").and_then(|a| a.strip_prefix("Bearer "));
+            if !given.is_some_and(|g| token_matches(expected, g)) {
+                return error(401, "missing or wrong admin token");
+            }
+        }
+        let segments: Vec<String> = request.path.split('/')
+            .filter(|s| !s.is_empty())
+            .map(percent_decode)
+            .collect();
+        let segments: Vec<&str> = segments.iter().map(|s| s.as_str()).collect();
+        match (request.method.as_str(), &segments[..]) {
+            ("GET", ["rooms"]) => {
+                let mut names: Vec<&String> = self.groups.keys().collect();
+                names.sort();
+                let rooms: Vec<Value> = names.into_iter().map(|r| self.room_json(r)).collect();
+                (200, json!({ "rooms": rooms }))
+            },
+            ("GET", ["rooms", room]) => match self.groups.contains_key(*room) {
+                true => (200, self.room_json(room)),
+                false => error(404, "no such room"),
+            },
+            ("GET", ["rooms", room, "history"]) => self.admin_history(room, request),
+            ("POST", ["rooms", room, "messages"]) => self.admin_post(room, &request.body),
+            ("DELETE", ["rooms", room, "members", user]) => self.admin_kick(room, user),
+            ("GET", ["bans"]) => {
+                let mut banned: Vec<&String> = self.banned.iter().collect();
+                banned.sort();
+                (200, json!({ "banned": banned }))
+            },
+            ("PUT", ["bans", user]) => self.admin_ban(user),
+            ("DELETE", ["bans", user]) => match self.banned.remove(*user) {
+                true => (204, Value::Null),
+                false => error(404, "not banned"),
+            },
+            (_, ["rooms"]) | (_, ["rooms", _]) | (_, ["rooms", _, "history"]) |
+            (_, ["rooms", _, "messages"]) | (_, ["rooms", _, "members", _]) |
+            (_, ["bans"]) | (_, ["bans", _]) => error(405, "method not allowed"),
+            _ => error(404, "no such endpoint"),
+        }
+    }
+
+    fn room_json(&self, room: &str) -> Value {
+        let members = self.groups.get(room).cloned().unwrap_or_default();
+        json!({
+            "name": room,
+            "admin": members.first(),
+            "members": members,
+            "topic": self.topics.get(room),
+        })
+    }
+
+    fn admin_history(&self, room: &str, request: &Request) -> Response {
+        if !self.groups.contains_key(room) {
+            return error(404, "no such room");
+        }
+        let limit = match request.query_param("limit").map(|l| l.parse::<usize>()) {
+            None => usize::MAX,
+            Some(Ok(l)) => l,
+            Some(Err(_)) => return error(400, "limit must be a number"),
+        };
+        let messages: Vec<Value> = match self.history.get(room) {
+            Some(history) => history.iter()
+                .skip(history.len().saturating_sub(limit))
+                .map(|h| json!({
+                    "username": h.username,
+                    "contents": h.contents,
+                    "time": h.time,
+                }))
+                .collect(),
+            None => Vec::new(),
+        };
+        (200, json!({ "room": room, "messages": messages }))
+    }
+
+    /// Bots need no session: the message goes straight to the room, under a
+    /// name no connected user has.
+    fn admin_post(&mut self, room: &str, body: &[u8]) -> Response {
+        let body: Value = match serde_json::from_slice(body) {
+            Ok(b) => b,
+            Err(e) => return error(400, &format!("invalid JSON: {}", e)),
+        };
+        let field = |name: &str| body.get(name).and_then(Value::as_str).unwrap_or("");
+        let (username, contents) = (field("username"), field("contents"));
+        if username.is_empty() || contents.is_empty() {
+            return error(400, "username and contents are required");
+        }
+        if username.len() > MAX_FIELD || contents.len() > MAX_FIELD {
+            return error(400, "username and contents must fit in 255 bytes");
+        }
+        if !self.groups.contains_key(room) {
+            return error(404, "no such room");
+        }
+        if username.eq_ignore_ascii_case("Server") || self.known_user(username) {
+            return error(409, "username belongs to someone else");
+        }
+        if !self.chat_message(Message::chat_message(username, room, contents)) {
+            return error(403, "blocked by a plugin");
+        }
+        (201, json!({ "room": room, "username": username, "contents": contents }))
+    }
+
+    fn admin_kick(&mut self, room: &str, user: &str) -> Response {
+        match self.groups.get(room) {
+            Some(members) if members.iter().any(|m| m == user) => (),
+            Some(_) => return error(404, "no such member"),
+            None => return error(404, "no such room"),
+        }
+        self.kick("Server".to_owned(), room.to_owned(), user.to_owned());
+        (204, Value::Null)
+    }
+
+    fn admin_ban(&mut self, user: &str) -> Response {
+        self.banned.insert(user.to_owned());
+        if self.sessions.contains_key(user) {
+            println!("Disconnecting banned user {}", user);
+            self.fail(user, FailureCode::Banned);
+            self.terminate_connection(user.to_owned(), "Banned");
+        }
+        (200, json!({ "banned": user }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{Action, Context, Plugin, ServerBuilder};
+    use super::super::http::{Http, Request};
+    use super::token_matches;
+
+    struct Quiet;
+
+    impl Plugin for Quiet {
+        fn on_chat(&mut self, _ctx: &mut Context, _user: &str, _room: &str,
+                   _contents: &mut String) -> Action {
+            Action::Block
+        }
+    }
+
+    fn request(head: &str) -> Request {
+        let mut http = Http::default();
+        http.feed(format!("{}\r\n\r\n", head).as_bytes());
+        http.decode(&mut Vec::new()).unwrap().pop().unwrap()
+    }
+
+    #[test]
+    fn tokens_must_match_exactly() {
+        assert!(token_matches("s3cret", "s3cret"));
+        for guess in &["", "s3cre", "s3cret ", "s3cret\0", "S3cret", "x3cret"] {
+            assert!(!token_matches("s3cret", guess), "{:?}", guess);
+        }
+    }
+
+    #[test]
+    fn admin_requests_need_the_token() {
+        let mut server = ServerBuilder::new().admin_token("s3cret").build().unwrap();
+        let status = |server: &mut ::chatserver::Server, auth: &str| {
+            server.admin_route(&request(&format!("GET /rooms HTTP/1.1{}", auth))).0
+        };
+        assert_eq!(status(&mut server, ""), 401);
+        assert_eq!(status(&mut server, "\r\nAuthorization: Bearer guess"), 401);
+        assert_eq!(status(&mut server, "\r\nAuthorization: s3cret"), 401);
+        assert_eq!(status(&mut server, "\r\nAuthorization: Bearer s3cret"), 200);
+        let mut open = ServerBuilder::new().build().unwrap();
+        assert_eq!(status(&mut open, ""), 200);
+    }
+
+    #[test]
+    fn paths_are_decoded_before_routing() {
+        let mut server = ServerBuilder::new().build().unwrap();
+        assert_eq!(server.admin_route(&request("GET /rooms/Chat%31 HTTP/1.1")).0, 200);
+        assert_eq!(server.admin_route(&request("GET /rooms/Chat2 HTTP/1.1")).0, 404);
+        assert_eq!(server.admin_route(&request("PUT /bans/eve%20two HTTP/1.1")).0, 200);
+        assert!(server.banned.contains("eve two"));
+    }
+
+    #[test]
+    fn posts_go_to_the_room() {
+        let mut server = ServerBuilder::new().build().unwrap();
+        let body = br#"{"username": "bot", "contents": "hi"}"#;
+        assert_eq!(server.admin_post("Chat1", body).0, 201);
+        assert_eq!(server.history["Chat1"].len(), 1);
+        assert_eq!(server.admin_post("Nowhere", body).0, 404);
+        assert_eq!(server.admin_post("Chat1", b"{").0, 400);
+    }
+
+    #[test]
+    fn posts_a_plugin_blocks_are_forbidden() {
+        let mut server = ServerBuilder::new().plugin(Quiet).build().unwrap();
+        let body = br#"{"username": "bot", "contents": "hi"}"#;
+        assert_eq!(server.admin_post("Chat1", body).0, 403);
+        assert!(server.history.get("Chat1").is_none_or(|h| h.is_empty()));
+    }
+}
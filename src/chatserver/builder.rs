@@ -0,0 +1,215 @@
+use std::io;
+use std::net::ToSocketAddrs;
+use std::sync::Arc;
+use std::time::Duration;
+use rustls::ServerConfig;
+use super::Server;
+use super::command::Command;
+use super::plugin::Plugin;
+use super::ratelimit::{Escalation, MessageKind, Rate, RateLimits};
+use super::webhook::Webhook;
+
+/// What to do with a client whose outbound queue is full.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SlowConsumerPolicy {
+    /// Discard the oldest queued messages to make room for new ones.
+    DropOldest,
+    /// Close the connection; the client may still resume its session.
+    Disconnect,
+}
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub max_queued_messages: usize,
+    pub slow_consumer: SlowConsumerPolicy,
+    pub rate_limits: RateLimits,
+    pub max_connections: usize,
+    pub max_connections_per_ip: usize,
+    /// How long a new socket has to send `InitUser` or `Resume`.
+    pub handshake_timeout: Duration,
+    /// How many sockets may be waiting on their handshake at once.
+    pub max_pending_handshakes: usize,
+    pub tls: Option<Arc<ServerConfig>>,
+    /// How many chat messages each room remembers for the admin API.
+    pub history_size: usize,
+    /// When set, admin requests must carry `Authorization: Bearer <token>`.
+    pub admin_token: Option<String>,
+    pub webhooks: Vec<Webhook>,
+    /// How this server introduces itself to linked servers.
+    pub server_name: String,
+    /// Shared by every server allowed to link with this one.
+    pub link_secret: Option<String>,
+    /// Servers to dial and keep linked to.
+    pub links: Vec<String>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            max_queued_messages: 512,
+            slow_consumer: SlowConsumerPolicy::DropOldest,
+            rate_limits: Default::default(),
+            max_connections: 4096,
+            max_connections_per_ip: 16,
+            handshake_timeout: Duration::from_secs(5),
+            max_pending_handshakes: 256,
+            tls: None,
+            history_size: 100,
+            admin_token: None,
+            webhooks: Vec::new(),
+            server_name: "chat_np1".into(),
+            link_secret: None,
+            links: Vec::new(),
+        }
+    }
+}
+
+/// Configures and starts a chat server. `start_server` is the same as
+/// `ServerBuilder::new().start(addr)`.
+#[derive(Default)]
+pub struct ServerBuilder {
+    config: Config,
+    plugins: Vec<Box<dyn Plugin>>,
+    commands: Vec<Command>,
+}
+
+impl ServerBuilder {
+    pub fn new() -> ServerBuilder {
+        Default::default()
+    }
+
+    /// How many messages may wait to be written to a single client.
+    pub fn max_queued_messages(mut self, max: usize) -> ServerBuilder {
+        self.config.max_queued_messages = ::std::cmp::max(max, 1);
+        self
+    }
+
+    pub fn slow_consumer_policy(mut self, policy: SlowConsumerPolicy) -> ServerBuilder {
+        self.config.slow_consumer = policy;
+        self
+    }
+
+    /// Limits how often each user may send a kind of message; `None`
+    /// removes the limit.
+    pub fn rate_limit(mut self, kind: MessageKind, rate: Option<Rate>) -> ServerBuilder {
+        match rate {
+            Some(r) => self.config.rate_limits.per_kind.insert(kind, r),
+            None => self.config.rate_limits.per_kind.remove(&kind),
+        };
+        self
+    }
+
+    /// Limits how many chat messages a single chat relays, across all users.
+    pub fn room_rate_limit(mut self, rate: Option<Rate>) -> ServerBuilder {
+        self.config.rate_limits.per_room = rate;
+        self
+    }
+
+    pub fn flood_escalation(mut self, escalation: Escalation) -> ServerBuilder {
+        self.config.rate_limits.escalation = escalation;
+        self
+    }
+
+    pub fn max_connections(mut self, max: usize) -> ServerBuilder {
+        self.config.max_connections = max;
+        self
+    }
+
+    pub fn max_connections_per_ip(mut self, max: usize) -> ServerBuilder {
+        self.config.max_connections_per_ip = max;
+        self
+    }
+
+    pub fn handshake_timeout(mut self, timeout: Duration) -> ServerBuilder {
+        self.config.handshake_timeout = timeout;
+        self
+    }
+
+    pub fn max_pending_handshakes(mut self, max: usize) -> ServerBuilder {
+        self.config.max_pending_handshakes = max;
+        self
+    }
+
+    /// Makes the listener speak TLS only. See `tls::server_config`.
+    pub fn tls(mut self, config: Arc<ServerConfig>) -> ServerBuilder {
+        self.config.tls = Some(config);
+        self
+    }
+
+    /// How many recent chat messages each room keeps; 0 keeps none.
+    pub fn room_history(mut self, size: usize) -> ServerBuilder {
+        self.config.history_size = size;
+        self
+    }
+
+    /// Requires admin API requests to authenticate with this bearer token.
+    pub fn admin_token<S: Into<String>>(mut self, token: S) -> ServerBuilder {
+        self.config.admin_token = Some(token.into());
+        self
+    }
+
+    /// POSTs the events of `room` (or of every room, for `None`) as JSON
+    /// to `url`, which must be a plain `http://` URL. See `webhook.rs`.
+    pub fn webhook<S: Into<String>>(mut self, room: Option<&str>, url: S) -> ServerBuilder {
+        self.config.webhooks.push(Webhook {
+            room: room.map(|r| r.to_owned()),
+            url: url.into(),
+        });
+        self
+    }
+
+    /// Must differ between linked servers.
+    pub fn server_name<S: Into<String>>(mut self, name: S) -> ServerBuilder {
+        self.config.server_name = name.into();
+        self
+    }
+
+    /// The secret linked servers authenticate each other with. Needed for
+    /// both `link` and `Server::listen_links`.
+    pub fn link_secret<S: Into<String>>(mut self, secret: S) -> ServerBuilder {
+        self.config.link_secret = Some(secret.into());
+        self
+    }
+
+    /// Links to the server listening for links on `addr`, dialing again
+    /// whenever the link drops. See `link.rs`.
+    pub fn link<S: Into<String>>(mut self, addr: S) -> ServerBuilder {
+        self.config.links.push(addr.into());
+        self
+    }
+
+    /// Adds a plugin after those already added. See `plugin.rs`.
+    pub fn plugin<P: Plugin + 'static>(mut self, plugin: P) -> ServerBuilder {
+        self.plugins.push(Box::new(plugin));
+        self
+    }
+
+    /// Adds a slash command, replacing any other by the same name. See
+    /// `command.rs`.
+    pub fn command(mut self, command: Command) -> ServerBuilder {
+        self.commands.push(command);
+        self
+    }
+
+    /// Sets up a server with no listeners yet, for callers that want to
+    /// pick them (see `Server::listen`) or connect in-process.
+    pub fn build(self) -> io::Result<Server> {
+        if !self.config.links.is_empty() && self.config.link_secret.is_none() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "linking to other servers needs a link secret"));
+        }
+        let mut server = Server::new(self.config)?;
+        server.groups.insert("Chat1".into(), Vec::new());
+        server.plugins = self.plugins;
+        for command in self.commands {
+            server.add_command(command);
+        }
+        Ok(server)
+    }
+
+    pub fn start<A: ToSocketAddrs>(self, addr: A) {
+        let mut server = self.build().expect("Falha ao iniciar o servidor");
+        server.listen(addr).expect("Falha ao criar listener nesse endereço");
+        server.run().expect("Event loop failed");
+    }
+}
@@ -0,0 +1,147 @@
+use std::io;
+use serde_json::Value;
+use message::{FailureCode, Message};
+use transport::Frames;
+use super::event::RoomEvent;
+use super::http::{self, Http, Request};
+use super::irc::{self, Irc, Line};
+use super::link::{self, Link};
+use super::websocket::WebSocket;
+
+/// What a listener speaks. Connections translate to and from `Message`
+/// themselves, so the rest of the server never sees the difference.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Protocol {
+    Native,
+    WebSocket,
+    Irc,
+    /// The admin API; see `Server::listen_admin`.
+    Http,
+    /// Other servers; see `Server::listen_links`.
+    Link,
+}
+
+pub enum Codec {
+    Native(Frames),
+    WebSocket(WebSocket),
+    Irc(Irc),
+    Http(Http),
+    Link(Link),
+}
+
+/// What a connection received, once decoded.
+pub enum Inbound {
+    Message(Message),
+    /// IRC has too much of its own (registration, numerics, topics) to map
+    /// onto `Message` here; the server handles these lines itself.
+    Irc(Line),
+    /// Admin requests are answered by the server, never attached to a user.
+    Http(Request),
+    Link(Value),
+}
+
+impl Codec {
+    pub fn new(protocol: Protocol) -> Codec {
+        match protocol {
+            Protocol::Native => Codec::Native(Default::default()),
+            Protocol::WebSocket => Codec::WebSocket(Default::default()),
+            Protocol::Irc => Codec::Irc(Default::default()),
+            Protocol::Http => Codec::Http(Default::default()),
+            Protocol::Link => Codec::Link(Default::default()),
+        }
+    }
+
+    pub fn feed(&mut self, bytes: &[u8]) {
+        match *self {
+            Codec::Native(ref mut frames) => frames.extend(bytes),
+            Codec::WebSocket(ref mut ws) => ws.feed(bytes),
+            Codec::Irc(ref mut irc) => irc.feed(bytes),
+            Codec::Http(ref mut http) => http.feed(bytes),
+            Codec::Link(ref mut link) => link.feed(bytes),
+        }
+    }
+
+    /// Parses everything fed so far. Answers that belong to the protocol
+    /// itself (handshakes, pongs, closing) go in `replies`, already
+    /// encoded. An error means the connection should be closed once the
+    /// replies are out.
+    pub fn decode(&mut self, replies: &mut Vec<Vec<u8>>) -> io::Result<Vec<Inbound>> {
+        match *self {
+            Codec::Native(ref mut frames) => {
+                let mut messages = Vec::new();
+                while let Some(m) = frames.next_message() {
+                    match m {
+                        Ok(m) => messages.push(Inbound::Message(m)),
+                        Err(e) => {
+                            let reason = format!("Failed parsing message: {}", e);
+                            replies.push(Message::failure(FailureCode::InvalidMessage, reason)
+                                         .into_bytes()
+                                         .unwrap_or_default());
+                            if e.kind() != io::ErrorKind::InvalidData {
+                                return Err(e);
+                            }
+                        },
+                    }
+                }
+                Ok(messages)
+            },
+            Codec::WebSocket(ref mut ws) => Ok(ws.decode(replies)?
+                                               .into_iter()
+                                               .map(Inbound::Message)
+                                               .collect()),
+            Codec::Irc(ref mut irc) => Ok(irc.decode()?
+                                          .into_iter()
+                                          .map(Inbound::Irc)
+                                          .collect()),
+            Codec::Http(ref mut http) => Ok(http.decode(replies)?
+                                            .into_iter()
+                                            .map(Inbound::Http)
+                                            .collect()),
+            Codec::Link(ref mut link) => Ok(link.decode()?
+                                            .into_iter()
+                                            .map(Inbound::Link)
+                                            .collect()),
+        }
+    }
+
+    /// `username` is who the connection speaks for, if anyone yet. Empty
+    /// when the protocol has nothing to show for the message.
+    pub fn encode(&self, m: &Message, username: Option<&str>) -> Vec<u8> {
+        match *self {
+            Codec::Native(_) => m.into_bytes().unwrap_or_else(|e| {
+                eprintln!("Not sending to {}: {}", username.unwrap_or("?"), e);
+                Vec::new()
+            }),
+            Codec::WebSocket(_) => WebSocket::encode(m),
+            Codec::Irc(_) => irc::encode(m, username.unwrap_or("*")),
+            Codec::Http(_) | Codec::Link(_) => Vec::new(),
+        }
+    }
+
+    pub fn encode_event(&self, event: &RoomEvent) -> Vec<u8> {
+        match *self {
+            Codec::Native(_) | Codec::WebSocket(_) | Codec::Http(_) | Codec::Link(_) =>
+                Vec::new(),
+            Codec::Irc(_) => irc::encode_event(event),
+        }
+    }
+
+    /// Whether a client on this protocol can come back to its session
+    /// after losing the connection.
+    pub fn can_resume(&self) -> bool {
+        !matches!(*self, Codec::Irc(_))
+    }
+
+    /// What to write to a socket that is turned away before it even got
+    /// started.
+    pub fn refusal(&self, code: FailureCode) -> Vec<u8> {
+        let reason = code.text();
+        match *self {
+            Codec::Native(_) => Message::failure(code, reason).into_bytes().unwrap_or_default(),
+            Codec::WebSocket(_) => WebSocket::refusal(reason),
+            Codec::Irc(_) => irc::line(&format!("ERROR :{}", reason)),
+            Codec::Http(_) => http::error(503, reason),
+            Codec::Link(_) => link::line(&json!({ "type": "error", "reason": reason })),
+        }
+    }
+}
@@ -0,0 +1,308 @@
+//! Slash commands, parsed and run by the server so that adding one takes
+//! no change to the clients: they send `Message::Command` with whatever
+//! followed the slash, and `/help` lists what is registered here.
+//!
+//! A command's usage string doubles as its argument spec: `<name>` is
+//! required, `[name]` optional, and a last `<name...>` (or `[name...]`)
+//! takes the rest of the line, spaces included.
+
+use std::mem;
+use message::{FailureCode, Message};
+use super::Server;
+use super::event::RoomEvent;
+use super::plugin::Context;
+
+/// Who may run a command. Anything past `Anyone` is about the chat the
+/// user sent it from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Permission {
+    Anyone,
+    Member,
+    /// The chat's admin, that is its oldest member.
+    Admin,
+}
+
+/// One run of a command, with its arguments already checked against the
+/// usage.
+#[derive(Clone, Debug)]
+pub struct Call {
+    pub user: String,
+    /// Set whenever the command needs a chat.
+    pub room: Option<String>,
+    pub args: Vec<String>,
+}
+
+impl Call {
+    /// The `i`th argument, or "" for a missing optional one.
+    pub fn arg(&self, i: usize) -> &str {
+        self.args.get(i).map_or("", |a| a.as_str())
+    }
+}
+
+/// Why a command couldn't run, with the words to say it in.
+type Refusal = (FailureCode, String);
+type Builtin = fn(&mut Server, &Call) -> Result<(), Refusal>;
+type Handler = Box<dyn FnMut(&mut Context, &Call) -> Result<(), String> + Send>;
+
+enum Run {
+    Builtin(Builtin),
+    Custom(Handler),
+}
+
+/// A command that can be added with `ServerBuilder::command`. An `Err`
+/// from its handler goes back to the user as a failure, coded `Other`.
+pub struct Command {
+    name: String,
+    usage: String,
+    summary: String,
+    permission: Permission,
+    run: Run,
+}
+
+impl Command {
+    pub fn new<S, F>(name: S, usage: S, summary: S, permission: Permission, handler: F) -> Command
+        where S: Into<String>,
+              F: FnMut(&mut Context, &Call) -> Result<(), String> + Send + 'static
+    {
+        Command {
+            name: name.into(),
+            usage: usage.into(),
+            summary: summary.into(),
+            permission,
+            run: Run::Custom(Box::new(handler)),
+        }
+    }
+
+    fn builtin(name: &str, usage: &str, summary: &str, permission: Permission,
+               run: Builtin) -> Command {
+        Command {
+            name: name.to_owned(),
+            usage: usage.to_owned(),
+            summary: summary.to_owned(),
+            permission,
+            run: Run::Builtin(run),
+        }
+    }
+
+    fn synopsis(&self) -> String {
+        match self.usage.is_empty() {
+            true => format!("/{}", self.name),
+            false => format!("/{} {}", self.name, self.usage),
+        }
+    }
+
+    /// Its line in `/help`, with the synopsis padded to `width`.
+    fn describe(&self, width: usize) -> String {
+        let note = match self.permission {
+            Permission::Admin => " (chat admin)",
+            _ => "",
+        };
+        format!("{:width$} -- {}{}", self.synopsis(), self.summary, note, width = width)
+    }
+
+    /// Splits `rest` into arguments as the usage says, or tells how to
+    /// call the command.
+    fn parse(&self, rest: &str) -> Result<Vec<String>, Refusal> {
+        let params: Vec<&str> = self.usage.split_whitespace().collect();
+        let required = params.iter().filter(|p| p.starts_with('<')).count();
+        let mut args = Vec::new();
+        let mut rest = rest.trim();
+        for (i, param) in params.iter().enumerate() {
+            if rest.is_empty() {
+                break;
+            }
+            if i + 1 == params.len() && (param.ends_with("...>") || param.ends_with("...]")) {
+                args.push(rest.to_owned());
+                rest = "";
+                break;
+            }
+            let (arg, tail) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+            args.push(arg.to_owned());
+            rest = tail.trim_start();
+        }
+        if args.len() < required || !rest.is_empty() {
+            return Err((FailureCode::BadUsage, format!("Usage: {}", self.synopsis())));
+        }
+        Ok(args)
+    }
+}
+
+/// What every server knows, in the order `/help` lists them.
+pub fn builtins() -> Vec<Command> {
+    use self::Permission::*;
+    vec![
+        Command::builtin("help", "[command]", "list commands, or show how to use one",
+                         Anyone, help),
+        Command::builtin("list", "", "show available chats", Anyone, list),
+        Command::builtin("online", "", "list the users in this chat", Member, online),
+        Command::builtin("join", "<chat>", "leave this chat and join another", Anyone, join),
+        Command::builtin("new", "<chat>", "create a chat and move to it", Anyone, new),
+        Command::builtin("leave", "", "leave this chat", Member, leave),
+        Command::builtin("msg", "<user> <text...>", "send a private message", Anyone, msg),
+        Command::builtin("kick", "<user>", "kick a user out of this chat", Admin, kick),
+        Command::builtin("topic", "[topic...]", "set this chat's topic, or clear it",
+                         Admin, topic),
+    ]
+}
+
+impl Server {
+    pub(super) fn add_command(&mut self, command: Command) {
+        match self.commands.iter().position(|c| c.name == command.name) {
+            Some(i) => self.commands[i] = command,
+            None => self.commands.push(command),
+        }
+    }
+
+    /// Runs a command from `user`, answering with a failure if it can't.
+    /// `user` is who the connection logged in as: `read_from` refuses
+    /// messages naming anyone else.
+    pub(super) fn run_command(&mut self, user: String, room: String, line: String) {
+        let (name, rest) = line.trim_start().split_once(char::is_whitespace)
+            .unwrap_or((line.trim(), ""));
+        let name = name.trim_start_matches('/').to_lowercase();
+        let index = match self.commands.iter().position(|c| c.name == name) {
+            Some(i) => i,
+            None => return self.fail(&user, FailureCode::NoSuchCommand),
+        };
+        let result = self.check_command(index, &user, &room, rest)
+            .and_then(|call| self.call_command(index, &call));
+        if let Err((code, reason)) = result {
+            self.failure_message(&user, code, reason);
+        }
+    }
+
+    fn check_command(&self, index: usize, user: &str, room: &str, rest: &str)
+                     -> Result<Call, Refusal> {
+        let command = &self.commands[index];
+        let args = command.parse(rest)?;
+        let members = self.groups.get(room).filter(|m| m.iter().any(|u| u == user));
+        let room = match (command.permission, members) {
+            (Permission::Anyone, _) => members.map(|_| room.to_owned()),
+            (_, None) => return Err(refuse(FailureCode::NotInChat)),
+            (Permission::Admin, Some(m)) if m.first().map(|a| a.as_str()) != Some(user) =>
+                return Err(refuse(FailureCode::NotAdmin)),
+            (_, Some(_)) => Some(room.to_owned()),
+        };
+        Ok(Call { user: user.to_owned(), room, args })
+    }
+
+    fn call_command(&mut self, index: usize, call: &Call) -> Result<(), Refusal> {
+        if let Run::Builtin(run) = self.commands[index].run {
+            return run(self, call);
+        }
+        // Taken out for the call, like plugins: the handler borrows it.
+        let mut commands = mem::take(&mut self.commands);
+        let mut ctx = Context::new(&call.user);
+        let result = match commands[index].run {
+            Run::Custom(ref mut handler) => handler(&mut ctx, call),
+            Run::Builtin(_) => unreachable!(),
+        };
+        self.commands = commands;
+        self.answer(ctx);
+        result.map_err(|reason| (FailureCode::Other, reason))
+    }
+}
+
+fn refuse(code: FailureCode) -> Refusal {
+    (code, code.text().to_owned())
+}
+
+/// Handles `message` as if the user had sent it, flood limits and mutes
+/// included, so that a command is no way around them.
+fn act(server: &mut Server, message: Message) -> Result<(), Refusal> {
+    let user = message.sender().unwrap_or_default().to_owned();
+    if server.admit(&user, &message) {
+        server.communicate_message(message);
+    }
+    Ok(())
+}
+
+fn help(server: &mut Server, call: &Call) -> Result<(), Refusal> {
+    let lines: Vec<String> = match call.args.first() {
+        Some(name) => {
+            let name = name.trim_start_matches('/').to_lowercase();
+            match server.commands.iter().find(|c| c.name == name) {
+                Some(c) => vec![c.describe(0)],
+                None => return Err(refuse(FailureCode::NoSuchCommand)),
+            }
+        },
+        None => {
+            let width = server.commands.iter().map(|c| c.synopsis().len()).max().unwrap_or(0);
+            let mut lines = vec!["Available commands:".to_owned()];
+            lines.extend(server.commands.iter().map(|c| c.describe(width)));
+            lines
+        },
+    };
+    for line in lines {
+        server.send_chat_message_to_user(&call.user, line);
+    }
+    Ok(())
+}
+
+fn list(server: &mut Server, call: &Call) -> Result<(), Refusal> {
+    let groups = server.groups_list();
+    server.send_chat_message_to_user(&call.user, groups);
+    Ok(())
+}
+
+fn online(server: &mut Server, call: &Call) -> Result<(), Refusal> {
+    let room = call.room.clone().unwrap_or_default();
+    server.list_users(Message::ListUsers(call.user.clone(), room));
+    Ok(())
+}
+
+fn join(server: &mut Server, call: &Call) -> Result<(), Refusal> {
+    let target = call.arg(0);
+    if !server.groups.contains_key(target) {
+        return Err(refuse(FailureCode::NoSuchChat));
+    }
+    let login = Message::login(call.user.as_str(), target);
+    if !server.admit(&call.user, &login) {
+        return Ok(());
+    }
+    // The other chats are only left once the user is in, so that a
+    // refused join changes nothing.
+    let rooms = server.rooms_of(&call.user);
+    let was_in = rooms.iter().any(|r| r == target);
+    server.login(login);
+    let joined = !was_in && server.groups.get(target).is_some_and(|g| g.contains(&call.user));
+    if joined {
+        for room in rooms {
+            server.logout(Message::logout(call.user.as_str(), &room));
+        }
+    }
+    Ok(())
+}
+
+fn new(server: &mut Server, call: &Call) -> Result<(), Refusal> {
+    act(server, Message::new_chat(call.user.as_str(), call.arg(0)))
+}
+
+fn leave(server: &mut Server, call: &Call) -> Result<(), Refusal> {
+    let room = call.room.clone().unwrap_or_default();
+    server.logout(Message::Logout(call.user.clone(), room));
+    Ok(())
+}
+
+fn msg(server: &mut Server, call: &Call) -> Result<(), Refusal> {
+    act(server, Message::private_message(call.user.as_str(), call.arg(0), call.arg(1)))
+}
+
+fn kick(server: &mut Server, call: &Call) -> Result<(), Refusal> {
+    let room = call.room.clone().unwrap_or_default();
+    act(server, Message::KickUser(call.user.clone(), room, call.arg(0).to_owned()))
+}
+
+fn topic(server: &mut Server, call: &Call) -> Result<(), Refusal> {
+    let room = call.room.clone().unwrap_or_default();
+    let topic = call.arg(0).to_owned();
+    if topic.is_empty() {
+        server.topics.remove(&room);
+    } else {
+        server.topics.insert(room.clone(), topic.clone());
+    }
+    let done = if topic.is_empty() { "Topic cleared" } else { "Topic set" };
+    server.send_chat_message_to_user(&call.user, done);
+    server.announce(RoomEvent::Topic { user: call.user.clone(), room, topic });
+    Ok(())
+}
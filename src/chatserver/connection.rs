@@ -0,0 +1,294 @@
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Instant;
+use rustls::{ServerConfig, ServerConnection};
+use message::Message;
+use super::SlowConsumerPolicy;
+use super::codec::{Codec, Inbound, Protocol};
+use super::event::RoomEvent;
+use super::irc::Irc;
+use super::link::Link;
+use super::stream::Stream;
+
+/// A non-blocking client socket owned by the event loop, along with the
+/// bytes read but not yet parsed and a bounded queue of messages not yet
+/// written.
+pub struct Connection {
+    pub socket: Stream,
+    /// Only known for TCP peers.
+    pub addr: Option<SocketAddr>,
+    pub opened: Instant,
+    /// The user this socket speaks for, if any.
+    pub username: Option<String>,
+    /// Set once the handshake (`InitUser` or `Resume`) is done.
+    pub handshake_done: bool,
+    /// Set when the peer hung up or the socket failed.
+    pub closed: bool,
+    /// Set once the last reply is queued; the socket is closed as soon as
+    /// it has been written out.
+    pub closing: bool,
+    /// Present when the listener speaks TLS; sits between the socket and
+    /// the buffers below.
+    tls: Option<ServerConnection>,
+    codec: Codec,
+    write_queue: VecDeque<Vec<u8>>,
+    /// How much of the front of `write_queue` already went out.
+    written: usize,
+    max_queued: usize,
+}
+
+impl Connection {
+    pub fn new(socket: Stream,
+               addr: Option<SocketAddr>,
+               max_queued: usize,
+               tls: Option<&Arc<ServerConfig>>,
+               protocol: Protocol) -> io::Result<Connection> {
+        let tls = match tls {
+            Some(config) => Some(ServerConnection::new(config.clone())
+                                 .map_err(|e| io::Error::other(e.to_string()))?),
+            None => None,
+        };
+        Ok(Connection {
+            socket,
+            addr,
+            opened: Instant::now(),
+            username: None,
+            handshake_done: false,
+            closed: false,
+            closing: false,
+            tls,
+            codec: Codec::new(protocol),
+            write_queue: VecDeque::new(),
+            written: 0,
+            max_queued,
+        })
+    }
+
+    /// Drains the socket and returns every complete message received so far.
+    pub fn read_messages(&mut self) -> Vec<Inbound> {
+        if self.tls.is_some() {
+            self.read_tls();
+        } else {
+            self.read_plain();
+        }
+        let mut replies = Vec::new();
+        let decoded = self.codec.decode(&mut replies);
+        self.write_queue.extend(replies);
+        // The TLS handshake and key updates need answers of their own too.
+        self.flush();
+        match decoded {
+            Ok(messages) => messages,
+            Err(e) => {
+                eprintln!("Dropping {}: {}", peer_name(self.addr), e);
+                self.closed = true;
+                Vec::new()
+            },
+        }
+    }
+
+    fn read_plain(&mut self) {
+        let mut chunk = [0u8; 4096];
+        loop {
+            match self.socket.read(&mut chunk) {
+                Ok(0) => {
+                    self.closed = true;
+                    break;
+                },
+                Ok(n) => self.codec.feed(&chunk[..n]),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(_) => {
+                    self.closed = true;
+                    break;
+                },
+            }
+        }
+    }
+
+    fn read_tls(&mut self) {
+        let tls = match self.tls {
+            Some(ref mut tls) => tls,
+            None => return,
+        };
+        let mut chunk = [0u8; 4096];
+        loop {
+            let more = match tls.read_tls(&mut self.socket) {
+                Ok(0) => {
+                    self.closed = true;
+                    false
+                },
+                Ok(_) => match tls.process_new_packets() {
+                    Ok(_) => true,
+                    Err(e) => {
+                        eprintln!("TLS error from {}: {}", peer_name(self.addr), e);
+                        self.closed = true;
+                        false
+                    },
+                },
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => false,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(_) => {
+                    self.closed = true;
+                    false
+                },
+            };
+            // Drained after every read: rustls won't read any more once
+            // its plaintext buffer is full.
+            loop {
+                match tls.reader().read(&mut chunk) {
+                    Ok(0) => {
+                        self.closed = true;
+                        break;
+                    },
+                    Ok(n) => self.codec.feed(&chunk[..n]),
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                    Err(_) => {
+                        self.closed = true;
+                        break;
+                    },
+                }
+            }
+            if !more {
+                break;
+            }
+        }
+    }
+
+    /// Queues a message and writes as much of the queue as the socket takes.
+    /// Returns false if the queue was full and the policy was to disconnect.
+    pub fn send(&mut self, m: &Message, policy: SlowConsumerPolicy) -> bool {
+        let frame = self.codec.encode(m, self.username.as_deref());
+        self.enqueue(frame, policy)
+    }
+
+    /// Same as `send`, for protocols that can show room events at all.
+    pub fn send_event(&mut self, event: &RoomEvent, policy: SlowConsumerPolicy) -> bool {
+        let frame = self.codec.encode_event(event);
+        self.enqueue(frame, policy)
+    }
+
+    /// Same as `send`, for bytes already in the connection's protocol.
+    pub fn send_raw(&mut self, frame: Vec<u8>, policy: SlowConsumerPolicy) -> bool {
+        self.enqueue(frame, policy)
+    }
+
+    /// IRC registration state, for IRC connections.
+    pub fn irc(&mut self) -> Option<&mut Irc> {
+        match self.codec {
+            Codec::Irc(ref mut irc) => Some(irc),
+            _ => None,
+        }
+    }
+
+    /// Handshake state, for links to other servers.
+    pub fn link(&mut self) -> Option<&mut Link> {
+        match self.codec {
+            Codec::Link(ref mut link) => Some(link),
+            _ => None,
+        }
+    }
+
+    pub fn can_resume(&self) -> bool {
+        self.codec.can_resume()
+    }
+
+    fn enqueue(&mut self, frame: Vec<u8>, policy: SlowConsumerPolicy) -> bool {
+        if self.closed || frame.is_empty() {
+            return true;
+        }
+        if self.write_queue.len() >= self.max_queued {
+            match policy {
+                SlowConsumerPolicy::Disconnect => {
+                    self.closed = true;
+                    return false;
+                },
+                SlowConsumerPolicy::DropOldest => {
+                    // A partially written message has to go out whole.
+                    let oldest = if self.written > 0 { 1 } else { 0 };
+                    self.write_queue.remove(oldest);
+                },
+            }
+        }
+        self.write_queue.push_back(frame);
+        self.flush();
+        true
+    }
+
+    pub fn flush(&mut self) {
+        loop {
+            if !self.write_records() {
+                return;
+            }
+            let frame = match self.write_queue.front() {
+                Some(f) => f,
+                None => return,
+            };
+            if let Some(ref mut tls) = self.tls {
+                // Only handed over once the previous records went out, so
+                // rustls never buffers more than one message.
+                if tls.writer().write_all(&frame[self.written..]).is_err() {
+                    self.closed = true;
+                    return;
+                }
+                self.write_queue.pop_front();
+                self.written = 0;
+                continue;
+            }
+            match self.socket.write(&frame[self.written..]) {
+                Ok(0) => {
+                    self.closed = true;
+                    return;
+                },
+                Ok(n) => {
+                    self.written += n;
+                    if self.written == frame.len() {
+                        self.write_queue.pop_front();
+                        self.written = 0;
+                    }
+                },
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(_) => {
+                    self.closed = true;
+                    return;
+                },
+            }
+        }
+    }
+
+    /// Writes out pending TLS records; false if the socket can't take them
+    /// all right now.
+    fn write_records(&mut self) -> bool {
+        let tls = match self.tls {
+            Some(ref mut tls) => tls,
+            None => return true,
+        };
+        while tls.wants_write() {
+            match tls.write_tls(&mut self.socket) {
+                Ok(_) => (),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return false,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(_) => {
+                    self.closed = true;
+                    return false;
+                },
+            }
+        }
+        true
+    }
+
+    /// Whether the socket has nothing left to do and can be dropped.
+    pub fn finished(&self) -> bool {
+        self.closed || (self.closing && !self.wants_write())
+    }
+
+    pub fn wants_write(&self) -> bool {
+        !self.write_queue.is_empty() || self.tls.as_ref().is_some_and(|t| t.wants_write())
+    }
+}
+
+pub fn peer_name(addr: Option<SocketAddr>) -> String {
+    addr.map_or_else(|| "local client".to_owned(), |a| a.to_string())
+}
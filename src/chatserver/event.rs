@@ -0,0 +1,12 @@
+/// Something that happened to a chat's membership, announced to the people
+/// in it. The native protocol has no way of showing these, so only
+/// gateways that do (IRC) pass them on.
+#[derive(Clone, Debug)]
+pub enum RoomEvent {
+    Joined { user: String, room: String },
+    Left { user: String, room: String },
+    Kicked { by: String, user: String, room: String },
+    /// The user is gone from the server, and so from every chat in `rooms`.
+    Quit { user: String, rooms: Vec<String>, reason: String },
+    Topic { user: String, room: String, topic: String },
+}
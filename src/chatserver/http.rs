@@ -0,0 +1,239 @@
+//! The little HTTP/1.1 the admin API needs: one request per connection,
+//! bodies by `Content-Length` only, and the connection closed after the
+//! response.
+
+use std::io;
+use serde_json::Value;
+
+const MAX_HEADER: usize = 8 * 1024;
+const MAX_BODY: usize = 64 * 1024;
+
+#[derive(Default)]
+pub struct Http {
+    buf: Vec<u8>,
+    /// Set once the request is in; anything after it is ignored.
+    done: bool,
+}
+
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub query: String,
+    headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Http {
+    pub fn feed(&mut self, bytes: &[u8]) {
+        if !self.done {
+            self.buf.extend_from_slice(bytes);
+        }
+    }
+
+    pub fn decode(&mut self, replies: &mut Vec<Vec<u8>>) -> io::Result<Vec<Request>> {
+        if self.done {
+            return Ok(Vec::new());
+        }
+        let end = match self.buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            Some(p) => p + 4,
+            None if self.buf.len() > MAX_HEADER =>
+                return Err(fail(replies, 431, "request header too large")),
+            None => return Ok(Vec::new()),
+        };
+        let head = String::from_utf8_lossy(&self.buf[..end]).into_owned();
+        let mut lines = head.split("\r\n");
+        let mut request_line = lines.next().unwrap_or("").split(' ');
+        let (method, target) = match (request_line.next(), request_line.next()) {
+            (Some(m), Some(t)) if !m.is_empty() => (m.to_owned(), t.to_owned()),
+            _ => return Err(fail(replies, 400, "malformed request line")),
+        };
+        let headers: Vec<(String, String)> = lines
+            .filter_map(|l| l.split_once(':'))
+            .map(|(n, v)| (n.trim().to_ascii_lowercase(), v.trim().to_owned()))
+            .collect();
+        let header = |name: &str| headers.iter().find(|&(n, _)| n == name).map(|(_, v)| v);
+        if header("transfer-encoding").is_some() {
+            return Err(fail(replies, 501, "only Content-Length bodies are supported"));
+        }
+        let length = match header("content-length").map(|l| l.parse::<usize>()) {
+            None => 0,
+            Some(Ok(l)) if l <= MAX_BODY => l,
+            Some(Ok(_)) => return Err(fail(replies, 413, "request body too large")),
+            Some(Err(_)) => return Err(fail(replies, 400, "invalid Content-Length")),
+        };
+        if self.buf.len() < end + length {
+            return Ok(Vec::new());
+        }
+        let body = self.buf[end..end + length].to_vec();
+        self.buf = Vec::new();
+        self.done = true;
+        let (path, query) = match target.split_once('?') {
+            Some((p, q)) => (p.to_owned(), q.to_owned()),
+            None => (target, String::new()),
+        };
+        Ok(vec![Request { method, path, query, headers, body }])
+    }
+}
+
+impl Request {
+    /// `name` in lowercase.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|&(n, _)| n == name).map(|(_, v)| v.as_str())
+    }
+
+    pub fn query_param(&self, name: &str) -> Option<String> {
+        self.query.split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .find(|&(n, _)| n == name)
+            .map(|(_, v)| percent_decode(v))
+    }
+}
+
+/// Decodes `%XX` escapes (and `+` as a space) in a path segment or query
+/// value. Malformed escapes are kept as they are.
+pub fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => {
+                // Two hex digits, not anything `from_str_radix` takes: it
+                // would read `%+1` as 1.
+                let escaped = bytes.get(i + 1..i + 3)
+                    .filter(|hex| hex.iter().all(u8::is_ascii_hexdigit))
+                    .and_then(|hex| ::std::str::from_utf8(hex).ok())
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+                match escaped {
+                    Some(b) => {
+                        out.push(b);
+                        i += 3;
+                        continue;
+                    },
+                    None => out.push(b'%'),
+                }
+            },
+            b'+' => out.push(b' '),
+            b => out.push(b),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// A complete response; `204` goes without a body.
+pub fn response(status: u16, body: &Value) -> Vec<u8> {
+    let body = if status == 204 { String::new() } else { body.to_string() };
+    format!("HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\
+             Connection: close\r\n\r\n{}",
+            status, reason(status), body.len(), body).into_bytes()
+}
+
+pub fn error(status: u16, message: &str) -> Vec<u8> {
+    response(status, &json!({ "error": message }))
+}
+
+fn fail(replies: &mut Vec<Vec<u8>>, status: u16, message: &str) -> io::Error {
+    replies.push(error(status, message));
+    io::Error::new(io::ErrorKind::InvalidData, message.to_owned())
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        201 => "Created",
+        204 => "No Content",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        411 => "Length Required",
+        413 => "Payload Too Large",
+        431 => "Request Header Fields Too Large",
+        501 => "Not Implemented",
+        503 => "Service Unavailable",
+        _ => "",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Feeds `bytes` in one go, returning the request or the status of the
+    /// error sent back.
+    fn parse(bytes: &[u8]) -> Result<Option<Request>, u16> {
+        let mut http = Http::default();
+        let mut replies = Vec::new();
+        http.feed(bytes);
+        match http.decode(&mut replies) {
+            Ok(mut requests) => Ok(requests.pop()),
+            Err(_) => Err(status(&replies[0])),
+        }
+    }
+
+    fn status(response: &[u8]) -> u16 {
+        String::from_utf8_lossy(&response[9..12]).parse().unwrap()
+    }
+
+    #[test]
+    fn requests_are_split_up() {
+        let request = parse(b"GET /rooms/Chat1/history?limit=5&x=a%20b HTTP/1.1\r\n\
+                              Host: localhost\r\nAuthorization:  Bearer t0k \r\n\r\n")
+            .unwrap().unwrap();
+        assert_eq!(request.method, "GET");
+        assert_eq!(request.path, "/rooms/Chat1/history");
+        assert_eq!(request.query_param("limit").as_deref(), Some("5"));
+        assert_eq!(request.query_param("x").as_deref(), Some("a b"));
+        assert_eq!(request.query_param("y"), None);
+        assert_eq!(request.header("authorization"), Some("Bearer t0k"));
+        assert_eq!(request.header("host"), Some("localhost"));
+        assert!(request.body.is_empty());
+    }
+
+    #[test]
+    fn bodies_come_by_content_length() {
+        let request = parse(b"POST /x HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello, and more")
+            .unwrap().unwrap();
+        assert_eq!(request.body, b"hello");
+        let mut http = Http::default();
+        let mut replies = Vec::new();
+        http.feed(b"POST /x HTTP/1.1\r\nContent-Length: 5\r\n\r\nhel");
+        assert!(http.decode(&mut replies).unwrap().is_empty());
+        http.feed(b"lo");
+        assert_eq!(http.decode(&mut replies).unwrap()[0].body, b"hello");
+        // One request per connection.
+        http.feed(b"GET / HTTP/1.1\r\n\r\n");
+        assert!(http.decode(&mut replies).unwrap().is_empty());
+        assert!(replies.is_empty());
+    }
+
+    #[test]
+    fn bad_requests_get_an_error() {
+        assert_eq!(parse(b"GET\r\n\r\n").err(), Some(400));
+        assert_eq!(parse(b" / HTTP/1.1\r\n\r\n").err(), Some(400));
+        assert_eq!(parse(b"POST / HTTP/1.1\r\nContent-Length: five\r\n\r\n").err(), Some(400));
+        assert_eq!(parse(b"POST / HTTP/1.1\r\nContent-Length: -1\r\n\r\n").err(), Some(400));
+        let too_big = format!("POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n", MAX_BODY + 1);
+        assert_eq!(parse(too_big.as_bytes()).err(), Some(413));
+        assert_eq!(parse(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n").err(),
+                   Some(501));
+        assert_eq!(parse(&[b'x'; MAX_HEADER + 1]).err(), Some(431));
+        // Still waiting, as long as the header may yet end.
+        assert!(parse(&[b'x'; MAX_HEADER]).unwrap().is_none());
+    }
+
+    #[test]
+    fn percent_escapes_are_decoded() {
+        assert_eq!(percent_decode("a%2Fb+c%41"), "a/b cA");
+        assert_eq!(percent_decode("caf%C3%A9"), "caf\u{e9}");
+        // Malformed escapes stay as they are.
+        assert_eq!(percent_decode("100%"), "100%");
+        assert_eq!(percent_decode("%4"), "%4");
+        assert_eq!(percent_decode("%zz%+1%-1"), "%zz% 1%-1");
+        // What isn't UTF-8 is replaced, never passed on.
+        assert_eq!(percent_decode("%FFok%C3"), "\u{fffd}ok\u{fffd}");
+    }
+}
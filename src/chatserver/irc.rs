@@ -0,0 +1,672 @@
+//! A subset of IRC (RFC 2812) on top of the server's own chats: NICK, USER,
+//! JOIN, PART, PRIVMSG, NOTICE, NAMES, LIST, KICK, TOPIC and QUIT, plus what
+//! clients send on their own (PING, CAP, MODE, WHO). Nicknames are
+//! usernames, `#name` is the chat `name`, and a chat's admin shows up as
+//! its only operator.
+
+use std::io;
+use mio::Token;
+use message::{Message, MAX_FIELD};
+use super::Server;
+use super::event::RoomEvent;
+
+const SERVER_NAME: &str = "chat_np1";
+const MAX_LINE: usize = 8 * 1024;
+const NAMES_PER_LINE: usize = 40;
+
+/// Registration state and unparsed input of one IRC connection.
+#[derive(Default)]
+pub struct Irc {
+    buf: Vec<u8>,
+    nick: Option<String>,
+    user_sent: bool,
+}
+
+/// A command from the client. Tags and prefix are dropped.
+pub struct Line {
+    pub command: String,
+    pub params: Vec<String>,
+}
+
+impl Irc {
+    pub fn feed(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    pub fn decode(&mut self) -> io::Result<Vec<Line>> {
+        let mut lines = Vec::new();
+        while let Some(pos) = self.buf.iter().position(|&b| b == b'\n') {
+            let raw: Vec<u8> = self.buf.drain(..pos + 1).collect();
+            if let Some(line) = Line::parse(String::from_utf8_lossy(&raw).trim_end()) {
+                lines.push(line);
+            }
+        }
+        if self.buf.len() > MAX_LINE {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "IRC line too long"));
+        }
+        Ok(lines)
+    }
+}
+
+impl Line {
+    fn parse(text: &str) -> Option<Line> {
+        let mut rest = text.trim_start();
+        if rest.starts_with('@') {
+            rest = rest.split_once(' ')?.1.trim_start();
+        }
+        if rest.starts_with(':') {
+            rest = rest.split_once(' ')?.1.trim_start();
+        }
+        let (command, mut rest) = rest.split_once(' ').unwrap_or((rest, ""));
+        if command.is_empty() {
+            return None;
+        }
+        let mut params = Vec::new();
+        loop {
+            rest = rest.trim_start_matches(' ');
+            if rest.is_empty() {
+                break;
+            }
+            if let Some(trailing) = rest.strip_prefix(':') {
+                params.push(trailing.to_owned());
+                break;
+            }
+            let (param, remaining) = rest.split_once(' ').unwrap_or((rest, ""));
+            params.push(param.to_owned());
+            rest = remaining;
+        }
+        Some(Line {
+            command: command.to_ascii_uppercase(),
+            params,
+        })
+    }
+
+    fn param(&self, i: usize) -> Option<&str> {
+        self.params.get(i).map(|p| p.as_str())
+    }
+}
+
+/// `text` as one IRC line. Whatever it holds, it can't end the line early
+/// and slip in another: line breaks and NULs become spaces.
+pub fn line(text: &str) -> Vec<u8> {
+    format!("{}\r\n", text.replace(['\r', '\n', '\0'], " ")).into_bytes()
+}
+
+/// A name as a single IRC parameter. Names from linked servers or native
+/// clients may have spaces, which would split it.
+fn word(name: &str) -> String {
+    name.replace([' ', '\r', '\n', '\0'], "_")
+}
+
+fn source(user: &str) -> String {
+    format!("{0}!{0}@{1}", word(user), SERVER_NAME)
+}
+
+fn channel(room: &str) -> String {
+    format!("#{}", word(room))
+}
+
+fn room_of(channel: &str) -> Option<&str> {
+    channel.strip_prefix('#').filter(|r| valid_room(r))
+}
+
+/// Whether IRC users could name this chat: a channel name can't be empty,
+/// nor hold spaces, commas, colons or control characters.
+pub(super) fn valid_room(room: &str) -> bool {
+    !room.is_empty()
+        && !room.contains(|c: char| c.is_control() || c == ' ' || c == ',' || c == ':')
+}
+
+/// One IRC line per line of `text`, since IRC has no way of escaping them.
+fn per_line(out: &mut Vec<u8>, text: &str, head: &str) {
+    for l in text.split(['\r', '\n']).filter(|l| !l.is_empty()) {
+        out.extend(line(&format!("{} :{}", head, l)));
+    }
+}
+
+/// How `m`, addressed to `me`, looks to an IRC client. Replies to things
+/// IRC clients never send, and confirmations IRC shows as room events
+/// instead (joining, leaving), come out empty.
+pub fn encode(m: &Message, me: &str) -> Vec<u8> {
+    let mut out = Vec::new();
+    match *m {
+        Message::ChatMessage(ref from, ref room, ref text)
+            if from == "Server" && room == "(SERVER)" =>
+            per_line(&mut out, text, &format!(":{} NOTICE {}", SERVER_NAME, me)),
+        Message::ChatMessage(ref from, ref room, ref text) =>
+            per_line(&mut out, text, &format!(":{} PRIVMSG {}", source(from), channel(room))),
+        Message::PrivateMessage(ref from, _, ref text) =>
+            per_line(&mut out, text, &format!(":{} PRIVMSG {}", source(from), me)),
+        Message::Failure(_, ref reason, _) =>
+            per_line(&mut out, reason, &format!(":{} NOTICE {}", SERVER_NAME, me)),
+        Message::ConnectionTermination(_) => out = line("ERROR :Closing link"),
+        _ => (),
+    }
+    out
+}
+
+pub fn encode_event(event: &RoomEvent) -> Vec<u8> {
+    let text = match *event {
+        RoomEvent::Joined { ref user, ref room } =>
+            format!(":{} JOIN {}", source(user), channel(room)),
+        RoomEvent::Left { ref user, ref room } =>
+            format!(":{} PART {}", source(user), channel(room)),
+        RoomEvent::Kicked { ref by, ref user, ref room } =>
+            format!(":{} KICK {} {} :{}", source(by), channel(room), word(user), by),
+        RoomEvent::Quit { ref user, ref reason, .. } =>
+            format!(":{} QUIT :{}", source(user), reason),
+        RoomEvent::Topic { ref user, ref room, ref topic } =>
+            format!(":{} TOPIC {} :{}", source(user), channel(room), topic),
+    };
+    line(&text)
+}
+
+fn valid_nick(nick: &str) -> bool {
+    !nick.is_empty() && nick.len() <= 32
+        && !nick.starts_with(['#', '&', ':'])
+        && !nick.contains([' ', ',', '*', '?', '!', '@'])
+        && !nick.eq_ignore_ascii_case("Server")
+}
+
+impl Server {
+    pub(super) fn irc_command(&mut self, token: Token, line: Line) {
+        let nick = match self.connections.get(&token) {
+            Some(conn) => conn.username.clone(),
+            None => return,
+        };
+        let nick = match (line.command.as_str(), nick) {
+            ("PING", _) => {
+                let text = format!(":{} PONG {} :{}", SERVER_NAME, SERVER_NAME,
+                                   line.param(0).unwrap_or(""));
+                return self.irc_send(token, text);
+            },
+            ("PONG", _) | ("PASS", None) => return,
+            ("CAP", _) => return self.irc_cap(token, &line),
+            ("NICK", None) => return self.irc_nick(token, &line),
+            ("USER", None) => {
+                if line.params.len() < 4 {
+                    return self.irc_numeric(token, "461", "USER :Not enough parameters");
+                }
+                if let Some(irc) = self.connections.get_mut(&token).and_then(|c| c.irc()) {
+                    irc.user_sent = true;
+                }
+                return self.irc_register(token);
+            },
+            ("QUIT", None) => return self.close_connection(token),
+            (_, None) => return self.irc_numeric(token, "451", ":You have not registered"),
+            ("NICK", Some(_)) =>
+                return self.irc_numeric(token, "484", ":Changing nicknames is not supported"),
+            ("USER", Some(_)) | ("PASS", Some(_)) =>
+                return self.irc_numeric(token, "462", ":You may not reregister"),
+            (_, Some(nick)) => nick,
+        };
+        // Everything ends up in native messages, whose fields can't be
+        // longer than this.
+        if line.params.iter().any(|p| p.len() > MAX_FIELD) {
+            return self.irc_numeric(token, "417", ":Input line was too long");
+        }
+        match line.command.as_str() {
+            "JOIN" => self.irc_join(token, &nick, &line),
+            "PART" => self.irc_part(token, &nick, &line),
+            "PRIVMSG" | "NOTICE" => self.irc_privmsg(token, &nick, &line),
+            "NAMES" => self.irc_names(token, &nick, &line),
+            "LIST" => self.irc_list(token, &nick, &line),
+            "KICK" => self.irc_kick(token, &nick, &line),
+            "TOPIC" => self.irc_topic(token, &nick, &line),
+            "MODE" => self.irc_mode(token, &nick, &line),
+            "WHO" => self.irc_who(token, &line),
+            "QUIT" => {
+                let reason = line.param(0).unwrap_or("Quit").to_owned();
+                self.terminate_connection(nick, &reason);
+            },
+            other => self.irc_numeric(token, "421", &format!("{} :Unknown command", other)),
+        }
+    }
+
+    fn irc_send(&mut self, token: Token, text: String) {
+        if let Some(conn) = self.connections.get_mut(&token) {
+            conn.send_raw(line(&text), self.config.slow_consumer);
+            self.dirty.push(token);
+        }
+    }
+
+    /// Sends a numeric reply, addressed to the client's nickname.
+    fn irc_numeric(&mut self, token: Token, code: &str, text: &str) {
+        let nick = match self.connections.get_mut(&token) {
+            Some(conn) => match conn.username {
+                Some(ref u) => u.clone(),
+                None => conn.irc().and_then(|i| i.nick.clone()).unwrap_or_else(|| "*".into()),
+            },
+            None => return,
+        };
+        self.irc_send(token, format!(":{} {} {} {}", SERVER_NAME, code, nick, text));
+    }
+
+    /// No capabilities to offer, but saying so keeps IRCv3 clients happy.
+    fn irc_cap(&mut self, token: Token, line: &Line) {
+        match line.param(0) {
+            Some("LS") | Some("LIST") => {
+                self.irc_send(token, format!(":{} CAP * {} :", SERVER_NAME,
+                                             line.params[0]))
+            },
+            Some("REQ") => {
+                let requested = line.param(1).unwrap_or("").to_owned();
+                self.irc_send(token, format!(":{} CAP * NAK :{}", SERVER_NAME, requested))
+            },
+            _ => (),
+        }
+    }
+
+    fn irc_nick(&mut self, token: Token, line: &Line) {
+        let nick = match line.param(0) {
+            Some(n) => n.to_owned(),
+            None => return self.irc_numeric(token, "431", ":No nickname given"),
+        };
+        if !valid_nick(&nick) {
+            return self.irc_numeric(token, "432", &format!("{} :Erroneous nickname", nick));
+        }
+        if self.known_user(&nick) {
+            return self.irc_numeric(token, "433", &format!("{} :Nickname is already in use",
+                                                           nick));
+        }
+        if let Some(irc) = self.connections.get_mut(&token).and_then(|c| c.irc()) {
+            irc.nick = Some(nick);
+        }
+        self.irc_register(token);
+    }
+
+    /// Opens the session once both NICK and USER have arrived.
+    fn irc_register(&mut self, token: Token) {
+        let nick = match self.connections.get_mut(&token).and_then(|c| c.irc()) {
+            Some(&mut Irc { nick: Some(ref nick), user_sent: true, .. }) => nick.clone(),
+            _ => return,
+        };
+        // Someone may have taken it between NICK and USER.
+        if self.sessions.contains_key(&nick) {
+            return self.irc_numeric(token, "433", &format!("{} :Nickname is already in use",
+                                                           nick));
+        }
+        if self.refuse_login(token, &nick) {
+            return;
+        }
+        self.open_session(nick.clone(), token);
+        self.irc_numeric(token, "001", &format!(":Welcome to the chat_np1 IRC gateway, {}",
+                                                nick));
+        self.irc_numeric(token, "002", &format!(":Your host is {}", SERVER_NAME));
+        self.irc_numeric(token, "003", ":This server was created a while ago");
+        self.irc_numeric(token, "004", &format!("{} chat_np1 o o", SERVER_NAME));
+        self.irc_numeric(token, "005", "CHANTYPES=# PREFIX=(o)@ NICKLEN=32 \
+                                        :are supported by this server");
+        self.irc_numeric(token, "422", ":MOTD File is missing");
+        self.notify_plugins(&nick, |p, ctx| p.on_connect(ctx, &nick));
+    }
+
+    fn irc_join(&mut self, token: Token, nick: &str, line: &Line) {
+        let channels = match line.param(0) {
+            Some(c) => c.to_owned(),
+            None => return self.irc_numeric(token, "461", "JOIN :Not enough parameters"),
+        };
+        if channels == "0" {
+            for room in self.rooms_of(nick) {
+                let m = Message::logout(nick, &room);
+                if self.admit(nick, &m) {
+                    self.communicate_message(m);
+                }
+            }
+            return;
+        }
+        for chan in channels.split(',') {
+            let room = match room_of(chan) {
+                Some(r) => r.to_owned(),
+                None => {
+                    self.irc_numeric(token, "403", &format!("{} :No such channel", chan));
+                    continue;
+                },
+            };
+            match self.groups.get(&room) {
+                Some(members) if members.iter().any(|m| m == nick) => continue,
+                Some(_) => {
+                    let m = Message::login(nick, &room);
+                    if self.admit(nick, &m) {
+                        self.communicate_message(m);
+                    }
+                },
+                // Joining creates the chat, as IRC users expect; unlike
+                // `NewChat`, it doesn't take them out of their other chats.
+                None => {
+                    if !self.admit(nick, &Message::new_chat(nick, &room)) {
+                        continue;
+                    }
+                    let ctx = match self.ask_join(nick, &room) {
+                        Some(ctx) => ctx,
+                        None => continue,
+                    };
+                    self.groups.insert(room.clone(), vec![nick.to_owned()]);
+                    self.webhooks.created(&room, nick);
+                    self.announce(RoomEvent::Joined { user: nick.to_owned(), room: room.clone() });
+                    self.answer(ctx);
+                },
+            }
+            if self.groups.get(&room).is_some_and(|m| m.iter().any(|m| m == nick)) {
+                if let Some(topic) = self.topics.get(&room).cloned() {
+                    self.irc_numeric(token, "332", &format!("{} :{}", channel(&room), topic));
+                }
+                self.irc_send_names(token, &room);
+            }
+        }
+    }
+
+    fn irc_part(&mut self, token: Token, nick: &str, line: &Line) {
+        let channels = match line.param(0) {
+            Some(c) => c.to_owned(),
+            None => return self.irc_numeric(token, "461", "PART :Not enough parameters"),
+        };
+        for chan in channels.split(',') {
+            let room = match room_of(chan).filter(|r| self.groups.contains_key(*r)) {
+                Some(r) => r.to_owned(),
+                None => {
+                    self.irc_numeric(token, "403", &format!("{} :No such channel", chan));
+                    continue;
+                },
+            };
+            if !self.groups[&room].iter().any(|m| m == nick) {
+                self.irc_numeric(token, "442", &format!("{} :You're not on that channel", chan));
+                continue;
+            }
+            let m = Message::logout(nick, &room);
+            if self.admit(nick, &m) {
+                self.communicate_message(m);
+            }
+        }
+    }
+
+    fn irc_privmsg(&mut self, token: Token, nick: &str, line: &Line) {
+        // NOTICE never gets an error back, so bots can't loop on each other.
+        let errors = line.command == "PRIVMSG";
+        let (targets, text) = match (line.param(0), line.param(1)) {
+            (Some(t), Some(text)) if !text.is_empty() => (t.to_owned(), text.to_owned()),
+            (None, _) if errors =>
+                return self.irc_numeric(token, "411", ":No recipient given (PRIVMSG)"),
+            (Some(_), _) if errors => return self.irc_numeric(token, "412", ":No text to send"),
+            _ => return,
+        };
+        for target in targets.split(',') {
+            let m = match room_of(target) {
+                Some(room) => match self.groups.get(room) {
+                    Some(members) if members.iter().any(|m| m == nick) =>
+                        Message::chat_message(nick, room, &text),
+                    Some(_) => {
+                        if errors {
+                            self.irc_numeric(token, "404", &format!("{} :Cannot send to channel",
+                                                                    target));
+                        }
+                        continue;
+                    },
+                    None => {
+                        if errors {
+                            self.irc_numeric(token, "403", &format!("{} :No such channel",
+                                                                    target));
+                        }
+                        continue;
+                    },
+                },
+                None if self.known_user(target) =>
+                    Message::private_message(nick, target, &text),
+                None => {
+                    if errors {
+                        self.irc_numeric(token, "401", &format!("{} :No such nick/channel",
+                                                                target));
+                    }
+                    continue;
+                },
+            };
+            if self.admit(nick, &m) {
+                self.communicate_message(m);
+            }
+        }
+    }
+
+    fn irc_send_names(&mut self, token: Token, room: &str) {
+        let members = self.groups.get(room).cloned().unwrap_or_default();
+        // The first member is the chat's admin.
+        let names: Vec<String> = members.iter().enumerate()
+            .map(|(i, m)| if i == 0 { format!("@{}", word(m)) } else { word(m) })
+            .collect();
+        for chunk in names.chunks(NAMES_PER_LINE) {
+            self.irc_numeric(token, "353", &format!("= {} :{}", channel(room), chunk.join(" ")));
+        }
+        self.irc_numeric(token, "366", &format!("{} :End of /NAMES list", channel(room)));
+    }
+
+    fn irc_names(&mut self, token: Token, nick: &str, line: &Line) {
+        let channels = match line.param(0) {
+            Some(c) => c.to_owned(),
+            None => return self.irc_numeric(token, "366", "* :End of /NAMES list"),
+        };
+        if !self.admit(nick, &Message::ListUsers(nick.to_owned(), channels.clone())) {
+            return;
+        }
+        for chan in channels.split(',') {
+            match room_of(chan).filter(|r| self.groups.contains_key(*r)) {
+                Some(room) => self.irc_send_names(token, room),
+                None => self.irc_numeric(token, "366", &format!("{} :End of /NAMES list", chan)),
+            }
+        }
+    }
+
+    fn irc_list(&mut self, token: Token, nick: &str, line: &Line) {
+        if !self.admit(nick, &Message::ListGroups(nick.to_owned())) {
+            return;
+        }
+        let wanted: Option<Vec<String>> = line.param(0)
+            .map(|c| c.split(',').filter_map(room_of).map(|r| r.to_owned()).collect());
+        let mut rooms: Vec<(String, usize)> = self.groups.iter()
+            .filter(|&(room, _)| wanted.as_ref().is_none_or(|w| w.contains(room)))
+            .map(|(room, members)| (room.clone(), members.len()))
+            .collect();
+        rooms.sort();
+        self.irc_numeric(token, "321", "Channel :Users  Name");
+        for (room, count) in rooms {
+            let topic = self.topics.get(&room).cloned().unwrap_or_default();
+            self.irc_numeric(token, "322", &format!("{} {} :{}", channel(&room), count, topic));
+        }
+        self.irc_numeric(token, "323", ":End of /LIST");
+    }
+
+    fn irc_kick(&mut self, token: Token, nick: &str, line: &Line) {
+        let (chan, target) = match (line.param(0), line.param(1)) {
+            (Some(c), Some(t)) => (c.to_owned(), t.to_owned()),
+            _ => return self.irc_numeric(token, "461", "KICK :Not enough parameters"),
+        };
+        let members = match room_of(&chan).and_then(|r| self.groups.get(r)) {
+            Some(m) => m.clone(),
+            None => return self.irc_numeric(token, "403", &format!("{} :No such channel", chan)),
+        };
+        if !members.iter().any(|m| m == nick) {
+            return self.irc_numeric(token, "442", &format!("{} :You're not on that channel",
+                                                           chan));
+        }
+        if !members.contains(&target) {
+            return self.irc_numeric(token, "441", &format!("{} {} :They aren't on that channel",
+                                                           target, chan));
+        }
+        if members.first().map(|m| m.as_str()) != Some(nick) {
+            return self.irc_numeric(token, "482", &format!("{} :You're not channel operator",
+                                                           chan));
+        }
+        let m = Message::kick_user(nick, &chan[1..], &target);
+        if self.admit(nick, &m) {
+            self.communicate_message(m);
+        }
+    }
+
+    /// Topics only exist for IRC users to see; the chat's admin sets them.
+    fn irc_topic(&mut self, token: Token, nick: &str, line: &Line) {
+        let chan = match line.param(0) {
+            Some(c) => c.to_owned(),
+            None => return self.irc_numeric(token, "461", "TOPIC :Not enough parameters"),
+        };
+        let (room, members) = match room_of(&chan).and_then(|r| self.groups.get(r).map(|m| (r, m))) {
+            Some((r, m)) => (r.to_owned(), m.clone()),
+            None => return self.irc_numeric(token, "403", &format!("{} :No such channel", chan)),
+        };
+        let topic = match line.param(1) {
+            Some(t) => t.to_owned(),
+            None => {
+                return match self.topics.get(&room).cloned() {
+                    Some(t) => self.irc_numeric(token, "332", &format!("{} :{}", chan, t)),
+                    None => self.irc_numeric(token, "331", &format!("{} :No topic is set", chan)),
+                };
+            },
+        };
+        if !members.iter().any(|m| m == nick) {
+            return self.irc_numeric(token, "442", &format!("{} :You're not on that channel",
+                                                           chan));
+        }
+        if members.first().map(|m| m.as_str()) != Some(nick) {
+            return self.irc_numeric(token, "482", &format!("{} :You're not channel operator",
+                                                           chan));
+        }
+        if topic.is_empty() {
+            self.topics.remove(&room);
+        } else {
+            self.topics.insert(room.clone(), topic.clone());
+        }
+        self.announce(RoomEvent::Topic { user: nick.to_owned(), room, topic });
+    }
+
+    /// Modes can't be changed; this only answers the queries clients make
+    /// on their own after joining.
+    fn irc_mode(&mut self, token: Token, nick: &str, line: &Line) {
+        let target = match line.param(0) {
+            Some(t) => t.to_owned(),
+            None => return self.irc_numeric(token, "461", "MODE :Not enough parameters"),
+        };
+        if line.params.len() > 1 {
+            return;
+        }
+        if room_of(&target).is_some_and(|r| self.groups.contains_key(r)) {
+            self.irc_numeric(token, "324", &format!("{} +", target));
+        } else if target == nick {
+            self.irc_numeric(token, "221", "+");
+        } else {
+            self.irc_numeric(token, "403", &format!("{} :No such channel", target));
+        }
+    }
+
+    fn irc_who(&mut self, token: Token, line: &Line) {
+        let target = line.param(0).unwrap_or("*").to_owned();
+        let members = room_of(&target)
+            .and_then(|r| self.groups.get(r))
+            .cloned()
+            .unwrap_or_default();
+        for member in members.iter().map(|m| word(m)) {
+            self.irc_numeric(token, "352", &format!("{} {} {} {} {} H :0 {}", target, member,
+                                                    SERVER_NAME, SERVER_NAME, member, member));
+        }
+        self.irc_numeric(token, "315", &format!("{} :End of /WHO list", target));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use message::{FailureCode, Message};
+    use super::*;
+
+    fn parse(text: &str) -> (String, Vec<String>) {
+        let line = Line::parse(text).expect("a line");
+        (line.command, line.params)
+    }
+
+    fn lines(bytes: Vec<u8>) -> Vec<String> {
+        let text = String::from_utf8(bytes).unwrap();
+        assert!(text.is_empty() || text.ends_with("\r\n"), "unterminated: {:?}", text);
+        text.split_terminator("\r\n").map(|l| l.to_owned()).collect()
+    }
+
+    #[test]
+    fn lines_are_split_into_params() {
+        assert_eq!(parse("privmsg #Chat1 :hi there"),
+                   ("PRIVMSG".to_owned(), vec!["#Chat1".to_owned(), "hi there".to_owned()]));
+        assert_eq!(parse("@time=now :ana!ana@host JOIN  #a,#b"),
+                   ("JOIN".to_owned(), vec!["#a,#b".to_owned()]));
+        assert_eq!(parse("TOPIC #a :"), ("TOPIC".to_owned(), vec!["#a".to_owned(), "".to_owned()]));
+        assert_eq!(parse("QUIT"), ("QUIT".to_owned(), vec![]));
+        assert!(Line::parse("").is_none());
+        assert!(Line::parse(":prefix-only").is_none());
+        assert!(Line::parse("@tags-only").is_none());
+    }
+
+    #[test]
+    fn input_is_cut_at_line_ends() {
+        let mut irc = Irc::default();
+        irc.feed(b"NICK ana\r\nUSER a 0 * :Ana\nPING");
+        let commands: Vec<String> = irc.decode().unwrap().into_iter().map(|l| l.command).collect();
+        assert_eq!(commands, ["NICK", "USER"]);
+        irc.feed(b" x\r\n");
+        assert_eq!(irc.decode().unwrap()[0].params, ["x"]);
+        irc.feed(&vec![b'x'; MAX_LINE + 1]);
+        assert!(irc.decode().is_err());
+    }
+
+    #[test]
+    fn nothing_sent_can_add_a_line() {
+        let evil = "x\r\nPRIVMSG #Chat1 :pwned\0";
+        let m = Message::chat_message(evil, evil, "hi");
+        assert_eq!(lines(encode(&m, "ana")).len(), 1);
+        let m = Message::Failure(FailureCode::Other, evil.to_owned(), String::new());
+        assert!(lines(encode(&m, "ana")).iter().all(|l| !l.starts_with("PRIVMSG")));
+        let events = vec![
+            RoomEvent::Joined { user: evil.into(), room: evil.into() },
+            RoomEvent::Left { user: evil.into(), room: evil.into() },
+            RoomEvent::Kicked { by: evil.into(), user: evil.into(), room: evil.into() },
+            RoomEvent::Quit { user: evil.into(), rooms: vec![], reason: evil.into() },
+            RoomEvent::Topic { user: evil.into(), room: evil.into(), topic: evil.into() },
+        ];
+        for event in events {
+            let sent = lines(encode_event(&event));
+            assert_eq!(sent.len(), 1, "{:?}", sent);
+            assert!(!sent[0].contains('\0'));
+        }
+    }
+
+    #[test]
+    fn names_stay_single_params() {
+        let event = RoomEvent::Kicked { by: "an admin".into(), user: "a user".into(),
+                                        room: "a room".into() };
+        let sent = lines(encode_event(&event));
+        let (command, params) = parse(&sent[0]);
+        assert_eq!(command, "KICK");
+        assert_eq!(params, ["#a_room", "a_user", "an admin"]);
+    }
+
+    #[test]
+    fn text_goes_out_a_line_at_a_time() {
+        let m = Message::chat_message("ana", "Chat1", "one\r\ntwo\n\nthree");
+        assert_eq!(lines(encode(&m, "bob")), [
+            ":ana!ana@chat_np1 PRIVMSG #Chat1 :one",
+            ":ana!ana@chat_np1 PRIVMSG #Chat1 :two",
+            ":ana!ana@chat_np1 PRIVMSG #Chat1 :three",
+        ]);
+    }
+
+    #[test]
+    fn only_names_irc_can_carry_make_rooms() {
+        assert!(valid_room("Chat1"));
+        assert!(valid_room("caf\u{e9}-2"));
+        for name in &["", "a b", "a,b", "a:b", "a\rb", "a\nb", "a\0b", "a\x07b"] {
+            assert!(!valid_room(name), "{:?}", name);
+        }
+        assert_eq!(room_of("#Chat1"), Some("Chat1"));
+        assert_eq!(room_of("Chat1"), None);
+        assert_eq!(room_of("#"), None);
+    }
+
+    #[test]
+    fn nicks_are_checked() {
+        assert!(valid_nick("ana"));
+        for nick in &["", "#ana", ":ana", "a b", "a!b", "server", &"x".repeat(33)] {
+            assert!(!valid_nick(nick), "{:?}", nick);
+        }
+    }
+}
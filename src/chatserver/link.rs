@@ -0,0 +1,672 @@
+//! Server-to-server links, so users of several `server` processes share
+//! the same chats. Each server keeps the whole membership map, its own
+//! users and everyone else's; a link carries the changes its side makes,
+//! one JSON object per line:
+//!
+//! - `hello {server, nonce}` and `auth {mac}`: both sides prove they know
+//!   the shared secret, by answering the other's nonce with
+//!   HMAC-SHA256(secret, side + "\n" + their nonce + "\n" + own nonce +
+//!   "\n" + own name), where side is `dialer` or `acceptor`. Signing the
+//!   side and both nonces keeps an answer from being replayed on another
+//!   link. The secret itself never goes over the wire, but nothing else is
+//!   encrypted.
+//! - `burst {users: [{user, rooms}], rooms, topics}`: everything a side
+//!   knows, sent once the link is up, so a restarted server catches up.
+//! - `user`, `quit`, `join`, `leave`, `kick`, `topic`, `message` and
+//!   `private`: changes as they happen.
+//!
+//! Servers may link in a chain or a tree, never in a loop: lines are passed
+//! on to every other link, but not back where they came from. Usernames
+//! are global, so a name already in use on a linked server is refused, and
+//! a link may only speak for the users reached through it. Fields are held
+//! to the same `MAX_FIELD` as the native protocol.
+
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+use std::time::{Duration, Instant};
+use mio::Token;
+use mio::net::TcpStream;
+use ring::hmac;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde_json::Value;
+use message::{Message, MAX_FIELD};
+use super::{Server, SlowConsumerPolicy};
+use super::codec::Protocol;
+use super::event::RoomEvent;
+use super::irc::valid_room;
+use super::stream::Stream;
+
+const MAX_LINE: usize = 1024 * 1024;
+const FIRST_RETRY: Duration = Duration::from_secs(1);
+const MAX_RETRY: Duration = Duration::from_secs(30);
+
+/// Handshake state and unparsed input of one link.
+#[derive(Default)]
+pub struct Link {
+    buf: Vec<u8>,
+    /// What we asked the peer to sign, in our `hello`.
+    nonce: String,
+    /// What the peer asked us to sign, in its `hello`.
+    peer_nonce: String,
+    /// The peer's name, once its `hello` arrived.
+    peer: Option<String>,
+}
+
+impl Link {
+    pub fn feed(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    pub fn decode(&mut self) -> io::Result<Vec<Value>> {
+        let mut lines = Vec::new();
+        while let Some(pos) = self.buf.iter().position(|&b| b == b'\n') {
+            let raw: Vec<u8> = self.buf.drain(..pos + 1).collect();
+            match serde_json::from_slice::<Value>(&raw) {
+                Ok(v) if v.is_object() => lines.push(v),
+                _ => return Err(invalid("link sent something that isn't a JSON object")),
+            }
+        }
+        if self.buf.len() > MAX_LINE {
+            return Err(invalid("link line too long"));
+        }
+        Ok(lines)
+    }
+}
+
+pub fn line(value: &Value) -> Vec<u8> {
+    let mut bytes = value.to_string().into_bytes();
+    bytes.push(b'\n');
+    bytes
+}
+
+fn invalid(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason.to_owned())
+}
+
+/// A peer this server dials itself, and dials again whenever the link
+/// drops.
+pub struct Outbound {
+    addr: String,
+    token: Option<Token>,
+    retry_at: Instant,
+    backoff: Duration,
+    /// Where the answer to a name lookup still under way will come.
+    resolving: Option<Receiver<io::Result<SocketAddr>>>,
+}
+
+impl Outbound {
+    pub fn new(addr: String) -> Outbound {
+        Outbound {
+            addr,
+            token: None,
+            retry_at: Instant::now(),
+            backoff: FIRST_RETRY,
+            resolving: None,
+        }
+    }
+
+    /// Where to dial, or `None` while that's still being looked up. Names
+    /// are looked up on a thread of their own, every time, so a slow name
+    /// server never holds up the event loop and a moved peer is found; the
+    /// answer is picked up by a later sweep.
+    fn resolve(&mut self) -> Option<io::Result<SocketAddr>> {
+        if let Ok(addr) = self.addr.parse() {
+            return Some(Ok(addr));
+        }
+        if self.resolving.is_none() {
+            let (answer, resolving) = mpsc::channel();
+            let addr = self.addr.clone();
+            let spawned = thread::Builder::new()
+                .name("link lookup".into())
+                .spawn(move || {
+                    let found = addr.to_socket_addrs().and_then(|mut a| {
+                        a.next().ok_or_else(|| invalid("no address to link to"))
+                    });
+                    let _ = answer.send(found);
+                });
+            if let Err(e) = spawned {
+                return Some(Err(e));
+            }
+            self.resolving = Some(resolving);
+        }
+        let found = match self.resolving.as_ref()?.try_recv() {
+            Ok(found) => found,
+            Err(TryRecvError::Empty) => return None,
+            Err(TryRecvError::Disconnected) => Err(invalid("name lookup gave no answer")),
+        };
+        self.resolving = None;
+        Some(found)
+    }
+
+    fn failed(&mut self) {
+        self.token = None;
+        self.retry_at = Instant::now() + self.backoff;
+        self.backoff = ::std::cmp::min(self.backoff * 2, MAX_RETRY);
+    }
+}
+
+/// What the side that `dialed` (or not) signs, answering `asked` with its
+/// own `nonce` and `name`.
+fn signed(dialed: bool, asked: &str, nonce: &str, name: &str) -> String {
+    let side = if dialed { "dialer" } else { "acceptor" };
+    format!("{}\n{}\n{}\n{}", side, asked, nonce, name)
+}
+
+fn sign(secret: &str, data: &str) -> hmac::Tag {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+    hmac::sign(&key, data.as_bytes())
+}
+
+fn verify(secret: &str, data: &str, mac: &str) -> bool {
+    let mac: Option<Vec<u8>> = (0..mac.len()).step_by(2)
+        .map(|i| mac.get(i..i + 2).and_then(|h| u8::from_str_radix(h, 16).ok()))
+        .collect();
+    let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+    mac.is_some_and(|mac| hmac::verify(&key, data.as_bytes(), &mac).is_ok())
+}
+
+/// Whether every string in `value`, keys included, fits in a field.
+fn fits(value: &Value) -> bool {
+    match *value {
+        Value::String(ref s) => s.len() <= MAX_FIELD,
+        Value::Array(ref a) => a.iter().all(fits),
+        Value::Object(ref o) => o.iter().all(|(k, v)| k.len() <= MAX_FIELD && fits(v)),
+        _ => true,
+    }
+}
+
+pub(super) fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+impl Server {
+    /// Dials the configured peers whose link is down, once their retry is
+    /// due.
+    pub(super) fn connect_links(&mut self) {
+        for i in 0..self.outbound.len() {
+            match self.outbound[i].token {
+                Some(t) if self.connections.contains_key(&t) => continue,
+                Some(_) => {
+                    eprintln!("Link to {} is down, retrying in {:?}",
+                              self.outbound[i].addr, self.outbound[i].backoff);
+                    self.outbound[i].failed();
+                    continue;
+                },
+                None if self.outbound[i].retry_at > Instant::now() => continue,
+                None => (),
+            }
+            let dialed = match self.outbound[i].resolve() {
+                Some(found) => found.and_then(|a| TcpStream::connect(a).map(|s| (s, a))),
+                None => continue,
+            };
+            let addr = self.outbound[i].addr.clone();
+            let token = match dialed {
+                Ok((socket, a)) => self.adopt(Stream::Tcp(socket), Some(a), Protocol::Link),
+                Err(e) => {
+                    eprintln!("Failed linking to {}: {}", addr, e);
+                    None
+                },
+            };
+            match token {
+                Some(t) => self.outbound[i].token = Some(t),
+                None => self.outbound[i].failed(),
+            }
+        }
+    }
+
+    /// Opens the handshake on a new link, whichever side dialed.
+    pub(super) fn link_hello(&mut self, token: Token) {
+        let mut nonce = [0u8; 16];
+        if SystemRandom::new().fill(&mut nonce).is_err() {
+            return self.close_connection(token);
+        }
+        let nonce = hex(&nonce);
+        if let Some(link) = self.connections.get_mut(&token).and_then(|c| c.link()) {
+            link.nonce = nonce.clone();
+        }
+        let hello = json!({ "type": "hello", "server": self.config.server_name, "nonce": nonce });
+        self.link_send(token, &hello);
+    }
+
+    pub(super) fn link_line(&mut self, token: Token, line: Value) {
+        let field = |name: &str| line.get(name).and_then(Value::as_str).unwrap_or("").to_owned();
+        let authenticated = match self.connections.get(&token) {
+            Some(conn) => conn.handshake_done,
+            None => return,
+        };
+        if !fits(&line) {
+            eprintln!("Ignoring a link line with a field over {} bytes", MAX_FIELD);
+            return;
+        }
+        match (line["type"].as_str().unwrap_or(""), authenticated) {
+            ("hello", false) => self.link_greeted(token, field("server"), field("nonce")),
+            ("auth", false) => self.link_authenticate(token, &field("mac")),
+            ("error", _) => {
+                eprintln!("Linked server gave up: {}", field("reason"));
+                self.close_connection(token);
+            },
+            (_, false) => self.link_refuse(token, "Not authenticated"),
+            ("burst", true) => self.link_burst(token, &line),
+            ("user", true) => {
+                self.link_user(token, field("user"));
+            },
+            ("quit", true) => if self.remote_users.get(&field("user")) == Some(&token) {
+                self.link_quit(token, field("user"), &field("reason"));
+            },
+            ("join", true) => if self.remote_users.get(&field("user")) == Some(&token) {
+                self.link_join(token, field("user"), field("room"));
+            },
+            ("leave", true) => if self.remote_users.get(&field("user")) == Some(&token) {
+                let (user, room) = (field("user"), field("room"));
+                if self.leave_group(&user, &room) {
+                    self.share(&line, Some(token));
+                    self.announce_locally(RoomEvent::Left { user, room });
+                }
+            },
+            ("kick", true) => {
+                let (by, user, room) = (field("by"), field("user"), field("room"));
+                if self.speaks_for(token, &by) && self.leave_group(&user, &room) {
+                    self.share(&line, Some(token));
+                    self.announce_locally(RoomEvent::Kicked { by, user: user.clone(), room });
+                    self.tell_kicked(&user);
+                }
+            },
+            ("topic", true) => {
+                let (user, room, topic) = (field("user"), field("room"), field("topic"));
+                if self.speaks_for(token, &user) && self.groups.contains_key(&room) {
+                    self.topics.insert(room.clone(), topic.clone());
+                    self.share(&line, Some(token));
+                    self.announce_locally(RoomEvent::Topic { user, room, topic });
+                }
+            },
+            ("message", true) => {
+                let (user, room) = (field("user"), field("room"));
+                if self.speaks_for(token, &user) && self.groups.contains_key(&room) {
+                    let m = Message::chat_message(user, room, field("contents"));
+                    self.relay_chat(m, Some(token));
+                }
+            },
+            ("private", true) => self.link_private(token, field("from"), field("to"),
+                                                   field("contents")),
+            (other, true) => eprintln!("Ignoring unknown link line {}", other),
+        }
+    }
+
+    /// Whether the link `token` may speak as `name`: one of the users
+    /// reached through it, or a name that is nobody's, as bots and the
+    /// admin API use. Never someone connected here or through another link.
+    fn speaks_for(&self, token: Token, name: &str) -> bool {
+        !self.sessions.contains_key(name)
+            && self.remote_users.get(name).is_none_or(|&t| t == token)
+    }
+
+    /// Whether this server dialed the link `token`, rather than accepted it.
+    fn dialed(&self, token: Token) -> bool {
+        self.outbound.iter().any(|o| o.token == Some(token))
+    }
+
+    fn link_greeted(&mut self, token: Token, server: String, nonce: String) {
+        if server.is_empty() || nonce.is_empty() || server == self.config.server_name {
+            return self.link_refuse(token, "Invalid hello");
+        }
+        let secret = match self.config.link_secret {
+            Some(ref s) => s.clone(),
+            None => return self.link_refuse(token, "Linking is not set up here"),
+        };
+        let own_nonce = match self.connections.get_mut(&token).and_then(|c| c.link()) {
+            Some(link) if link.peer.is_none() => {
+                link.peer = Some(server);
+                link.peer_nonce = nonce.clone();
+                link.nonce.clone()
+            },
+            _ => return self.link_refuse(token, "Unexpected hello"),
+        };
+        let data = signed(self.dialed(token), &nonce, &own_nonce, &self.config.server_name);
+        let mac = sign(&secret, &data);
+        self.link_send(token, &json!({ "type": "auth", "mac": hex(mac.as_ref()) }));
+    }
+
+    fn link_authenticate(&mut self, token: Token, mac: &str) {
+        let (nonce, peer_nonce, peer) = match self.connections.get_mut(&token)
+            .and_then(|c| c.link()) {
+            Some(&mut Link { ref nonce, ref peer_nonce, peer: Some(ref peer), .. }) =>
+                (nonce.clone(), peer_nonce.clone(), peer.clone()),
+            _ => return self.link_refuse(token, "Unexpected auth"),
+        };
+        // The peer signed as the other side of this link.
+        let data = signed(!self.dialed(token), &nonce, &peer_nonce, &peer);
+        let valid = self.config.link_secret.as_ref()
+            .is_some_and(|secret| verify(secret, &data, mac));
+        if !valid {
+            eprintln!("Link from {} failed to authenticate", peer);
+            return self.link_refuse(token, "Authentication failed");
+        }
+        if self.links.contains_key(&peer) {
+            return self.link_refuse(token, "Already linked");
+        }
+        if let Some(conn) = self.connections.get_mut(&token) {
+            conn.handshake_done = true;
+            self.pending_handshakes -= 1;
+        }
+        println!("Linked with {}", peer);
+        self.links.insert(peer, token);
+        if let Some(outbound) = self.outbound.iter_mut().find(|o| o.token == Some(token)) {
+            outbound.backoff = FIRST_RETRY;
+        }
+        let users: Vec<Value> = self.sessions.keys()
+            .chain(self.remote_users.iter().filter(|&(_, &t)| t != token).map(|(u, _)| u))
+            .map(|u| json!({ "user": u, "rooms": self.rooms_of(u) }))
+            .collect();
+        let rooms: Vec<&String> = self.groups.keys().collect();
+        let burst = json!({
+            "type": "burst",
+            "users": users,
+            "rooms": rooms,
+            "topics": self.topics,
+        });
+        self.link_send(token, &burst);
+    }
+
+    fn link_refuse(&mut self, token: Token, reason: &str) {
+        self.link_send(token, &json!({ "type": "error", "reason": reason }));
+        if let Some(conn) = self.connections.get_mut(&token) {
+            conn.closing = true;
+        }
+    }
+
+    fn link_burst(&mut self, token: Token, burst: &Value) {
+        let strings = |v: &Value| -> Vec<String> {
+            v.as_array().map_or_else(Vec::new, |a| {
+                a.iter().filter_map(Value::as_str).map(|s| s.to_owned()).collect()
+            })
+        };
+        for room in strings(&burst["rooms"]).into_iter().filter(|r| valid_room(r)) {
+            self.groups.entry(room).or_default();
+        }
+        for entry in burst["users"].as_array().cloned().unwrap_or_default() {
+            let user = match entry["user"].as_str() {
+                Some(u) => u.to_owned(),
+                None => continue,
+            };
+            if self.link_user(token, user.clone()) {
+                for room in strings(&entry["rooms"]) {
+                    self.link_join(token, user.clone(), room);
+                }
+            }
+        }
+        if let Some(topics) = burst["topics"].as_object() {
+            for (room, topic) in topics {
+                if let (Some(topic), false) = (topic.as_str(), self.topics.contains_key(room)) {
+                    self.topics.insert(room.clone(), topic.to_owned());
+                    let line = json!({ "type": "topic", "user": "", "room": room, "topic": topic });
+                    self.share(&line, Some(token));
+                }
+            }
+        }
+    }
+
+    /// Learns about a user on the other side of `token`; false if the name
+    /// is already someone else's.
+    fn link_user(&mut self, token: Token, user: String) -> bool {
+        let taken = self.sessions.contains_key(&user)
+            || self.remote_users.get(&user).is_some_and(|&t| t != token);
+        if user.is_empty() || taken {
+            eprintln!("Ignoring linked user {}, the name is taken here", user);
+            return false;
+        }
+        self.remote_users.insert(user.clone(), token);
+        self.share(&json!({ "type": "user", "user": user }), Some(token));
+        true
+    }
+
+    fn link_join(&mut self, token: Token, user: String, room: String) {
+        if !valid_room(&room) {
+            return;
+        }
+        let group = self.groups.entry(room.clone()).or_default();
+        if group.contains(&user) {
+            return;
+        }
+        group.push(user.clone());
+        self.share(&json!({ "type": "join", "user": user, "room": room }), Some(token));
+        self.announce_locally(RoomEvent::Joined { user, room });
+    }
+
+    fn link_quit(&mut self, token: Token, user: String, reason: &str) {
+        self.remote_users.remove(&user);
+        self.share(&json!({ "type": "quit", "user": user, "reason": reason }), Some(token));
+        let rooms = self.rooms_of(&user);
+        for room in &rooms {
+            self.leave_group(&user, room);
+        }
+        if !rooms.is_empty() {
+            self.announce_locally(RoomEvent::Quit { user, rooms, reason: reason.to_owned() });
+        }
+    }
+
+    fn link_private(&mut self, token: Token, from: String, to: String, contents: String) {
+        if !self.speaks_for(token, &from) {
+            return;
+        }
+        if self.sessions.contains_key(&to) {
+            self.send_to_user(&to, Message::private_message(from, to.clone(), contents));
+        } else if let Some(&next) = self.remote_users.get(&to).filter(|&&t| t != token) {
+            let line = json!({ "type": "private", "from": from, "to": to, "contents": contents });
+            self.link_send(next, &line);
+        }
+    }
+
+    /// Forgets everyone who was reachable through a link that just closed.
+    pub(super) fn link_closed(&mut self, token: Token) {
+        let peer = match self.links.iter().find(|&(_, &t)| t == token) {
+            Some((peer, _)) => peer.clone(),
+            None => return,
+        };
+        println!("Lost link with {}", peer);
+        self.links.remove(&peer);
+        let users: Vec<String> = self.remote_users.iter()
+            .filter(|&(_, &t)| t == token)
+            .map(|(u, _)| u.clone())
+            .collect();
+        for user in users {
+            self.link_quit(token, user, "Server link lost");
+        }
+    }
+
+    /// Passes a change on to every linked server but `except`.
+    pub(super) fn share(&mut self, line: &Value, except: Option<Token>) {
+        let tokens: Vec<Token> = self.links.values()
+            .cloned()
+            .filter(|&t| Some(t) != except)
+            .collect();
+        for token in tokens {
+            self.link_send(token, line);
+        }
+    }
+
+    /// Passes on what happened to users of this server. `Quit` is left to
+    /// `terminate_connection`, which knows about users in no chat too.
+    pub(super) fn share_event(&mut self, event: &RoomEvent) {
+        let line = match *event {
+            RoomEvent::Joined { ref user, ref room } =>
+                json!({ "type": "join", "user": user, "room": room }),
+            RoomEvent::Left { ref user, ref room } =>
+                json!({ "type": "leave", "user": user, "room": room }),
+            RoomEvent::Kicked { ref by, ref user, ref room } =>
+                json!({ "type": "kick", "by": by, "user": user, "room": room }),
+            RoomEvent::Topic { ref user, ref room, ref topic } =>
+                json!({ "type": "topic", "user": user, "room": room, "topic": topic }),
+            RoomEvent::Quit { .. } => return,
+        };
+        self.share(&line, None);
+    }
+
+    /// A link that can't keep up is dropped; it resyncs when it comes back.
+    pub(super) fn link_send(&mut self, token: Token, line_value: &Value) {
+        if let Some(conn) = self.connections.get_mut(&token) {
+            conn.send_raw(line(line_value), SlowConsumerPolicy::Disconnect);
+            self.dirty.push(token);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use transport::{self, ChannelTransport};
+    use super::*;
+    use super::super::ServerBuilder;
+
+    const SECRET: &str = "secret";
+
+    /// Polls `outbound` as the sweep would, until its lookup is answered.
+    fn resolved(outbound: &mut Outbound) -> io::Result<SocketAddr> {
+        loop {
+            if let Some(found) = outbound.resolve() {
+                return found;
+            }
+            assert!(outbound.resolving.is_some());
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    #[test]
+    fn names_are_looked_up_off_the_event_loop() {
+        let mut literal = Outbound::new("127.0.0.1:7000".into());
+        assert_eq!(literal.resolve().unwrap().unwrap(), "127.0.0.1:7000".parse().unwrap());
+        assert!(literal.resolving.is_none());
+        let mut named = Outbound::new("localhost:7000".into());
+        assert_eq!(resolved(&mut named).unwrap().port(), 7000);
+        assert!(named.resolving.is_none());
+        let mut unnamed = Outbound::new("no port".into());
+        assert!(resolved(&mut unnamed).is_err());
+        assert!(unnamed.resolving.is_none());
+    }
+
+    /// A server allowed to link, without any links yet.
+    fn server() -> Server {
+        ServerBuilder::new().link_secret(SECRET).build().unwrap()
+    }
+
+    /// A new link, its handshake still to do.
+    fn adopt(server: &mut Server) -> (Token, ChannelTransport) {
+        let (ours, theirs) = transport::pair();
+        let token = server.adopt(Stream::Channel(theirs), None, Protocol::Link).unwrap();
+        (token, ours)
+    }
+
+    fn own_nonce(server: &mut Server, token: Token) -> String {
+        server.connections.get_mut(&token).and_then(|c| c.link()).unwrap().nonce.clone()
+    }
+
+    fn authenticated(server: &Server, token: Token) -> bool {
+        server.connections[&token].handshake_done
+    }
+
+    /// A link already past its handshake, reaching `users`.
+    fn linked(server: &mut Server, peer: &str, users: &[&str]) -> (Token, ChannelTransport) {
+        let (token, end) = adopt(server);
+        server.connections.get_mut(&token).unwrap().handshake_done = true;
+        server.links.insert(peer.to_owned(), token);
+        for user in users {
+            server.remote_users.insert((*user).to_owned(), token);
+            server.groups.get_mut("Chat1").unwrap().push((*user).to_owned());
+        }
+        (token, end)
+    }
+
+    fn hello(server: &mut Server, token: Token, name: &str, nonce: &str) {
+        server.link_line(token, json!({ "type": "hello", "server": name, "nonce": nonce }));
+    }
+
+    fn auth(server: &mut Server, token: Token, mac: &hmac::Tag) {
+        server.link_line(token, json!({ "type": "auth", "mac": hex(mac.as_ref()) }));
+    }
+
+    #[test]
+    fn a_peer_that_knows_the_secret_is_linked() {
+        let mut server = server();
+        let (token, _end) = adopt(&mut server);
+        let nonce = own_nonce(&mut server, token);
+        hello(&mut server, token, "other", "theirs");
+        auth(&mut server, token, &sign(SECRET, &signed(true, &nonce, "theirs", "other")));
+        assert!(authenticated(&server, token));
+        assert_eq!(server.links.get("other"), Some(&token));
+    }
+
+    #[test]
+    fn a_wrong_secret_is_refused() {
+        let mut server = server();
+        let (token, _end) = adopt(&mut server);
+        let nonce = own_nonce(&mut server, token);
+        hello(&mut server, token, "other", "theirs");
+        auth(&mut server, token, &sign("guess", &signed(true, &nonce, "theirs", "other")));
+        assert!(!authenticated(&server, token));
+        assert!(server.connections[&token].closing);
+    }
+
+    #[test]
+    fn a_reflected_answer_is_refused() {
+        let mut server = server();
+        let (token, _end) = adopt(&mut server);
+        let nonce = own_nonce(&mut server, token);
+        // What another server accepting a link would answer, were it sent
+        // this server's nonce in a hello of the attacker's.
+        hello(&mut server, token, "other", "theirs");
+        auth(&mut server, token, &sign(SECRET, &signed(false, &nonce, "theirs", "other")));
+        assert!(!authenticated(&server, token));
+        // Nor does an answer to the same nonce alongside another one.
+        let mut server = self::server();
+        let (token, _end) = adopt(&mut server);
+        let nonce = own_nonce(&mut server, token);
+        hello(&mut server, token, "other", "theirs");
+        auth(&mut server, token, &sign(SECRET, &signed(true, &nonce, "someone's", "other")));
+        assert!(!authenticated(&server, token));
+    }
+
+    #[test]
+    fn nothing_but_the_handshake_before_it_is_done() {
+        let mut server = server();
+        let (token, _end) = adopt(&mut server);
+        server.link_line(token, json!({ "type": "user", "user": "eve" }));
+        assert!(server.remote_users.is_empty());
+        assert!(server.connections[&token].closing);
+    }
+
+    #[test]
+    fn links_only_speak_for_their_own_users() {
+        let mut server = server();
+        let (one, _a) = linked(&mut server, "one", &["ana"]);
+        let (two, _b) = linked(&mut server, "two", &["bob"]);
+        server.link_line(two, json!({ "type": "kick", "by": "ana", "user": "bob",
+                                      "room": "Chat1" }));
+        server.link_line(two, json!({ "type": "topic", "user": "ana", "room": "Chat1",
+                                      "topic": "bob was here" }));
+        server.link_line(two, json!({ "type": "message", "user": "ana", "room": "Chat1",
+                                      "contents": "bob was here" }));
+        assert_eq!(server.groups["Chat1"], ["ana", "bob"]);
+        assert!(server.topics.is_empty());
+        assert!(server.history.get("Chat1").is_none_or(|h| h.is_empty()));
+        server.link_line(one, json!({ "type": "kick", "by": "ana", "user": "bob",
+                                      "room": "Chat1" }));
+        server.link_line(two, json!({ "type": "message", "user": "a bot", "room": "Chat1",
+                                      "contents": "beep" }));
+        assert_eq!(server.groups["Chat1"], ["ana"]);
+        assert_eq!(server.history["Chat1"].len(), 1);
+    }
+
+    #[test]
+    fn lines_with_overlong_fields_are_ignored() {
+        let mut server = server();
+        let (token, _end) = linked(&mut server, "one", &[]);
+        let long = "x".repeat(MAX_FIELD + 1);
+        server.link_line(token, json!({ "type": "user", "user": long }));
+        server.link_line(token, json!({ "type": "burst", "users": [], "rooms": [],
+                                        "topics": { long.clone(): "topic" } }));
+        assert!(server.remote_users.is_empty());
+        assert!(server.topics.is_empty());
+        assert!(fits(&json!({ "a": ["b", { "c": 1 }] })));
+        assert!(!fits(&json!(["b", [long]])));
+    }
+}
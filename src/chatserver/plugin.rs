@@ -0,0 +1,130 @@
+//! Hooks for bots and other extensions, registered with
+//! `ServerBuilder::plugin`. Plugins run in the order they were registered,
+//! on the server's own thread, so a hook should never block.
+
+use message::{FailureCode, Message};
+use super::Server;
+
+/// What to do with the message or join a hook was shown.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    Continue,
+    /// Drop it; later plugins don't see it either.
+    Block,
+}
+
+/// Every hook does nothing by default. Hooks only see this server's own
+/// users, never those of linked servers.
+pub trait Plugin: Send {
+    fn on_connect(&mut self, _ctx: &mut Context, _user: &str) {}
+
+    fn on_disconnect(&mut self, _ctx: &mut Context, _user: &str) {}
+
+    fn on_join(&mut self, _ctx: &mut Context, _user: &str, _room: &str) -> Action {
+        Action::Continue
+    }
+
+    /// Called for leaving, moving to a new chat and being kicked alike.
+    fn on_leave(&mut self, _ctx: &mut Context, _user: &str, _room: &str) {}
+
+    /// `contents` may be changed before the message goes out.
+    fn on_chat(&mut self, _ctx: &mut Context, _user: &str, _room: &str,
+               _contents: &mut String) -> Action {
+        Action::Continue
+    }
+
+    fn on_private(&mut self, _ctx: &mut Context, _from: &str, _to: &str,
+                  _contents: &mut String) -> Action {
+        Action::Continue
+    }
+}
+
+enum Answer {
+    Reply(String),
+    Say { room: String, from: String, contents: String },
+}
+
+/// How a hook answers. Answers go out after the message itself, and never
+/// through the hooks again, so bots can't set each other off.
+pub struct Context {
+    user: String,
+    answers: Vec<Answer>,
+}
+
+impl Context {
+    pub(super) fn new(user: &str) -> Context {
+        Context { user: user.to_owned(), answers: Vec::new() }
+    }
+
+    /// The user whose action triggered the hook.
+    pub fn user(&self) -> &str {
+        &self.user
+    }
+
+    /// Sends the user a message from the server, seen only by them.
+    pub fn reply<S: Into<String>>(&mut self, contents: S) {
+        self.answers.push(Answer::Reply(contents.into()));
+    }
+
+    /// Posts into `room` under the name `from`, like a bot would.
+    pub fn say<S: Into<String>>(&mut self, room: &str, from: &str, contents: S) {
+        self.answers.push(Answer::Say {
+            room: room.to_owned(),
+            from: from.to_owned(),
+            contents: contents.into(),
+        });
+    }
+}
+
+impl Server {
+    /// Runs `hook` on every plugin until one blocks. What they answered is
+    /// for the caller to `answer` once the message itself went out.
+    pub(super) fn run_plugins<F>(&mut self, user: &str, mut hook: F) -> (Action, Context)
+        where F: FnMut(&mut dyn Plugin, &mut Context) -> Action
+    {
+        let mut ctx = Context::new(user);
+        let mut plugins = ::std::mem::take(&mut self.plugins);
+        let action = plugins.iter_mut()
+            .map(|p| hook(p.as_mut(), &mut ctx))
+            .find(|&a| a == Action::Block)
+            .unwrap_or(Action::Continue);
+        self.plugins = plugins;
+        (action, ctx)
+    }
+
+    /// Same as `run_plugins`, for hooks that can't block.
+    pub(super) fn notify_plugins<F>(&mut self, user: &str, mut hook: F)
+        where F: FnMut(&mut dyn Plugin, &mut Context)
+    {
+        let (_, ctx) = self.run_plugins(user, |p, ctx| {
+            hook(p, ctx);
+            Action::Continue
+        });
+        self.answer(ctx);
+    }
+
+    /// Asks the plugins whether `user` may join `room`. If so, what they
+    /// answered is for once the user is in; if not, a request to join
+    /// fails.
+    pub(super) fn ask_join(&mut self, user: &str, room: &str) -> Option<Context> {
+        let (action, ctx) = self.run_plugins(user, |p, ctx| p.on_join(ctx, user, room));
+        if action == Action::Block {
+            self.answer(ctx);
+            self.fail_request(user, FailureCode::Blocked);
+            return None;
+        }
+        Some(ctx)
+    }
+
+    pub(super) fn answer(&mut self, ctx: Context) {
+        let user = ctx.user;
+        for answer in ctx.answers {
+            match answer {
+                Answer::Reply(contents) => self.send_chat_message_to_user(&user, contents),
+                Answer::Say { room, from, contents } => if self.groups.contains_key(&room) {
+                    self.relay_chat(Message::chat_message(from, room, contents), None);
+                },
+            }
+        }
+    }
+}
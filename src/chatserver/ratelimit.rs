@@ -0,0 +1,283 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use message::Message;
+
+/// A token bucket: holds up to `burst` tokens and refills `per_second` of
+/// them every second.
+#[derive(Clone, Copy, Debug)]
+pub struct Rate {
+    pub burst: u32,
+    pub per_second: f64,
+}
+
+impl Rate {
+    pub fn new(burst: u32, per_second: f64) -> Rate {
+        Rate { burst, per_second }
+    }
+}
+
+/// The kinds of client requests that can be limited separately.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum MessageKind {
+    ChatMessage,
+    PrivateMessage,
+    Login,
+    NewChat,
+    KickUser,
+    List,
+    Command,
+}
+
+impl MessageKind {
+    pub fn of(message: &Message) -> Option<MessageKind> {
+        use message::Message::*;
+        match *message {
+            ChatMessage(_,_,_) => Some(MessageKind::ChatMessage),
+            PrivateMessage(_,_,_) => Some(MessageKind::PrivateMessage),
+            Login(_,_) => Some(MessageKind::Login),
+            NewChat(_,_) => Some(MessageKind::NewChat),
+            KickUser(_,_,_) => Some(MessageKind::KickUser),
+            ListGroups(_) | ListUsers(_,_) => Some(MessageKind::List),
+            Command(_,_,_) => Some(MessageKind::Command),
+            _ => None,
+        }
+    }
+
+    /// Whether a muted user is still allowed to do this.
+    fn allowed_while_muted(self) -> bool {
+        !matches!(self, MessageKind::ChatMessage | MessageKind::PrivateMessage |
+                        MessageKind::NewChat)
+    }
+}
+
+/// How repeated offenses are punished. Strikes older than `window` are
+/// forgotten.
+#[derive(Clone, Copy, Debug)]
+pub struct Escalation {
+    pub window: Duration,
+    pub strikes_to_mute: u32,
+    pub mute_for: Duration,
+    pub strikes_to_disconnect: u32,
+}
+
+#[derive(Clone, Debug)]
+pub struct RateLimits {
+    pub per_kind: HashMap<MessageKind, Rate>,
+    /// Shared by everyone talking in the same chat.
+    pub per_room: Option<Rate>,
+    pub escalation: Escalation,
+}
+
+impl Default for RateLimits {
+    fn default() -> RateLimits {
+        let mut per_kind = HashMap::new();
+        per_kind.insert(MessageKind::ChatMessage, Rate::new(10, 2.0));
+        per_kind.insert(MessageKind::PrivateMessage, Rate::new(10, 2.0));
+        per_kind.insert(MessageKind::Login, Rate::new(5, 0.5));
+        per_kind.insert(MessageKind::NewChat, Rate::new(3, 0.1));
+        per_kind.insert(MessageKind::KickUser, Rate::new(5, 0.5));
+        per_kind.insert(MessageKind::List, Rate::new(5, 1.0));
+        per_kind.insert(MessageKind::Command, Rate::new(10, 1.0));
+        RateLimits {
+            per_kind,
+            per_room: Some(Rate::new(50, 20.0)),
+            escalation: Escalation {
+                window: Duration::from_secs(30),
+                strikes_to_mute: 5,
+                mute_for: Duration::from_secs(60),
+                strikes_to_disconnect: 20,
+            },
+        }
+    }
+}
+
+pub struct TokenBucket {
+    rate: Rate,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: Rate) -> TokenBucket {
+        TokenBucket {
+            rate,
+            tokens: f64::from(rate.burst),
+            last: Instant::now(),
+        }
+    }
+
+    /// Whether it would hold all `burst` tokens again by now.
+    pub fn is_full(&self) -> bool {
+        let refilled = self.last.elapsed().as_secs_f64() * self.rate.per_second;
+        self.tokens + refilled >= f64::from(self.rate.burst)
+    }
+
+    pub fn try_take(&mut self) -> bool {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.last = now;
+        self.tokens = (self.tokens + elapsed * self.rate.per_second)
+            .min(f64::from(self.rate.burst));
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+pub enum Verdict {
+    Allowed,
+    Limited,
+    Muted(Duration),
+    Disconnect,
+}
+
+/// Per-user flood state: one bucket per message kind plus the offense
+/// record used for escalation.
+#[derive(Default)]
+pub struct UserLimiter {
+    buckets: HashMap<MessageKind, TokenBucket>,
+    strikes: u32,
+    last_strike: Option<Instant>,
+    muted_until: Option<Instant>,
+}
+
+impl UserLimiter {
+    pub fn check(&mut self, kind: MessageKind, limits: &RateLimits) -> Verdict {
+        let now = Instant::now();
+        if let Some(until) = self.muted_until {
+            if until > now {
+                if kind.allowed_while_muted() {
+                    return Verdict::Allowed;
+                }
+                // Insisting while muted still counts against the user.
+                return match self.strike(limits) {
+                    Verdict::Disconnect => Verdict::Disconnect,
+                    _ => Verdict::Muted(until - now),
+                };
+            }
+            self.muted_until = None;
+        }
+        let rate = match limits.per_kind.get(&kind) {
+            Some(r) => *r,
+            None => return Verdict::Allowed,
+        };
+        if self.buckets.entry(kind).or_insert_with(|| TokenBucket::new(rate)).try_take() {
+            Verdict::Allowed
+        } else {
+            self.strike(limits)
+        }
+    }
+
+    /// Whether there is nothing left to remember: no mute running, and no
+    /// strike that still counts.
+    pub fn is_clean(&self, escalation: &Escalation) -> bool {
+        let now = Instant::now();
+        self.muted_until.is_none_or(|until| until <= now)
+            && self.last_strike.is_none_or(|t| now.duration_since(t) > escalation.window)
+    }
+
+    /// Records an offense and decides how to punish it.
+    fn strike(&mut self, limits: &RateLimits) -> Verdict {
+        let now = Instant::now();
+        let escalation = limits.escalation;
+        if self.last_strike.is_some_and(|t| now.duration_since(t) > escalation.window) {
+            self.strikes = 0;
+        }
+        self.strikes += 1;
+        self.last_strike = Some(now);
+        if self.strikes >= escalation.strikes_to_disconnect {
+            Verdict::Disconnect
+        } else if self.strikes >= escalation.strikes_to_mute {
+            self.muted_until = Some(now + escalation.mute_for);
+            Verdict::Muted(escalation.mute_for)
+        } else {
+            Verdict::Limited
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+    use super::*;
+
+    fn limits(rate: Rate) -> RateLimits {
+        let mut per_kind = HashMap::new();
+        per_kind.insert(MessageKind::ChatMessage, rate);
+        RateLimits {
+            per_kind,
+            per_room: None,
+            escalation: Escalation {
+                window: Duration::from_secs(30),
+                strikes_to_mute: 3,
+                mute_for: Duration::from_secs(60),
+                strikes_to_disconnect: 5,
+            },
+        }
+    }
+
+    #[test]
+    fn buckets_allow_a_burst_then_refill() {
+        let mut bucket = TokenBucket::new(Rate::new(3, 2.0));
+        assert!(bucket.is_full());
+        assert!((0..3).all(|_| bucket.try_take()));
+        assert!(!bucket.try_take());
+        assert!(!bucket.is_full());
+        // Half a second later, one token is back.
+        bucket.last -= Duration::from_millis(500);
+        assert!(bucket.try_take());
+        assert!(!bucket.try_take());
+        // And never more than the burst, however long it sat.
+        bucket.last -= Duration::from_secs(3600);
+        assert!(bucket.is_full());
+        assert!((0..3).all(|_| bucket.try_take()));
+        assert!(!bucket.try_take());
+    }
+
+    #[test]
+    fn strikes_escalate_to_a_mute_then_a_disconnect() {
+        let limits = limits(Rate::new(1, 0.0));
+        let mut limiter = UserLimiter::default();
+        let chat = MessageKind::ChatMessage;
+        assert!(matches!(limiter.check(chat, &limits), Verdict::Allowed));
+        assert!(matches!(limiter.check(chat, &limits), Verdict::Limited));
+        assert!(matches!(limiter.check(chat, &limits), Verdict::Limited));
+        assert!(matches!(limiter.check(chat, &limits), Verdict::Muted(_)));
+        // Muted users may still do what doesn't reach other people.
+        assert!(matches!(limiter.check(MessageKind::List, &limits), Verdict::Allowed));
+        assert!(matches!(limiter.check(chat, &limits), Verdict::Muted(_)));
+        assert!(matches!(limiter.check(chat, &limits), Verdict::Disconnect));
+    }
+
+    #[test]
+    fn old_strikes_are_forgotten() {
+        let limits = limits(Rate::new(1, 0.0));
+        let mut limiter = UserLimiter::default();
+        let chat = MessageKind::ChatMessage;
+        assert!(limiter.is_clean(&limits.escalation));
+        limiter.check(chat, &limits);
+        limiter.check(chat, &limits);
+        limiter.check(chat, &limits);
+        assert!(!limiter.is_clean(&limits.escalation));
+        limiter.last_strike = Some(Instant::now() - Duration::from_secs(31));
+        assert!(limiter.is_clean(&limits.escalation));
+        // The next strike starts over instead of muting.
+        assert!(matches!(limiter.check(chat, &limits), Verdict::Limited));
+        assert_eq!(limiter.strikes, 1);
+    }
+
+    #[test]
+    fn a_mute_runs_its_course() {
+        let limits = limits(Rate::new(1, 0.0));
+        let mut limiter = UserLimiter::default();
+        let chat = MessageKind::ChatMessage;
+        while !matches!(limiter.check(chat, &limits), Verdict::Muted(_)) {}
+        limiter.last_strike = Some(Instant::now() - Duration::from_secs(31));
+        assert!(!limiter.is_clean(&limits.escalation));
+        limiter.muted_until = Some(Instant::now());
+        assert!(limiter.is_clean(&limits.escalation));
+    }
+}
@@ -0,0 +1,242 @@
+//! Operator scripts in [Rhai](https://rhai.rs), run by the `Scripts`
+//! plugin. Every `*.rhai` file in the scripts directory may define any of
+//! these hooks; files are loaded, reloaded and dropped as they change on
+//! disk, with no restart needed.
+//!
+//! ```text
+//! fn on_connect(user) {}
+//! fn on_disconnect(user) {}
+//! fn on_join(user, room) {}         // false keeps the user out
+//! fn on_leave(user, room) {}
+//! fn on_chat(user, room, text) {}   // false drops it, a string replaces it
+//! fn on_private(from, to, text) {}  // same
+//! ```
+//!
+//! Scripts are sandboxed: all they can do is `reply(text)` to the user who
+//! set the hook off, `say(room, text)` under the script's file name, and
+//! `print`, which goes to the server's log. `this` is a map that lives
+//! until the script is reloaded. Code outside functions never runs.
+
+use std::fs;
+use std::io;
+use std::mem;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use rhai::{CallFnOptions, Dynamic, Engine, Map, Scope, AST};
+use super::plugin::{Action, Context, Plugin};
+
+/// How often the directory is looked at again, at most.
+const RELOAD_INTERVAL: Duration = Duration::from_secs(1);
+/// Stops runaway loops; plenty for anything a hook should be doing.
+const MAX_OPERATIONS: u64 = 100_000;
+/// Longest string a script may build, which is what a message can hold.
+const MAX_TEXT: usize = 255;
+
+enum Output {
+    Reply(String),
+    Say { room: String, contents: String },
+}
+
+/// Where the functions scripts call leave their output, to be turned into
+/// answers once the hook returns.
+#[derive(Default)]
+struct Outbox {
+    script: String,
+    out: Vec<Output>,
+}
+
+struct Script {
+    name: String,
+    path: PathBuf,
+    modified: SystemTime,
+    /// `None` while the file doesn't compile and there was no earlier
+    /// version to keep running.
+    ast: Option<AST>,
+    state: Dynamic,
+}
+
+/// Runs the scripts of a directory; add it with `ServerBuilder::plugin`.
+pub struct Scripts {
+    dir: PathBuf,
+    engine: Engine,
+    scripts: Vec<Script>,
+    outbox: Arc<Mutex<Outbox>>,
+    checked: Instant,
+}
+
+impl Scripts {
+    /// Loads every script in `dir`. Scripts that don't compile are
+    /// reported and left out until they are fixed.
+    pub fn load<P: Into<PathBuf>>(dir: P) -> io::Result<Scripts> {
+        let dir = dir.into();
+        if !fs::metadata(&dir)?.is_dir() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "scripts must be in a directory"));
+        }
+        let outbox = Arc::new(Mutex::new(Outbox::default()));
+        let mut scripts = Scripts {
+            dir,
+            engine: sandbox(&outbox),
+            scripts: Vec::new(),
+            outbox,
+            checked: Instant::now(),
+        };
+        scripts.reload();
+        Ok(scripts)
+    }
+
+    /// Compiles what is new or changed since the last look, keeping the
+    /// running version of a script whose new one doesn't compile.
+    fn reload(&mut self) {
+        self.checked = Instant::now();
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(e) => e,
+            Err(e) => return println!("Failed reading {}: {}", self.dir.display(), e),
+        };
+        let mut found: Vec<(PathBuf, SystemTime)> = entries.filter_map(Result::ok)
+            .map(|e| e.path())
+            .filter(|p| p.extension().is_some_and(|e| e == "rhai"))
+            .filter_map(|p| fs::metadata(&p).and_then(|m| m.modified()).ok().map(|m| (p, m)))
+            .collect();
+        found.sort();
+        let mut old = mem::take(&mut self.scripts);
+        for (path, modified) in found {
+            let previous = old.iter().position(|s| s.path == path).map(|i| old.remove(i));
+            let mut script = match previous {
+                Some(s) if s.modified == modified => {
+                    self.scripts.push(s);
+                    continue;
+                },
+                Some(s) => s,
+                None => Script {
+                    name: path.file_stem().unwrap_or_default().to_string_lossy().into_owned(),
+                    path: path.clone(),
+                    modified,
+                    ast: None,
+                    state: Dynamic::from_map(Map::new()),
+                },
+            };
+            script.modified = modified;
+            match self.engine.compile_file(path) {
+                Ok(ast) => {
+                    println!("Loaded script {}", script.name);
+                    script.ast = Some(ast);
+                    script.state = Dynamic::from_map(Map::new());
+                },
+                Err(e) => println!("Failed loading script {}: {}", script.name, e),
+            }
+            self.scripts.push(script);
+        }
+        for script in old {
+            println!("Unloaded script {}", script.name);
+        }
+    }
+
+    /// Calls `hook` in every script that defines it, in file name order,
+    /// until one returns false. When `edits` is set, a string returned
+    /// replaces the last argument for the scripts after it.
+    fn run(&mut self, ctx: &mut Context, hook: &str, args: &mut [Dynamic], edits: bool)
+           -> Action {
+        if self.checked.elapsed() >= RELOAD_INTERVAL {
+            self.reload();
+        }
+        for script in &mut self.scripts {
+            let Script { ref name, ref ast, ref mut state, .. } = *script;
+            let ast = match *ast {
+                Some(ref ast) if ast.iter_functions()
+                    .any(|f| f.name == hook && f.params.len() == args.len()) => ast,
+                _ => continue,
+            };
+            self.outbox.lock().unwrap().script = name.clone();
+            let options = CallFnOptions::new().eval_ast(false).bind_this_ptr(state);
+            let result = self.engine.call_fn_with_options::<Dynamic>(options,
+                                                                    &mut Scope::new(),
+                                                                    ast,
+                                                                    hook,
+                                                                    args.to_vec());
+            for output in self.outbox.lock().unwrap().out.drain(..) {
+                match output {
+                    Output::Reply(contents) => ctx.reply(contents),
+                    Output::Say { room, contents } => ctx.say(&room, name, contents),
+                }
+            }
+            match result {
+                Err(e) => println!("Script {} failed in {}: {}", name, hook, e),
+                Ok(ref v) if v.as_bool() == Ok(false) => return Action::Block,
+                Ok(v) => if edits && v.is_string() {
+                    if let Some(last) = args.last_mut() {
+                        *last = v;
+                    }
+                },
+            }
+        }
+        Action::Continue
+    }
+
+    /// `run` for hooks whose text can be replaced.
+    fn edit(&mut self, ctx: &mut Context, hook: &str, a: &str, b: &str,
+            text: &mut String) -> Action {
+        let mut args = [a.into(), b.into(), text.as_str().into()];
+        let action = self.run(ctx, hook, &mut args, true);
+        if let Ok(edited) = args[2].clone().into_string() {
+            *text = edited;
+        }
+        action
+    }
+}
+
+/// An engine that can't reach anything outside the scripts, with limits
+/// on how long and how big they can get.
+fn sandbox(outbox: &Arc<Mutex<Outbox>>) -> Engine {
+    let mut engine = Engine::new();
+    engine.set_max_operations(MAX_OPERATIONS)
+        .set_max_call_levels(32)
+        .set_max_expr_depths(64, 32)
+        .set_max_string_size(MAX_TEXT)
+        .set_max_array_size(1000)
+        .set_max_map_size(1000)
+        .disable_symbol("eval");
+    let out = outbox.clone();
+    engine.on_print(move |text| println!("Script {}: {}", out.lock().unwrap().script, text));
+    let out = outbox.clone();
+    engine.register_fn("reply", move |text: &str| {
+        out.lock().unwrap().out.push(Output::Reply(text.to_owned()));
+    });
+    let out = outbox.clone();
+    engine.register_fn("say", move |room: &str, text: &str| {
+        out.lock().unwrap().out.push(Output::Say {
+            room: room.to_owned(),
+            contents: text.to_owned(),
+        });
+    });
+    engine
+}
+
+impl Plugin for Scripts {
+    fn on_connect(&mut self, ctx: &mut Context, user: &str) {
+        self.run(ctx, "on_connect", &mut [user.into()], false);
+    }
+
+    fn on_disconnect(&mut self, ctx: &mut Context, user: &str) {
+        self.run(ctx, "on_disconnect", &mut [user.into()], false);
+    }
+
+    fn on_join(&mut self, ctx: &mut Context, user: &str, room: &str) -> Action {
+        self.run(ctx, "on_join", &mut [user.into(), room.into()], false)
+    }
+
+    fn on_leave(&mut self, ctx: &mut Context, user: &str, room: &str) {
+        self.run(ctx, "on_leave", &mut [user.into(), room.into()], false);
+    }
+
+    fn on_chat(&mut self, ctx: &mut Context, user: &str, room: &str,
+               contents: &mut String) -> Action {
+        self.edit(ctx, "on_chat", user, room, contents)
+    }
+
+    fn on_private(&mut self, ctx: &mut Context, from: &str, to: &str,
+                  contents: &mut String) -> Action {
+        self.edit(ctx, "on_private", from, to, contents)
+    }
+}
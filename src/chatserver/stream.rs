@@ -0,0 +1,124 @@
+#[cfg(unix)]
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+#[cfg(unix)]
+use std::path::Path;
+use mio::{Interest, Registry, Token};
+use mio::event::Source;
+use mio::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use mio::net::{UnixListener, UnixStream};
+use transport::ChannelTransport;
+
+/// The server end of any of the transports, as seen by the event loop.
+/// Channel ends have no file descriptor; they wake the loop through the
+/// notify hook set up when they are adopted, so registering them is a
+/// no-op, and dropping them is what hangs up on the other end.
+pub enum Stream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+    Channel(ChannelTransport),
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            Stream::Tcp(ref mut s) => s.read(buf),
+            #[cfg(unix)]
+            Stream::Unix(ref mut s) => s.read(buf),
+            Stream::Channel(ref c) => c.try_read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            Stream::Tcp(ref mut s) => s.write(buf),
+            #[cfg(unix)]
+            Stream::Unix(ref mut s) => s.write(buf),
+            Stream::Channel(ref c) => c.write_bytes(buf).map(|_| buf.len()),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            Stream::Tcp(ref mut s) => s.flush(),
+            #[cfg(unix)]
+            Stream::Unix(ref mut s) => s.flush(),
+            Stream::Channel(_) => Ok(()),
+        }
+    }
+}
+
+impl Source for Stream {
+    fn register(&mut self, registry: &Registry, token: Token, interests: Interest)
+                -> io::Result<()> {
+        match *self {
+            Stream::Tcp(ref mut s) => s.register(registry, token, interests),
+            #[cfg(unix)]
+            Stream::Unix(ref mut s) => s.register(registry, token, interests),
+            Stream::Channel(_) => Ok(()),
+        }
+    }
+
+    fn reregister(&mut self, registry: &Registry, token: Token, interests: Interest)
+                  -> io::Result<()> {
+        match *self {
+            Stream::Tcp(ref mut s) => s.reregister(registry, token, interests),
+            #[cfg(unix)]
+            Stream::Unix(ref mut s) => s.reregister(registry, token, interests),
+            Stream::Channel(_) => Ok(()),
+        }
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+        match *self {
+            Stream::Tcp(ref mut s) => s.deregister(registry),
+            #[cfg(unix)]
+            Stream::Unix(ref mut s) => s.deregister(registry),
+            Stream::Channel(_) => Ok(()),
+        }
+    }
+}
+
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+impl Listener {
+    /// Binds a Unix socket, replacing a stale socket file left behind by a
+    /// server that is no longer running.
+    #[cfg(unix)]
+    pub fn bind_unix(path: &Path) -> io::Result<Listener> {
+        match UnixListener::bind(path) {
+            Err(ref e) if e.kind() == io::ErrorKind::AddrInUse
+                && ::std::os::unix::net::UnixStream::connect(path).is_err() => {
+                fs::remove_file(path)?;
+                UnixListener::bind(path).map(Listener::Unix)
+            },
+            result => result.map(Listener::Unix),
+        }
+    }
+
+    /// Accepts the next connection, with the peer address for TCP ones.
+    pub fn accept(&self) -> io::Result<(Stream, Option<SocketAddr>)> {
+        match *self {
+            Listener::Tcp(ref l) => l.accept().map(|(s, a)| (Stream::Tcp(s), Some(a))),
+            #[cfg(unix)]
+            Listener::Unix(ref l) => l.accept().map(|(s, _)| (Stream::Unix(s), None)),
+        }
+    }
+
+    pub fn source(&mut self) -> &mut dyn Source {
+        match *self {
+            Listener::Tcp(ref mut l) => l,
+            #[cfg(unix)]
+            Listener::Unix(ref mut l) => l,
+        }
+    }
+}
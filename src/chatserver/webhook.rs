@@ -0,0 +1,234 @@
+//! Outgoing webhooks: room events POSTed as JSON to plain `http://` URLs.
+//! Deliveries happen on a thread of their own, so a slow or dead endpoint
+//! only ever delays other deliveries, never the chat; events that come
+//! faster than they can be delivered are dropped.
+//!
+//! Every payload has `event` (`message`, `join`, `leave`, `kick`, `create`
+//! or `topic`), `room`, `user`, a sequence `id` and `time` in seconds
+//! since the Unix epoch, plus `contents`, `by`, `reason` or `topic`
+//! depending on the event.
+
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use serde_json::Value;
+use super::event::RoomEvent;
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+const FIRST_RETRY: Duration = Duration::from_secs(1);
+const MAX_RETRY: Duration = Duration::from_secs(60);
+const MAX_ATTEMPTS: u32 = 8;
+/// Deliveries waiting for a retry beyond this are dropped, oldest first.
+const MAX_PENDING: usize = 1000;
+/// Deliveries the server may hand over before the thread takes them;
+/// more are dropped.
+const MAX_QUEUED: usize = 1000;
+
+/// Where to send the events of one room, or of every room when `room` is
+/// `None`.
+#[derive(Clone, Debug)]
+pub struct Webhook {
+    pub room: Option<String>,
+    pub url: String,
+}
+
+struct Target {
+    room: Option<String>,
+    url: String,
+    host: String,
+    port: u16,
+    path: String,
+}
+
+struct Delivery {
+    target: Arc<Target>,
+    body: String,
+    attempts: u32,
+    due: Instant,
+}
+
+/// The server's end of the delivery queue.
+#[derive(Default)]
+pub struct Webhooks {
+    targets: Vec<Arc<Target>>,
+    queue: Option<SyncSender<Delivery>>,
+    next_id: u64,
+    /// Deliveries dropped since the queue was last found full.
+    dropped: u64,
+}
+
+impl Webhooks {
+    /// Checks the URLs and, if there are any, starts the delivery thread.
+    pub fn start(hooks: &[Webhook]) -> io::Result<Webhooks> {
+        let targets = hooks.iter().map(Target::parse).collect::<io::Result<Vec<_>>>()?;
+        if targets.is_empty() {
+            return Ok(Default::default());
+        }
+        let (queue, pending) = mpsc::sync_channel(MAX_QUEUED);
+        thread::Builder::new()
+            .name("webhooks".into())
+            .spawn(move || deliver(pending))?;
+        Ok(Webhooks { targets, queue: Some(queue), next_id: 0, dropped: 0 })
+    }
+
+    pub fn announce(&mut self, event: &RoomEvent) {
+        match *event {
+            RoomEvent::Joined { ref user, ref room } =>
+                self.notify(room, json!({ "event": "join", "user": user })),
+            RoomEvent::Left { ref user, ref room } =>
+                self.notify(room, json!({ "event": "leave", "user": user })),
+            RoomEvent::Kicked { ref by, ref user, ref room } =>
+                self.notify(room, json!({ "event": "kick", "user": user, "by": by })),
+            RoomEvent::Quit { ref user, ref rooms, ref reason } => for room in rooms {
+                self.notify(room, json!({ "event": "leave", "user": user, "reason": reason }));
+            },
+            RoomEvent::Topic { ref user, ref room, ref topic } =>
+                self.notify(room, json!({ "event": "topic", "user": user, "topic": topic })),
+        }
+    }
+
+    pub fn message(&mut self, room: &str, user: &str, contents: &str) {
+        self.notify(room, json!({ "event": "message", "user": user, "contents": contents }));
+    }
+
+    pub fn created(&mut self, room: &str, user: &str) {
+        self.notify(room, json!({ "event": "create", "user": user }));
+    }
+
+    fn notify(&mut self, room: &str, mut payload: Value) {
+        let queue = match self.queue {
+            Some(ref q) => q,
+            None => return,
+        };
+        self.next_id += 1;
+        payload["id"] = json!(self.next_id);
+        payload["room"] = json!(room);
+        payload["time"] = json!(SystemTime::now().duration_since(UNIX_EPOCH)
+                                .map(|d| d.as_secs())
+                                .unwrap_or(0));
+        let body = payload.to_string();
+        for target in &self.targets {
+            if target.room.as_ref().is_some_and(|r| r != room) {
+                continue;
+            }
+            let delivery = Delivery {
+                target: target.clone(),
+                body: body.clone(),
+                attempts: 0,
+                due: Instant::now(),
+            };
+            match queue.try_send(delivery) {
+                Ok(()) if self.dropped > 0 => {
+                    eprintln!("Webhook queue was full, dropped {} deliveries", self.dropped);
+                    self.dropped = 0;
+                },
+                Ok(()) | Err(TrySendError::Disconnected(_)) => (),
+                Err(TrySendError::Full(_)) => {
+                    if self.dropped == 0 {
+                        eprintln!("Webhook queue is full, dropping deliveries");
+                    }
+                    self.dropped += 1;
+                },
+            }
+        }
+    }
+}
+
+impl Target {
+    fn parse(hook: &Webhook) -> io::Result<Arc<Target>> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidInput,
+                                        format!("webhook URL must be http://HOST[:PORT]/PATH, \
+                                                 got {}", hook.url));
+        let rest = hook.url.strip_prefix("http://").ok_or_else(invalid)?;
+        let (authority, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, "/"),
+        };
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((h, p)) if !p.contains(']') => (h, p.parse().map_err(|_| invalid())?),
+            _ => (authority, 80),
+        };
+        if host.is_empty() {
+            return Err(invalid());
+        }
+        Ok(Arc::new(Target {
+            room: hook.room.clone(),
+            url: hook.url.clone(),
+            host: host.to_owned(),
+            port,
+            path: path.to_owned(),
+        }))
+    }
+
+    fn post(&self, body: &str) -> io::Result<()> {
+        let host = self.host.trim_start_matches('[').trim_end_matches(']');
+        let addr = (host, self.port).to_socket_addrs()?.next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "host not found"))?;
+        let mut socket = TcpStream::connect_timeout(&addr, TIMEOUT)?;
+        socket.set_read_timeout(Some(TIMEOUT))?;
+        socket.set_write_timeout(Some(TIMEOUT))?;
+        write!(socket, "POST {} HTTP/1.1\r\nHost: {}:{}\r\nUser-Agent: chat_np1\r\n\
+                        Content-Type: application/json\r\nContent-Length: {}\r\n\
+                        Connection: close\r\n\r\n{}",
+               self.path, self.host, self.port, body.len(), body)?;
+        let mut head = Vec::new();
+        let mut byte = [0u8; 1];
+        while !head.ends_with(b"\r\n") && head.len() < 1024 {
+            if socket.read(&mut byte)? == 0 {
+                break;
+            }
+            head.push(byte[0]);
+        }
+        let status = String::from_utf8_lossy(&head);
+        match status.split(' ').nth(1) {
+            Some(code) if code.starts_with('2') => Ok(()),
+            Some(code) => Err(io::Error::other(format!("answered {}", code))),
+            None => Err(io::Error::new(io::ErrorKind::InvalidData, "no HTTP response")),
+        }
+    }
+}
+
+/// Runs on the delivery thread until the server goes away.
+fn deliver(pending: Receiver<Delivery>) {
+    let mut queue: Vec<Delivery> = Vec::new();
+    loop {
+        let now = Instant::now();
+        let (due, later): (Vec<_>, Vec<_>) = queue.into_iter().partition(|d| d.due <= now);
+        queue = later;
+        for mut delivery in due {
+            if let Err(e) = delivery.target.post(&delivery.body) {
+                delivery.attempts += 1;
+                if delivery.attempts >= MAX_ATTEMPTS {
+                    eprintln!("Giving up on webhook {}: {}", delivery.target.url, e);
+                    continue;
+                }
+                let wait = ::std::cmp::min(FIRST_RETRY * 2u32.pow(delivery.attempts - 1),
+                                           MAX_RETRY);
+                eprintln!("Webhook {} failed ({}), retrying in {:?}",
+                          delivery.target.url, e, wait);
+                delivery.due = Instant::now() + wait;
+                queue.push(delivery);
+            }
+        }
+        let received = match queue.iter().map(|d| d.due).min() {
+            Some(next) => pending.recv_timeout(next.saturating_duration_since(Instant::now())),
+            None => pending.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+        match received {
+            Ok(delivery) => {
+                queue.push(delivery);
+                queue.extend(pending.try_iter());
+            },
+            Err(RecvTimeoutError::Timeout) => (),
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+        if queue.len() > MAX_PENDING {
+            let dropped = queue.len() - MAX_PENDING;
+            eprintln!("Too many webhook deliveries pending, dropping {}", dropped);
+            queue.drain(..dropped);
+        }
+    }
+}
@@ -0,0 +1,434 @@
+//! Just enough of RFC 6455 for browsers: the HTTP upgrade, masked client
+//! frames, fragmentation, ping and close. Each text frame carries one
+//! `Message` as JSON (see `Message::to_json`).
+
+use std::io;
+use ring::digest;
+use message::{FailureCode, Message};
+
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+const MAX_HANDSHAKE: usize = 8 * 1024;
+const MAX_MESSAGE: usize = 64 * 1024;
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xA;
+
+const CLOSE_NORMAL: u16 = 1000;
+const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+const CLOSE_UNSUPPORTED: u16 = 1003;
+const CLOSE_INVALID_DATA: u16 = 1007;
+const CLOSE_TOO_BIG: u16 = 1009;
+
+#[derive(Default)]
+pub struct WebSocket {
+    buf: Vec<u8>,
+    upgraded: bool,
+    /// Text of a fragmented message received so far.
+    fragments: Option<Vec<u8>>,
+}
+
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+impl WebSocket {
+    pub fn feed(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    pub fn decode(&mut self, replies: &mut Vec<Vec<u8>>) -> io::Result<Vec<Message>> {
+        let mut messages = Vec::new();
+        if !self.upgraded && !self.handshake(replies)? {
+            return Ok(messages);
+        }
+        while let Some(frame) = self.next_frame(replies)? {
+            match frame.opcode {
+                OP_TEXT | OP_CONTINUATION => {
+                    let text = match (frame.opcode, self.fragments.take()) {
+                        (OP_TEXT, None) => frame.payload,
+                        (OP_CONTINUATION, Some(mut text)) => {
+                            text.extend_from_slice(&frame.payload);
+                            text
+                        },
+                        _ => return Err(close(replies, CLOSE_PROTOCOL_ERROR,
+                                              "unexpected continuation")),
+                    };
+                    if text.len() > MAX_MESSAGE {
+                        return Err(close(replies, CLOSE_TOO_BIG, "message too big"));
+                    }
+                    if !frame.fin {
+                        self.fragments = Some(text);
+                        continue;
+                    }
+                    let text = String::from_utf8(text)
+                        .map_err(|_| close(replies, CLOSE_INVALID_DATA, "invalid UTF-8"))?;
+                    match Message::from_json(&text).and_then(|m| m.check_fields().map(|_| m)) {
+                        Ok(m) => messages.push(m),
+                        Err(e) => replies.push(WebSocket::encode(
+                            &Message::failure(FailureCode::InvalidMessage,
+                                              format!("Invalid message: {}", e)))),
+                    }
+                },
+                OP_BINARY => return Err(close(replies, CLOSE_UNSUPPORTED,
+                                              "only text frames are supported")),
+                OP_PING => replies.push(frame_bytes(OP_PONG, &frame.payload)),
+                OP_PONG => (),
+                OP_CLOSE => return Err(close(replies, CLOSE_NORMAL, "")),
+                _ => return Err(close(replies, CLOSE_PROTOCOL_ERROR, "unknown opcode")),
+            }
+        }
+        Ok(messages)
+    }
+
+    /// Answers the HTTP upgrade request once it has fully arrived. Returns
+    /// whether the connection is now speaking WebSocket.
+    fn handshake(&mut self, replies: &mut Vec<Vec<u8>>) -> io::Result<bool> {
+        let end = match self.buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            Some(p) => p + 4,
+            None if self.buf.len() > MAX_HANDSHAKE => {
+                replies.push(http_response("431 Request Header Fields Too Large", "", ""));
+                return Err(invalid("handshake too long"));
+            },
+            None => return Ok(false),
+        };
+        let request: Vec<u8> = self.buf.drain(..end).collect();
+        let request = String::from_utf8_lossy(&request);
+        let mut lines = request.split("\r\n");
+        if !lines.next().is_some_and(|l| l.starts_with("GET ")) {
+            replies.push(http_response("405 Method Not Allowed", "", "Use GET\n"));
+            return Err(invalid("not a GET request"));
+        }
+        let header = |name: &str| {
+            request.split("\r\n").skip(1)
+                .filter_map(|l| l.split_once(':'))
+                .find(|&(n, _)| n.trim().eq_ignore_ascii_case(name))
+                .map(|(_, v)| v.trim().to_owned())
+        };
+        let upgrade = header("upgrade").is_some_and(|u| u.eq_ignore_ascii_case("websocket"));
+        let key = match header("sec-websocket-key") {
+            Some(k) if upgrade => k,
+            _ => {
+                replies.push(http_response("400 Bad Request", "",
+                                           "Expected a WebSocket upgrade\n"));
+                return Err(invalid("not a WebSocket upgrade"));
+            },
+        };
+        if header("sec-websocket-version").as_deref() != Some("13") {
+            replies.push(http_response("426 Upgrade Required",
+                                       "Sec-WebSocket-Version: 13\r\n", ""));
+            return Err(invalid("unsupported WebSocket version"));
+        }
+        let accept = digest::digest(&digest::SHA1_FOR_LEGACY_USE_ONLY,
+                                    format!("{}{}", key, GUID).as_bytes());
+        replies.push(format!("HTTP/1.1 101 Switching Protocols\r\n\
+                              Upgrade: websocket\r\n\
+                              Connection: Upgrade\r\n\
+                              Sec-WebSocket-Accept: {}\r\n\r\n",
+                             base64(accept.as_ref())).into_bytes());
+        self.upgraded = true;
+        Ok(true)
+    }
+
+    fn next_frame(&mut self, replies: &mut Vec<Vec<u8>>) -> io::Result<Option<Frame>> {
+        let buf = &self.buf;
+        if buf.len() < 2 {
+            return Ok(None);
+        }
+        let fin = buf[0] & 0x80 != 0;
+        let opcode = buf[0] & 0x0F;
+        if buf[0] & 0x70 != 0 {
+            return Err(close(replies, CLOSE_PROTOCOL_ERROR, "no extensions were negotiated"));
+        }
+        if buf[1] & 0x80 == 0 {
+            return Err(close(replies, CLOSE_PROTOCOL_ERROR, "client frames must be masked"));
+        }
+        let (len, mut pos) = match buf[1] & 0x7F {
+            126 if buf.len() >= 4 => (u16::from_be_bytes([buf[2], buf[3]]) as u64, 4),
+            127 if buf.len() >= 10 => {
+                let mut len = [0u8; 8];
+                len.copy_from_slice(&buf[2..10]);
+                (u64::from_be_bytes(len), 10)
+            },
+            126 | 127 => return Ok(None),
+            n => (u64::from(n), 2),
+        };
+        if opcode >= OP_CLOSE && (!fin || len > 125) {
+            return Err(close(replies, CLOSE_PROTOCOL_ERROR, "invalid control frame"));
+        }
+        if len > MAX_MESSAGE as u64 {
+            return Err(close(replies, CLOSE_TOO_BIG, "message too big"));
+        }
+        let len = len as usize;
+        if buf.len() < pos + 4 + len {
+            return Ok(None);
+        }
+        let mask = [buf[pos], buf[pos + 1], buf[pos + 2], buf[pos + 3]];
+        pos += 4;
+        let payload = buf[pos..pos + len].iter()
+            .enumerate()
+            .map(|(i, b)| b ^ mask[i % 4])
+            .collect();
+        self.buf.drain(..pos + len);
+        Ok(Some(Frame { fin, opcode, payload }))
+    }
+
+    pub fn encode(m: &Message) -> Vec<u8> {
+        frame_bytes(OP_TEXT, m.to_json().as_bytes())
+    }
+
+    pub fn refusal(reason: &str) -> Vec<u8> {
+        http_response("503 Service Unavailable", "", &format!("{}\n", reason))
+    }
+}
+
+/// An unmasked frame, as servers send them.
+fn frame_bytes(opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = vec![0x80 | opcode];
+    match payload.len() {
+        n if n < 126 => frame.push(n as u8),
+        n if n <= 0xFFFF => {
+            frame.push(126);
+            frame.extend_from_slice(&(n as u16).to_be_bytes());
+        },
+        n => {
+            frame.push(127);
+            frame.extend_from_slice(&(n as u64).to_be_bytes());
+        },
+    }
+    frame.extend_from_slice(payload);
+    frame
+}
+
+/// Queues a close frame and returns the error that drops the connection.
+fn close(replies: &mut Vec<Vec<u8>>, code: u16, reason: &str) -> io::Error {
+    let mut payload = code.to_be_bytes().to_vec();
+    payload.extend_from_slice(reason.as_bytes());
+    replies.push(frame_bytes(OP_CLOSE, &payload));
+    invalid(if reason.is_empty() { "closed by peer" } else { reason })
+}
+
+fn invalid(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason.to_owned())
+}
+
+fn http_response(status: &str, headers: &str, body: &str) -> Vec<u8> {
+    format!("HTTP/1.1 {}\r\n{}Content-Type: text/plain\r\nContent-Length: {}\r\n\
+             Connection: close\r\n\r\n{}",
+            status, headers, body.len(), body).into_bytes()
+}
+
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::new();
+    for chunk in bytes.chunks(3) {
+        let n = chunk.iter().enumerate()
+            .fold(0u32, |acc, (i, &b)| acc | u32::from(b) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i) & 0x3F) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use message::Message;
+    use super::*;
+
+    const UPGRADE: &[u8] = b"GET /chat HTTP/1.1\r\nHost: example.com\r\nUpgrade: websocket\r\n\
+                             Connection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                             Sec-WebSocket-Version: 13\r\n\r\n";
+
+    /// A client frame, masked as clients must.
+    fn masked(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [0x37, 0xfa, 0x21, 0x3d];
+        let mut frame = vec![if fin { 0x80 } else { 0 } | opcode];
+        match payload.len() {
+            n if n < 126 => frame.push(0x80 | n as u8),
+            n if n <= 0xFFFF => {
+                frame.push(0x80 | 126);
+                frame.extend_from_slice(&(n as u16).to_be_bytes());
+            },
+            n => {
+                frame.push(0x80 | 127);
+                frame.extend_from_slice(&(n as u64).to_be_bytes());
+            },
+        }
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        frame
+    }
+
+    fn upgraded() -> WebSocket {
+        let mut ws = WebSocket::default();
+        ws.feed(UPGRADE);
+        assert!(ws.decode(&mut Vec::new()).unwrap().is_empty());
+        ws
+    }
+
+    fn json(messages: Vec<Message>) -> Vec<String> {
+        messages.iter().map(|m| m.to_json()).collect()
+    }
+
+    fn text(status: &[u8]) -> String {
+        String::from_utf8_lossy(status).into_owned()
+    }
+
+    /// The close code of a close frame the server sent.
+    fn close_code(reply: &[u8]) -> u16 {
+        assert_eq!(reply[0], 0x80 | OP_CLOSE);
+        u16::from_be_bytes([reply[2], reply[3]])
+    }
+
+    fn refused(ws: &mut WebSocket, bytes: &[u8]) -> u16 {
+        let mut replies = Vec::new();
+        ws.feed(bytes);
+        assert!(ws.decode(&mut replies).is_err());
+        close_code(replies.last().expect("a close frame"))
+    }
+
+    #[test]
+    fn the_handshake_answers_the_rfc_sample_key() {
+        let mut ws = WebSocket::default();
+        let mut replies = Vec::new();
+        ws.feed(UPGRADE);
+        ws.decode(&mut replies).unwrap();
+        let answer = text(&replies[0]);
+        assert!(answer.starts_with("HTTP/1.1 101 "));
+        assert!(answer.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+    }
+
+    #[test]
+    fn base64_pads_like_the_rfc() {
+        let encoded: Vec<String> = ["", "f", "fo", "foo", "foob", "fooba", "foobar"].iter()
+            .map(|s| base64(s.as_bytes()))
+            .collect();
+        assert_eq!(encoded, ["", "Zg==", "Zm8=", "Zm9v", "Zm9vYg==", "Zm9vYmE=", "Zm9vYmFy"]);
+    }
+
+    #[test]
+    fn the_handshake_waits_until_complete() {
+        let mut ws = WebSocket::default();
+        let mut replies = Vec::new();
+        let (head, tail) = UPGRADE.split_at(40);
+        ws.feed(head);
+        assert!(ws.decode(&mut replies).unwrap().is_empty());
+        assert!(replies.is_empty());
+        ws.feed(tail);
+        ws.feed(&masked(true, OP_TEXT, br#"{"type": "list_groups", "username": "ana"}"#));
+        assert_eq!(json(ws.decode(&mut replies).unwrap()),
+                   [Message::ListGroups("ana".into()).to_json()]);
+    }
+
+    #[test]
+    fn bad_handshakes_get_an_http_error() {
+        let cases: [(&[u8], &str); 4] = [
+            (b"POST / HTTP/1.1\r\n\r\n", "405"),
+            (b"GET / HTTP/1.1\r\nHost: x\r\n\r\n", "400"),
+            (b"GET / HTTP/1.1\r\nUpgrade: websocket\r\nSec-WebSocket-Key: a\r\n\
+               Sec-WebSocket-Version: 8\r\n\r\n", "426"),
+            (&[b'x'; MAX_HANDSHAKE + 1], "431"),
+        ];
+        for &(request, status) in &cases {
+            let mut ws = WebSocket::default();
+            let mut replies = Vec::new();
+            ws.feed(request);
+            assert!(ws.decode(&mut replies).is_err());
+            assert!(text(&replies[0]).starts_with(&format!("HTTP/1.1 {} ", status)));
+        }
+    }
+
+    #[test]
+    fn frames_are_unmasked_and_reassembled() {
+        let mut ws = upgraded();
+        let mut replies = Vec::new();
+        let body = br#"{"type": "chat_message", "username": "ana", "chat": "Chat1",
+                        "contents": "hi"}"#;
+        let (first, rest) = body.split_at(10);
+        ws.feed(&masked(false, OP_TEXT, first));
+        // Control frames may come between fragments.
+        ws.feed(&masked(true, OP_PING, b"are you there"));
+        ws.feed(&masked(true, OP_CONTINUATION, rest));
+        let messages = ws.decode(&mut replies).unwrap();
+        assert_eq!(json(messages), [Message::chat_message("ana", "Chat1", "hi").to_json()]);
+        assert_eq!(replies, [frame_bytes(OP_PONG, b"are you there")]);
+    }
+
+    #[test]
+    fn truncated_frames_wait_for_the_rest() {
+        let mut ws = upgraded();
+        let mut replies = Vec::new();
+        let contents = "x".repeat(200);
+        let body = format!(r#"{{"type": "chat_message", "username": "ana", "chat": "Chat1",
+                               "contents": "{}"}}"#, contents);
+        let frame = masked(true, OP_TEXT, body.as_bytes());
+        // Cut inside the extended length, then inside the mask, then the payload.
+        for cut in [1, 3, 6, 20] {
+            let mut ws = upgraded();
+            ws.feed(&frame[..cut]);
+            assert!(ws.decode(&mut replies).unwrap().is_empty());
+            ws.feed(&frame[cut..]);
+            assert_eq!(ws.decode(&mut replies).unwrap().len(), 1);
+        }
+        ws.feed(&frame);
+        assert_eq!(json(ws.decode(&mut replies).unwrap()),
+                   [Message::chat_message("ana", "Chat1", &contents).to_json()]);
+        assert!(replies.is_empty());
+    }
+
+    #[test]
+    fn oversized_frames_are_refused_from_their_header() {
+        let mut ws = upgraded();
+        // Only the header of a 2^63 byte frame: no need to wait for the rest.
+        let mut header = vec![0x80 | OP_TEXT, 0x80 | 127];
+        header.extend_from_slice(&(1u64 << 63).to_be_bytes());
+        assert_eq!(refused(&mut ws, &header), CLOSE_TOO_BIG);
+        let mut ws = upgraded();
+        let half = vec![b' '; MAX_MESSAGE / 2 + 1];
+        ws.feed(&masked(false, OP_TEXT, &half));
+        assert_eq!(refused(&mut ws, &masked(true, OP_CONTINUATION, &half)), CLOSE_TOO_BIG);
+    }
+
+    #[test]
+    fn protocol_errors_close_the_connection() {
+        let mut unmasked = masked(true, OP_TEXT, b"{}");
+        unmasked[1] &= 0x7F;
+        let cases = [
+            (unmasked, CLOSE_PROTOCOL_ERROR),
+            (masked(true, OP_CONTINUATION, b"stray"), CLOSE_PROTOCOL_ERROR),
+            (masked(true, OP_TEXT | 0x40, b"{}"), CLOSE_PROTOCOL_ERROR),
+            (masked(false, OP_PING, b""), CLOSE_PROTOCOL_ERROR),
+            (masked(true, OP_PING, &[0; 126]), CLOSE_PROTOCOL_ERROR),
+            (masked(true, 0x3, b""), CLOSE_PROTOCOL_ERROR),
+            (masked(true, OP_BINARY, b"\x00"), CLOSE_UNSUPPORTED),
+            (masked(true, OP_TEXT, b"\xff\xfe"), CLOSE_INVALID_DATA),
+            (masked(true, OP_CLOSE, &CLOSE_NORMAL.to_be_bytes()), CLOSE_NORMAL),
+        ];
+        for (frame, code) in cases.iter() {
+            assert_eq!(refused(&mut upgraded(), frame), *code, "{:?}", frame);
+        }
+    }
+
+    #[test]
+    fn invalid_messages_get_a_failure_and_reading_goes_on() {
+        let mut ws = upgraded();
+        let mut replies = Vec::new();
+        ws.feed(&masked(true, OP_TEXT, b"not json"));
+        let long = format!(r#"{{"type": "init_user", "username": "{}"}}"#, "x".repeat(256));
+        ws.feed(&masked(true, OP_TEXT, long.as_bytes()));
+        ws.feed(&masked(true, OP_TEXT, br#"{"type": "init_user", "username": "ana"}"#));
+        assert_eq!(json(ws.decode(&mut replies).unwrap()), [Message::init_user("ana").to_json()]);
+        assert_eq!(replies.len(), 2);
+        assert!(replies.iter().all(|r| text(r).contains("invalid_message")));
+    }
+}
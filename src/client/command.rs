@@ -0,0 +1,240 @@
+//! Makes sense of a line typed into the client. Commands the client knows
+//! are checked here, and answered with their usage when the arguments
+//! don't fit; anything else after a slash goes to the server as is.
+//!
+//! Arguments are separated by spaces, and may be quoted ("like this" or
+//! 'like this') to hold spaces, with `\` escaping the next character. The
+//! text of a message is taken as typed.
+
+use chat_np1::chatclient::{ChatConnection, RequestError};
+use chat_np1::message::MAX_FIELD;
+
+/// The client's own commands, with their usage; the last argument takes
+/// the rest of the line when it ends in `...`.
+const COMMANDS: &[(&str, &str)] = &[
+    ("quit", ""),
+    ("join", "<chat>"),
+    ("new", "<chat>"),
+    ("leave", ""),
+    ("kick", "<user>"),
+    ("list", ""),
+    ("online", ""),
+    ("msg", "<user> <text...>"),
+];
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Input {
+    /// Text for the current chat.
+    Say(String),
+    Private { to: String, text: String },
+    Quit,
+    Join(String),
+    New(String),
+    Leave,
+    Kick(String),
+    List,
+    Online,
+    /// Any other command, without the slash, for the server to run.
+    Server(String),
+}
+
+impl Input {
+    /// Sends what the input asks for. Quitting is up to the caller.
+    pub fn send(self, connection: &mut ChatConnection) -> Result<(), RequestError> {
+        match self {
+            Input::Say(text) => connection.send_public_message(text)?,
+            Input::Private { to, text } => connection.send_private(to, text)?,
+            Input::Quit => (),
+            Input::Join(chat) => connection.join_chat(chat)?,
+            Input::New(chat) => connection.create_chat(chat)?,
+            Input::Leave => connection.leave_chat()?,
+            Input::Kick(user) => connection.kick(user)?,
+            Input::List => connection.request_groups()?,
+            Input::Online => connection.request_clients()?,
+            Input::Server(line) => connection.send_command(line)?,
+        }
+        Ok(())
+    }
+}
+
+/// Parses `line`, already trimmed, or says what's wrong with it.
+pub fn parse(line: &str) -> Result<Input, String> {
+    if let Some(rest) = line.strip_prefix('@') {
+        let mut words = Words::new(rest);
+        let to = words.next()?.unwrap_or_default();
+        let text = words.take_rest();
+        if to.is_empty() || text.is_empty() {
+            return Err("Usage: @<user> <text...>".to_owned());
+        }
+        return Ok(Input::Private { to: field(to)?, text: field(text.to_owned())? });
+    }
+    let command = match line.strip_prefix('/') {
+        Some(command) => command,
+        None => return Ok(Input::Say(field(line.to_owned())?)),
+    };
+    let (name, rest) = command.split_once(char::is_whitespace).unwrap_or((command, ""));
+    let name = name.to_lowercase();
+    let usage = match COMMANDS.iter().find(|&&(n, _)| n == name) {
+        Some(&(_, usage)) => usage,
+        None => return Ok(Input::Server(field(command.to_owned())?)),
+    };
+    let mut args = arguments(&name, usage, rest)?.into_iter();
+    let mut arg = || args.next().unwrap_or_default();
+    Ok(match name.as_str() {
+        "quit" => Input::Quit,
+        "join" => Input::Join(arg()),
+        "new" => Input::New(arg()),
+        "leave" => Input::Leave,
+        "kick" => Input::Kick(arg()),
+        "list" => Input::List,
+        "online" => Input::Online,
+        "msg" => Input::Private { to: arg(), text: arg() },
+        _ => unreachable!(),
+    })
+}
+
+/// Splits `rest` as the usage of command `name` says, or tells how to
+/// call it.
+fn arguments(name: &str, usage: &str, rest: &str) -> Result<Vec<String>, String> {
+    let synopsis = || match usage.is_empty() {
+        true => format!("Usage: /{}", name),
+        false => format!("Usage: /{} {}", name, usage),
+    };
+    let params: Vec<&str> = usage.split_whitespace().collect();
+    let mut words = Words::new(rest);
+    let mut args = Vec::new();
+    for (i, param) in params.iter().enumerate() {
+        let arg = match i + 1 == params.len() && param.ends_with("...>") {
+            true => Some(words.take_rest().to_owned()),
+            false => words.next()?,
+        };
+        match arg {
+            Some(arg) if !arg.is_empty() => args.push(field(arg)?),
+            _ => return Err(synopsis()),
+        }
+    }
+    match words.next()? {
+        Some(_) => Err(synopsis()),
+        None => Ok(args),
+    }
+}
+
+/// Refuses what wouldn't fit in a message.
+fn field(text: String) -> Result<String, String> {
+    match text.len() > MAX_FIELD {
+        true => Err(format!("Too long, at most {} bytes", MAX_FIELD)),
+        false => Ok(text),
+    }
+}
+
+/// The words of a line, one at a time, with quotes and escapes taken out.
+struct Words<'a> {
+    rest: &'a str,
+}
+
+impl<'a> Words<'a> {
+    fn new(text: &'a str) -> Words<'a> {
+        Words { rest: text }
+    }
+
+    /// Everything left, as typed.
+    fn take_rest(&mut self) -> &'a str {
+        let rest = self.rest.trim();
+        self.rest = "";
+        rest
+    }
+
+    fn next(&mut self) -> Result<Option<String>, String> {
+        let text = self.rest.trim_start();
+        if text.is_empty() {
+            self.rest = text;
+            return Ok(None);
+        }
+        let mut word = String::new();
+        let mut quote = None;
+        let mut chars = text.char_indices();
+        let mut end = text.len();
+        while let Some((i, c)) = chars.next() {
+            match (c, quote) {
+                ('\\', _) => match chars.next() {
+                    Some((_, escaped)) => word.push(escaped),
+                    None => return Err("Nothing to escape at the end".to_owned()),
+                },
+                ('"', None) | ('\'', None) => quote = Some(c),
+                (c, Some(q)) if c == q => quote = None,
+                (c, None) if c.is_whitespace() => {
+                    end = i;
+                    break;
+                },
+                (c, _) => word.push(c),
+            }
+        }
+        if quote.is_some() {
+            return Err("Unclosed quote".to_owned());
+        }
+        self.rest = &text[end..];
+        Ok(Some(word))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn private(to: &str, text: &str) -> Input {
+        Input::Private { to: to.to_owned(), text: text.to_owned() }
+    }
+
+    #[test]
+    fn quotes_hold_spaces() {
+        assert_eq!(parse(r#"/join "Big Room""#), Ok(Input::Join("Big Room".to_owned())));
+        assert_eq!(parse("/join 'Big Room'"), Ok(Input::Join("Big Room".to_owned())));
+        assert_eq!(parse(r#"/kick "it's me""#), Ok(Input::Kick("it's me".to_owned())));
+    }
+
+    #[test]
+    fn backslash_escapes() {
+        assert_eq!(parse(r"/join Big\ Room"), Ok(Input::Join("Big Room".to_owned())));
+        assert_eq!(parse(r#"/join \"q\""#), Ok(Input::Join("\"q\"".to_owned())));
+        assert!(parse(r"/join room\").is_err());
+    }
+
+    #[test]
+    fn unclosed_quote() {
+        assert_eq!(parse(r#"/join "Big Room"#), Err("Unclosed quote".to_owned()));
+    }
+
+    #[test]
+    fn wrong_argument_counts() {
+        assert_eq!(parse("/join"), Err("Usage: /join <chat>".to_owned()));
+        assert_eq!(parse("/join a b"), Err("Usage: /join <chat>".to_owned()));
+        assert_eq!(parse("/leave now"), Err("Usage: /leave".to_owned()));
+        assert_eq!(parse("/msg bob"), Err("Usage: /msg <user> <text...>".to_owned()));
+        assert_eq!(parse("@bob"), Err("Usage: @<user> <text...>".to_owned()));
+    }
+
+    #[test]
+    fn messages_are_taken_as_typed() {
+        assert_eq!(parse(r#"/msg bob say "hi" \o/"#), Ok(private("bob", r#"say "hi" \o/"#)));
+        assert_eq!(parse("@bob  hello there"), Ok(private("bob", "hello there")));
+        assert_eq!(parse("it's \"fine\""), Ok(Input::Say("it's \"fine\"".to_owned())));
+    }
+
+    #[test]
+    fn other_commands_go_to_the_server() {
+        assert_eq!(parse("/topic a b"), Ok(Input::Server("topic a b".to_owned())));
+        assert_eq!(parse("/QUIT"), Ok(Input::Quit));
+    }
+
+    #[test]
+    fn overlong_fields() {
+        let long = "x".repeat(MAX_FIELD + 1);
+        let too_long = Err(format!("Too long, at most {} bytes", MAX_FIELD));
+        assert_eq!(parse(&long), too_long);
+        assert_eq!(parse(&format!("/join {}", long)), too_long);
+        assert_eq!(parse(&format!("@bob {}", long)), too_long);
+        assert_eq!(parse(&format!("/msg bob {}", long)), too_long);
+        assert_eq!(parse(&format!("/topic {}", long)), too_long);
+        assert_eq!(parse(&"x".repeat(MAX_FIELD)), Ok(Input::Say("x".repeat(MAX_FIELD))));
+    }
+}
@@ -0,0 +1,128 @@
+//! Tab completion, for both the line and the full-screen client: slash
+//! commands, chats from the last listing, and the users in the current
+//! chat (also as `@user` private message targets).
+
+use chat_np1::chatclient::{ChatConnection, ClientEvent};
+use notice;
+
+/// Known before the server is asked, so that completion works right away;
+/// whatever else the server has turns up in `/help`.
+const COMMANDS: &[&str] = &["help", "list", "online", "join", "new", "leave", "msg", "kick",
+                            "topic", "quit"];
+
+/// Names worth completing, picked up from what the server says.
+pub struct Names {
+    commands: Vec<String>,
+    rooms: Vec<String>,
+    members: Vec<String>,
+    /// Who private messages have come from, for `@user` outside the chat.
+    contacts: Vec<String>,
+    /// Member lists asked for behind the user's back, whose answers
+    /// aren't shown.
+    quiet: usize,
+}
+
+impl Names {
+    pub fn new() -> Names {
+        Names {
+            commands: COMMANDS.iter().map(|&c| c.to_owned()).collect(),
+            rooms: Vec::new(),
+            members: Vec::new(),
+            contacts: Vec::new(),
+            quiet: 0,
+        }
+    }
+
+    /// The users known to be in the current chat.
+    pub fn members(&self) -> &[String] {
+        &self.members
+    }
+
+    /// Asks for the current chat's members, without showing the answer.
+    pub fn refresh_members(&mut self, connection: &mut ChatConnection) {
+        if !connection.chat_name.is_empty() && connection.request_clients().is_ok() {
+            self.quiet += 1;
+        }
+    }
+
+    /// Picks names out of `event`, which came while in `chat`. Returns
+    /// whether it was only the answer to `refresh_members`, not to be
+    /// shown.
+    pub fn learn(&mut self, event: &ClientEvent, chat: &str) -> bool {
+        match *event {
+            ClientEvent::Notice(ref text) => {
+                if let Some(command) = notice::command(text) {
+                    add(&mut self.commands, command);
+                } else if let Some(rooms) = notice::rooms(text) {
+                    self.rooms = rooms;
+                } else if let Some(members) = notice::members(text) {
+                    self.members = members;
+                    if self.quiet > 0 {
+                        self.quiet -= 1;
+                        return true;
+                    }
+                }
+            },
+            ClientEvent::Message { ref room, ref user, .. } if room == chat =>
+                add(&mut self.members, user),
+            ClientEvent::Private { ref from, .. } => add(&mut self.contacts, from),
+            ClientEvent::Joined(ref room) => {
+                add(&mut self.rooms, room);
+                self.members.clear();
+            },
+            ClientEvent::Left(_) | ClientEvent::Kicked(_) if chat.is_empty() =>
+                self.members.clear(),
+            _ => (),
+        }
+        false
+    }
+
+    /// Where the word before byte `pos` of `line` starts, and what it could
+    /// be completed to.
+    pub fn complete(&self, line: &str, pos: usize) -> (usize, Vec<String>) {
+        let before = &line[..pos];
+        let start = before.rfind(' ').map_or(0, |i| i + 1);
+        let word = &before[start..];
+        let earlier: Vec<&str> = before[..start].split_whitespace().collect();
+        let names: Vec<String> = match (earlier.first().cloned(), earlier.len()) {
+            (None, _) if word.starts_with('/') =>
+                self.commands.iter().map(|c| format!("/{}", c)).collect(),
+            (None, _) if word.starts_with('@') => self.members.iter()
+                .chain(self.contacts.iter())
+                .map(|u| format!("@{}", u))
+                .collect(),
+            (Some("/join"), 1) => self.rooms.clone(),
+            (Some("/help"), 1) => self.commands.clone(),
+            (Some("/new"), _) | (Some("/join"), _) | (Some("/help"), _) => Vec::new(),
+            _ => self.members.clone(),
+        };
+        let mut matches: Vec<String> = names.into_iter().filter(|n| n.starts_with(word)).collect();
+        matches.sort();
+        matches.dedup();
+        (start, matches)
+    }
+}
+
+fn add(names: &mut Vec<String>, name: &str) {
+    if !names.iter().any(|n| n == name) {
+        names.push(name.to_owned());
+    }
+}
+
+/// The longest start all of `names` share.
+pub fn common_prefix(names: &[String]) -> &str {
+    let first = match names.first() {
+        Some(f) => f.as_str(),
+        None => return "",
+    };
+    let mut len = first.len();
+    for name in &names[1..] {
+        len = first.char_indices()
+            .zip(name.chars())
+            .take_while(|&((_, a), b)| a == b)
+            .last()
+            .map_or(0, |((i, a), _)| i + a.len_utf8())
+            .min(len);
+    }
+    &first[..len]
+}
@@ -0,0 +1,59 @@
+//! What was typed, kept across runs in a plain file with a line each.
+
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+
+/// Lines kept, in memory and in the file.
+const LIMIT: usize = 500;
+
+/// `~/.chat_np1_history`, when there is a home.
+pub fn default_path() -> Option<PathBuf> {
+    env::var_os("HOME").map(|home| PathBuf::from(home).join(".chat_np1_history"))
+}
+
+pub struct History {
+    path: Option<PathBuf>,
+    lines: Vec<String>,
+}
+
+impl History {
+    /// Reads what is in the file at `path`, if anything; `None` keeps the
+    /// history for this run only.
+    pub fn load(path: Option<PathBuf>) -> History {
+        let text = path.as_ref().and_then(|p| fs::read_to_string(p).ok()).unwrap_or_default();
+        let mut lines: Vec<String> = text.lines()
+            .filter(|l| !l.is_empty())
+            .map(str::to_owned)
+            .collect();
+        if lines.len() > LIMIT {
+            lines.drain(..lines.len() - LIMIT);
+            if let Some(ref path) = path {
+                let _ = fs::write(path, lines.join("\n") + "\n");
+            }
+        }
+        History { path, lines }
+    }
+
+    pub fn lines(&self) -> &[String] {
+        &self.lines
+    }
+
+    /// Remembers `line`, unless it's the same as the one before. Failing
+    /// to write it down is not worth bothering the user about.
+    pub fn add(&mut self, line: &str) {
+        if line.is_empty() || self.lines.last().map(|l| l.as_str()) == Some(line) {
+            return;
+        }
+        if self.lines.len() == LIMIT {
+            self.lines.remove(0);
+        }
+        self.lines.push(line.to_owned());
+        if let Some(ref path) = self.path {
+            if let Ok(mut file) = OpenOptions::new().create(true).append(true).open(path) {
+                let _ = writeln!(file, "{}", line);
+            }
+        }
+    }
+}
@@ -1,120 +1,159 @@
 extern crate chat_np1;
+extern crate ratatui;
+extern crate rustyline;
+
+mod command;
+mod complete;
+mod history;
+mod notice;
+mod tui;
 
 use std::sync::mpsc;
-use std::sync::{Arc};
+use std::sync::{Arc, Mutex};
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::io::{stdin};
+use std::path::PathBuf;
 use std::thread;
-use chat_np1::chatclient::ChatConnection;
-use chat_np1::message::Message;
-
-fn input_loop(sender: mpsc::Sender<String>,
-              terminate: Arc<AtomicBool>) {
-    let mut buf = String::new();
-    'input: loop {
-        let terminated = terminate.load(Ordering::Relaxed);
-        if terminated { break 'input }
-
-        buf.clear();
-        stdin().read_line(&mut buf).unwrap();
-        sender.send(buf.trim().to_owned()).unwrap();
+use chat_np1::chatclient::{ChatConnection, ChatLog, ClientEvent, LogFormat};
+use chat_np1::tls::{ClientTls, ServerTrust};
+use chat_np1::transport::UnixConnector;
+use rustyline::{CompletionType, Config, Context, Editor, Helper};
+use rustyline::completion::{Completer, Pair};
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::history::DefaultHistory;
+use rustyline::validate::Validator;
+use command::Input;
+use complete::Names;
+use history::History;
+
+/// Everything the main loop waits on, so it can block on a single channel.
+enum Event {
+    Input(String),
+    /// A key or resize, in `--tui` mode.
+    Terminal(ratatui::crossterm::event::Event),
+    Server(ClientEvent),
+    Shutdown,
+}
+
+impl From<ClientEvent> for Event {
+    fn from(e: ClientEvent) -> Event {
+        Event::Server(e)
     }
 }
 
-fn handle_input(input: String,
-                connection: &mut ChatConnection,
-                terminate: Arc<AtomicBool>) {
-    if input.starts_with('/') {
-        handle_command(input, connection, terminate);
-    } else if input.starts_with('@') {
-        connection.send_private_message(input);
-    } else {
-        connection.send_public_message(input);
-    };
+/// Tab completion for the line editor, from the names the main loop
+/// picks up.
+struct Completion(Arc<Mutex<Names>>);
+
+impl Completer for Completion {
+    type Candidate = Pair;
+
+    fn complete(&self, line: &str, pos: usize, _: &Context)
+                -> rustyline::Result<(usize, Vec<Pair>)> {
+        let (start, names) = self.0.lock().unwrap().complete(line, pos);
+        let choices = names.into_iter()
+            .map(|name| Pair { replacement: format!("{} ", name), display: name })
+            .collect();
+        Ok((start, choices))
+    }
 }
 
-fn handle_command(input: String,
-                  connection: &mut ChatConnection,
-                  terminate: Arc<AtomicBool>) {
-    let split_str: Vec<&str> = input.split(' ').collect();
-    match split_str[0] {
-        "/help" => {
-            print_help();
-        },
-        "/list" => {
-            connection.request_groups();
-        },
-        "/join" =>{ 
-            if split_str[1..].len() != 1 {
-                println!("requires single argument");
-                return;
-            }
-            connection.join_chat(split_str[1].to_owned());
-        },
-        "/new" => {
-            if split_str[1..].len() != 1 {
-                println!("requires single argument");
-                return;
-            }
-            connection.create_chat(split_str[1].to_owned())
-        },
-        "/leave" => {
-            connection.leave_chat();
-        },
-        "/online" => {
-            connection.request_clients();
-        },
-        "/kick" => {
-            if split_str[1..].len() != 1 {
-                println!("requires single argument");
-                return;
-            }
-            connection.kick(split_str[1].to_owned());
+impl Hinter for Completion {
+    type Hint = String;
+}
+
+impl Highlighter for Completion {}
+
+impl Validator for Completion {}
+
+impl Helper for Completion {}
+
+fn input_loop(sender: mpsc::Sender<Event>, names: Arc<Mutex<Names>>, mut history: History) {
+    let config = Config::builder().completion_type(CompletionType::List).build();
+    let mut editor = match Editor::<Completion, DefaultHistory>::with_config(config) {
+        Ok(e) => e,
+        Err(e) => {
+            eprintln!("Can't read input: {}", e);
+            let _ = sender.send(Event::Shutdown);
+            return;
         },
-        "/quit" => {
-            terminate.store(true, Ordering::Relaxed);
+    };
+    editor.set_helper(Some(Completion(names)));
+    for line in history.lines() {
+        let _ = editor.add_history_entry(line.as_str());
+    }
+    // Ctrl-C and Ctrl-D end it, as does stdin closing.
+    while let Ok(line) = editor.readline("") {
+        let line = line.trim().to_owned();
+        history.add(&line);
+        let _ = editor.add_history_entry(line.as_str());
+        if sender.send(Event::Input(line)).is_err() {
+            return;
         }
-        _ => {
-            println!("-->ERROR: no such command");
-        },
+    }
+    let _ = sender.send(Event::Shutdown);
+}
+
+fn handle_input(input: String,
+                connection: &mut ChatConnection,
+                terminate: Arc<AtomicBool>) {
+    if input.is_empty() {
+        return;
+    }
+    let input = match command::parse(&input) {
+        Ok(input) => input,
+        Err(usage) => return println!("{}", usage),
+    };
+    if input == Input::Quit {
+        return terminate.store(true, Ordering::Relaxed);
+    }
+    if let Err(e) = input.send(connection) {
+        println!("{}", e);
     }
 }
 
-fn handle_server_message(message: Message,
-                         connection: &mut ChatConnection,
-                         terminate: Arc<AtomicBool>) {
-    use chat_np1::message::Message::*;
-    match message {
-        Failure(m) => println!("SERVER ERROR: {}", &m),
-        Joined(c) => connection.chat_name = c,
-        ChatMessage(u,_,m) => println!("{}: {}", &u, &m),
-        PrivateMessage(f,_,m) => println!("Private message from {}: {}", &f, &m),
-        Logout(_,_) => connection.chat_name = "".to_owned(),
-        ConnectionTermination(_) => {
+fn handle_server_event(event: ClientEvent,
+                       connection: &mut ChatConnection,
+                       names: &Mutex<Names>,
+                       terminate: Arc<AtomicBool>) {
+    use chat_np1::chatclient::ClientEvent::*;
+    connection.track(&event);
+    let mut names = names.lock().unwrap();
+    if names.learn(&event, &connection.chat_name) {
+        return;
+    }
+    match event {
+        Notice(m) => println!("Server: {}", &m),
+        Message { user, text, .. } => println!("{}: {}", &user, &text),
+        Private { from, text } => println!("Private message from {}: {}", &from, &text),
+        // Who's there, for completion.
+        Joined(_) => names.refresh_members(connection),
+        Left(_) => (),
+        Kicked(c) => println!("Kicked from {}!", &c),
+        Error { reason, .. } => println!("SERVER ERROR: {}", &reason),
+        Disconnected { reconnecting: false, .. } => {
             terminate.store(true, Ordering::Relaxed);
             println!("Connection with server terminated!!");
-        }
-        
-        m @ _ => eprintln!("{:?}", m),
+        },
+        Disconnected { reason, .. } => eprintln!("{}, reconnecting...", reason),
+        Reconnected => eprintln!("Reconnected to server"),
     }
 }
 
 fn print_help() {
-    println!("List of available commands:
-/help   -- show this message
-/list   -- show available chats
-/join   -- join a chat
-/new    -- create new chat
-/leave  -- leave current chat
-/online -- list of users in this chat
-/kick   -- kick user from chat (when admin)
-/quit   -- quit application");   
+    println!("Type /help for the list of commands, /quit to quit");
+}
+
+fn usage() -> ! {
+    eprintln!("usage: client USERNAME [ADDR | --unix PATH] [--tls-ca FILE | --tls-fingerprint HEX] [--tls-name NAME] [--tui] [--history FILE] [--log DIR [--log-format text|json] [--log-max-size BYTES] [--log-keep N]]");
+    std::process::exit(2);
 }
 
 fn main() {
-    let username = match std::env::args().nth(1) {
+    let mut args = std::env::args().skip(1);
+    let username = match args.next() {
         Some(u) => u,
-        None => {eprintln!("NO USERNAME GIVEN"); panic!("no username")}
+        None => {eprintln!("NO USERNAME GIVEN"); usage()}
     };
 
     if &username == "Server" {
@@ -122,42 +161,117 @@ fn main() {
         panic!("invalid name");
     }
 
-    
-    let (listen_snd, listen_rcv) = mpsc::channel();
-    let (input_snd, input_rcv) = mpsc::channel();
-    
+    let mut addr = "127.0.0.1:8080".to_owned();
+    let mut trust = None;
+    let mut tls_name = None;
+    let mut unix = None;
+    let mut full_screen = false;
+    let mut history = history::default_path();
+    let mut log_dir = None;
+    let mut log_format = LogFormat::Text;
+    let mut log_max_size = None;
+    let mut log_keep = 5;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--tls-ca" => trust = Some(ServerTrust::CaFile(args.next().unwrap_or_else(|| usage()))),
+            "--tls-fingerprint" => {
+                trust = Some(ServerTrust::Fingerprint(args.next().unwrap_or_else(|| usage())))
+            },
+            "--tls-name" => tls_name = Some(args.next().unwrap_or_else(|| usage())),
+            "--unix" => unix = Some(args.next().unwrap_or_else(|| usage())),
+            "--tui" => full_screen = true,
+            "--history" => history = Some(PathBuf::from(args.next().unwrap_or_else(|| usage()))),
+            "--log" => log_dir = Some(args.next().unwrap_or_else(|| usage())),
+            "--log-format" => log_format = match args.next().as_deref() {
+                Some("text") => LogFormat::Text,
+                Some("json") => LogFormat::JsonLines,
+                _ => usage(),
+            },
+            "--log-max-size" => {
+                log_max_size = Some(args.next().and_then(|n| n.parse().ok()).unwrap_or_else(|| usage()))
+            },
+            "--log-keep" => {
+                log_keep = args.next().and_then(|n| n.parse().ok()).unwrap_or_else(|| usage())
+            },
+            a if a.starts_with("--") => usage(),
+            _ => addr = arg,
+        }
+    }
+
+    let (event_snd, event_rcv) = mpsc::channel();
     let terminate = Arc::new(AtomicBool::new(false));
-    let input_terminate = terminate.clone();    
-    
-    let mut connection = ChatConnection::connect(username,
-                                             "127.0.0.1:8080",
-                                             listen_snd,
-                                             terminate.clone());
-    
-    let input_thread = thread::spawn(move || {
-        input_loop(input_snd, input_terminate);
-    });
-    print_help();
 
-    'main: loop {
-        match input_rcv.try_recv() {
-            Ok(input) => {
-                handle_input(input, &mut connection, terminate.clone());
-                    
-            },
-            Err(_) => (),
+    let mut connection = match (unix, trust) {
+        (Some(_), Some(_)) => usage(),
+        (Some(path), None) => ChatConnection::connect_with(username,
+                                                          UnixConnector::new(path),
+                                                          event_snd.clone(),
+                                                          terminate.clone()),
+        (None, Some(trust)) => {
+            // Without a name, check the certificate against the host we dial.
+            let name = tls_name.unwrap_or_else(|| {
+                addr.rsplit_once(':').map_or(&addr[..], |(host, _)| host).to_owned()
+            });
+            let tls = match ClientTls::new(&trust, &name) {
+                Ok(t) => t,
+                Err(e) => {
+                    eprintln!("Invalid TLS settings: {}", e);
+                    std::process::exit(1);
+                },
+            };
+            ChatConnection::connect_tls(username,
+                                        addr.as_str(),
+                                        tls,
+                                        event_snd.clone(),
+                                        terminate.clone())
+        },
+        (None, None) => ChatConnection::connect(username,
+                                                addr.as_str(),
+                                                event_snd.clone(),
+                                                terminate.clone()),
+    };
+
+    if let Some(dir) = log_dir {
+        let mut log = ChatLog::new(dir).format(log_format);
+        if let Some(max) = log_max_size {
+            log = log.rotate(max, log_keep);
+        }
+        if let Err(e) = connection.log_to(log) {
+            eprintln!("Couldn't start the chat log: {}", e);
+            std::process::exit(1);
         }
-        match listen_rcv.try_recv() {
-            Ok(message) => handle_server_message(message,
-                                                 &mut connection,
-                                                 terminate.clone()),
-            Err(_) => (),
+    }
+
+    let history = History::load(history);
+    if full_screen {
+        if let Err(e) = tui::run(connection, event_rcv, event_snd, terminate, history) {
+            eprintln!("Terminal failed: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    // Not joined on exit: it spends its life blocked reading stdin.
+    let names = Arc::new(Mutex::new(Names::new()));
+    let completion = names.clone();
+    thread::spawn(move || input_loop(event_snd, completion, history));
+    print_help();
+
+    for event in event_rcv.iter() {
+        match event {
+            Event::Input(input) => handle_input(input,
+                                                &mut connection,
+                                                terminate.clone()),
+            Event::Server(event) => handle_server_event(event,
+                                                        &mut connection,
+                                                        &names,
+                                                        terminate.clone()),
+            Event::Shutdown => terminate.store(true, Ordering::Relaxed),
+            Event::Terminal(_) => (),
         }
-        let terminated = terminate.load(Ordering::Relaxed);
-        if terminated {
-            drop(connection);
-            break 'main;
+        if terminate.load(Ordering::Relaxed) {
+            break;
         }
     }
-    input_thread.join().unwrap();
+    drop(connection);
 }
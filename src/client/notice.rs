@@ -0,0 +1,31 @@
+//! The server answers `/online` and `/list` with plain notices; these pick
+//! the names back out of them.
+
+/// The users in an `Online:  a, b,` notice.
+pub fn members(notice: &str) -> Option<Vec<String>> {
+    let names = notice.strip_prefix("Online:")?;
+    Some(names.split(',')
+         .map(str::trim)
+         .filter(|n| !n.is_empty())
+         .map(str::to_owned)
+         .collect())
+}
+
+/// The chats in an `A | B | ` listing.
+pub fn rooms(notice: &str) -> Option<Vec<String>> {
+    if !notice.ends_with(" | ") {
+        return None;
+    }
+    Some(notice.split(" | ")
+         .map(str::trim)
+         .filter(|r| !r.is_empty())
+         .map(str::to_owned)
+         .collect())
+}
+
+/// The command named in a line of `/help`'s answer, like
+/// `/kick <user> -- kick a user out of this chat`.
+pub fn command(line: &str) -> Option<&str> {
+    let (synopsis, _) = line.strip_prefix('/')?.split_once(" -- ")?;
+    synopsis.split_whitespace().next()
+}
@@ -0,0 +1,571 @@
+//! The full-screen client, for `--tui`: conversations on the left, the
+//! selected one's scrollback in the middle and the members of the current
+//! chat on the right, with the input line and a status bar below.
+//!
+//! Ctrl-N and Ctrl-P move between conversations, PageUp and PageDown
+//! scroll, Up and Down go through what was typed before and Tab completes
+//! commands and names. `@user text` sends a private message from anywhere;
+//! plain text goes wherever the selected conversation is.
+
+use std::collections::VecDeque;
+use std::io;
+use std::mem;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use chat_np1::chatclient::{ChatConnection, ClientEvent};
+use ratatui::{DefaultTerminal, Frame};
+use ratatui::crossterm::event::{self, Event as TermEvent, KeyCode, KeyEvent, KeyEventKind,
+                                KeyModifiers};
+use ratatui::layout::{Constraint, Layout, Position};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, List, ListItem, Paragraph};
+use command::{self, Input as Typed};
+use complete::{self, Names};
+use history::History;
+use Event;
+
+/// How often the screen is looked at when nothing happens, for the timers.
+const TICK: Duration = Duration::from_secs(1);
+/// How often the current chat's member list is asked for again.
+const MEMBERS_REFRESH: Duration = Duration::from_secs(15);
+/// Lines kept per conversation.
+const SCROLLBACK: usize = 1000;
+/// Lines moved by PageUp and PageDown.
+const PAGE: usize = 10;
+const SIDE_WIDTH: u16 = 20;
+
+fn notice_style() -> Style {
+    Style::default().fg(Color::Yellow)
+}
+
+fn error_style() -> Style {
+    Style::default().fg(Color::Red)
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Target {
+    /// Where the client's own notes go before there is anything else.
+    Server,
+    Room(String),
+    Direct(String),
+}
+
+impl Target {
+    fn title(&self) -> String {
+        match *self {
+            Target::Server => "server".to_owned(),
+            Target::Room(ref room) => format!("#{}", room),
+            Target::Direct(ref user) => format!("@{}", user),
+        }
+    }
+}
+
+struct Entry {
+    /// Who said it; empty for notices.
+    from: String,
+    text: String,
+    style: Style,
+}
+
+struct Conversation {
+    target: Target,
+    entries: VecDeque<Entry>,
+    unread: usize,
+}
+
+impl Conversation {
+    fn new(target: Target) -> Conversation {
+        Conversation { target, entries: VecDeque::new(), unread: 0 }
+    }
+
+    /// Every entry, wrapped to `width`, with the speaker's name in bold.
+    fn render(&self, width: usize) -> Vec<Line<'static>> {
+        let mut lines = Vec::new();
+        for entry in &self.entries {
+            let (text, name) = match entry.from.is_empty() {
+                true => (entry.text.clone(), 0),
+                false => (format!("{}: {}", entry.from, entry.text), entry.from.chars().count() + 1),
+            };
+            for (n, chunk) in wrap(&text, width.max(1)).into_iter().enumerate() {
+                if n > 0 || name == 0 {
+                    lines.push(Line::styled(chunk, entry.style));
+                    continue;
+                }
+                let split = chunk.char_indices().nth(name).map_or(chunk.len(), |(i, _)| i);
+                let (who, said) = chunk.split_at(split);
+                lines.push(Line::from(vec![
+                    Span::styled(who.to_owned(), entry.style.add_modifier(Modifier::BOLD)),
+                    Span::styled(said.to_owned(), entry.style),
+                ]));
+            }
+        }
+        lines
+    }
+}
+
+/// Cuts `text` into lines of at most `width` characters, at spaces where
+/// it can.
+fn wrap(text: &str, width: usize) -> Vec<String> {
+    let mut lines = Vec::new();
+    for paragraph in text.split('\n') {
+        let mut line = String::new();
+        let mut len = 0;
+        for word in paragraph.split(' ') {
+            let mut word: Vec<char> = word.chars().collect();
+            if len > 0 && len + 1 + word.len() > width {
+                lines.push(mem::take(&mut line));
+                len = 0;
+            }
+            if len > 0 {
+                line.push(' ');
+                len += 1;
+            }
+            while len + word.len() > width {
+                line.extend(word.drain(..width - len));
+                lines.push(mem::take(&mut line));
+                len = 0;
+            }
+            len += word.len();
+            line.extend(word);
+        }
+        lines.push(line);
+    }
+    lines
+}
+
+enum Status {
+    Connected,
+    Reconnecting,
+    Disconnected,
+}
+
+/// The input line, with a cursor and Up and Down through earlier lines.
+struct Input {
+    chars: Vec<char>,
+    cursor: usize,
+    history: History,
+    /// Where Up and Down have got to in `history`, and what was being
+    /// typed before they were pressed.
+    browsing: Option<(usize, Vec<char>)>,
+}
+
+impl Input {
+    fn new(history: History) -> Input {
+        Input { chars: Vec::new(), cursor: 0, history, browsing: None }
+    }
+
+    /// Applies `key`, returning the line once it's entered.
+    fn key(&mut self, key: KeyEvent) -> Option<String> {
+        let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+        match key.code {
+            KeyCode::Enter => return self.take(),
+            KeyCode::Char('a') if ctrl => self.cursor = 0,
+            KeyCode::Char('e') if ctrl => self.cursor = self.chars.len(),
+            KeyCode::Char('u') if ctrl => {
+                self.chars.drain(..self.cursor);
+                self.cursor = 0;
+            },
+            KeyCode::Char('k') if ctrl => self.chars.truncate(self.cursor),
+            KeyCode::Char('w') if ctrl => {
+                let mut start = self.cursor;
+                while start > 0 && self.chars[start - 1] == ' ' {
+                    start -= 1;
+                }
+                while start > 0 && self.chars[start - 1] != ' ' {
+                    start -= 1;
+                }
+                self.chars.drain(start..self.cursor);
+                self.cursor = start;
+            },
+            KeyCode::Char(_) if ctrl => (),
+            KeyCode::Char(c) => {
+                self.chars.insert(self.cursor, c);
+                self.cursor += 1;
+            },
+            KeyCode::Backspace if self.cursor > 0 => {
+                self.cursor -= 1;
+                self.chars.remove(self.cursor);
+            },
+            KeyCode::Delete if self.cursor < self.chars.len() => {
+                self.chars.remove(self.cursor);
+            },
+            KeyCode::Left => self.cursor = self.cursor.saturating_sub(1),
+            KeyCode::Right => self.cursor = (self.cursor + 1).min(self.chars.len()),
+            KeyCode::Home => self.cursor = 0,
+            KeyCode::End => self.cursor = self.chars.len(),
+            KeyCode::Up => self.browse(true),
+            KeyCode::Down => self.browse(false),
+            _ => (),
+        }
+        None
+    }
+
+    fn take(&mut self) -> Option<String> {
+        self.browsing = None;
+        self.cursor = 0;
+        let line: String = self.chars.drain(..).collect();
+        let line = line.trim().to_owned();
+        if line.is_empty() {
+            return None;
+        }
+        self.history.add(&line);
+        Some(line)
+    }
+
+    /// Shows the line before (or after) the one shown, and what was being
+    /// typed once past the newest.
+    fn browse(&mut self, back: bool) {
+        let lines = self.history.lines();
+        let next = match (self.browsing.as_ref().map(|b| b.0), back) {
+            (None, true) => lines.len().checked_sub(1),
+            (None, false) => return,
+            (Some(i), true) => Some(i.saturating_sub(1)),
+            (Some(i), false) => Some(i + 1),
+        };
+        let draft = match self.browsing.take() {
+            Some((_, draft)) => draft,
+            None => self.chars.clone(),
+        };
+        match next {
+            Some(i) if i < lines.len() => {
+                self.chars = lines[i].chars().collect();
+                self.browsing = Some((i, draft));
+            },
+            _ => self.chars = draft,
+        }
+        self.cursor = self.chars.len();
+    }
+
+    /// Completes the word before the cursor as far as it can, returning
+    /// the choices when that's not far at all.
+    fn complete(&mut self, names: &Names) -> Option<String> {
+        let line: String = self.chars.iter().collect();
+        let pos = self.chars[..self.cursor].iter().map(|c| c.len_utf8()).sum();
+        let (start, choices) = names.complete(&line, pos);
+        let insert = match choices.len() {
+            0 => return None,
+            1 => format!("{} ", choices[0]),
+            _ => complete::common_prefix(&choices).to_owned(),
+        };
+        let start = line[..start].chars().count();
+        let insert: Vec<char> = insert.chars().collect();
+        if insert.len() <= self.cursor - start {
+            return Some(choices.join("  "));
+        }
+        let end = start + insert.len();
+        self.chars.splice(start..self.cursor, insert);
+        self.cursor = end;
+        None
+    }
+}
+
+struct App {
+    conversations: Vec<Conversation>,
+    selected: usize,
+    /// How many lines up from the bottom the selected conversation is
+    /// scrolled.
+    scroll: usize,
+    names: Names,
+    refreshed: Instant,
+    status: Status,
+    input: Input,
+}
+
+impl App {
+    fn new(history: History) -> App {
+        App {
+            conversations: vec![Conversation::new(Target::Server)],
+            selected: 0,
+            scroll: 0,
+            names: Names::new(),
+            refreshed: Instant::now(),
+            status: Status::Connected,
+            input: Input::new(history),
+        }
+    }
+
+    fn run(&mut self,
+           terminal: &mut DefaultTerminal,
+           connection: &mut ChatConnection,
+           events: mpsc::Receiver<Event>,
+           terminate: &AtomicBool) -> io::Result<()> {
+        while !terminate.load(Ordering::Relaxed) {
+            terminal.draw(|frame| self.draw(frame, connection))?;
+            let first = match events.recv_timeout(TICK) {
+                Ok(event) => Some(event),
+                Err(RecvTimeoutError::Timeout) => None,
+                Err(RecvTimeoutError::Disconnected) => break,
+            };
+            // Whatever else is already waiting goes in the same redraw.
+            for event in first.into_iter().chain(events.try_iter()) {
+                match event {
+                    Event::Terminal(TermEvent::Key(key)) if key.kind == KeyEventKind::Press =>
+                        self.key(key, connection, terminate),
+                    Event::Server(event) => self.event(event, connection, terminate),
+                    Event::Shutdown => terminate.store(true, Ordering::Relaxed),
+                    Event::Terminal(_) | Event::Input(_) => (),
+                }
+            }
+            if self.refreshed.elapsed() >= MEMBERS_REFRESH {
+                self.refresh_members(connection);
+            }
+        }
+        Ok(())
+    }
+
+    /// The conversation for `target`, opened if there isn't one yet.
+    fn conversation(&mut self, target: Target) -> usize {
+        match self.conversations.iter().position(|c| c.target == target) {
+            Some(i) => i,
+            None => {
+                self.conversations.push(Conversation::new(target));
+                self.conversations.len() - 1
+            },
+        }
+    }
+
+    /// Adds a line to conversation `i`. Unless it's the one on screen,
+    /// `unread` lines count towards its marker.
+    fn push(&mut self, i: usize, from: &str, text: &str, style: Style, unread: bool) {
+        let on_screen = i == self.selected;
+        let conversation = &mut self.conversations[i];
+        if conversation.entries.len() == SCROLLBACK {
+            conversation.entries.pop_front();
+        }
+        conversation.entries.push_back(Entry {
+            from: from.to_owned(),
+            text: text.to_owned(),
+            style,
+        });
+        if unread && !on_screen {
+            conversation.unread += 1;
+        }
+    }
+
+    /// Adds a line to the conversation on screen.
+    fn note(&mut self, text: &str, style: Style) {
+        let i = self.selected;
+        self.push(i, "", text, style, false);
+    }
+
+    fn select(&mut self, i: usize) {
+        self.selected = i;
+        self.scroll = 0;
+        self.conversations[i].unread = 0;
+    }
+
+    fn key(&mut self, key: KeyEvent, connection: &mut ChatConnection, terminate: &AtomicBool) {
+        let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+        let count = self.conversations.len();
+        match key.code {
+            KeyCode::Char('c') if ctrl => terminate.store(true, Ordering::Relaxed),
+            KeyCode::Char('n') if ctrl => self.select((self.selected + 1) % count),
+            KeyCode::Char('p') if ctrl => self.select((self.selected + count - 1) % count),
+            KeyCode::PageUp => self.scroll += PAGE,
+            KeyCode::PageDown => self.scroll = self.scroll.saturating_sub(PAGE),
+            KeyCode::Tab => if let Some(choices) = self.input.complete(&self.names) {
+                self.note(&choices, notice_style());
+            },
+            _ => if let Some(line) = self.input.key(key) {
+                self.submit(line, connection, terminate);
+            },
+        }
+    }
+
+    fn submit(&mut self, line: String, connection: &mut ChatConnection, terminate: &AtomicBool) {
+        if let Some(to) = line.strip_prefix('@').filter(|to| !to.is_empty() && !to.contains(' ')) {
+            // Just `@user`: open the conversation with them.
+            let i = self.conversation(Target::Direct(to.to_owned()));
+            return self.select(i);
+        }
+        let input = match command::parse(&line) {
+            Ok(input) => input,
+            Err(usage) => return self.note(&usage, error_style()),
+        };
+        let (target, text) = match input {
+            Typed::Quit => return terminate.store(true, Ordering::Relaxed),
+            Typed::Say(text) => (self.conversations[self.selected].target.clone(), text),
+            Typed::Private { to, text } => (Target::Direct(to), text),
+            input => {
+                if let Err(e) = input.send(connection) {
+                    self.note(&e.to_string(), error_style());
+                }
+                return;
+            },
+        };
+        let sent = match target {
+            Target::Direct(ref to) => connection.send_private(to.clone(), text.clone()),
+            Target::Room(ref room) if *room != connection.chat_name => {
+                let text = format!("Not in {}, /join {} to talk there", room, room);
+                return self.note(&text, error_style());
+            },
+            Target::Room(_) | Target::Server => connection.send_public_message(text.clone()),
+        };
+        if let Err(e) = sent {
+            return self.note(&e.to_string(), error_style());
+        }
+        let target = match target {
+            Target::Server => Target::Room(connection.chat_name.clone()),
+            target => target,
+        };
+        let i = self.conversation(target);
+        self.push(i, connection.username(), &text, Style::default(), false);
+    }
+
+    fn event(&mut self, event: ClientEvent, connection: &mut ChatConnection,
+             terminate: &AtomicBool) {
+        connection.track(&event);
+        if self.names.learn(&event, &connection.chat_name) {
+            return;
+        }
+        match event {
+            ClientEvent::Notice(text) => self.note(&text, notice_style()),
+            ClientEvent::Message { room, user, text } => {
+                let i = self.conversation(Target::Room(room));
+                self.push(i, &user, &text, Style::default(), true);
+            },
+            ClientEvent::Private { from, text } => {
+                let i = self.conversation(Target::Direct(from.clone()));
+                self.push(i, &from, &text, Style::default(), true);
+            },
+            ClientEvent::Joined(room) => {
+                let i = self.conversation(Target::Room(room.clone()));
+                self.select(i);
+                self.push(i, "", &format!("Joined {}", room), notice_style(), false);
+                self.refresh_members(connection);
+            },
+            ClientEvent::Left(room) => {
+                let i = self.conversation(Target::Room(room.clone()));
+                self.push(i, "", &format!("Left {}", room), notice_style(), false);
+            },
+            ClientEvent::Kicked(room) => {
+                let i = self.conversation(Target::Room(room.clone()));
+                self.push(i, "", &format!("Kicked from {}!", room), error_style(), true);
+            },
+            ClientEvent::Error { reason, .. } => self.note(&reason, error_style()),
+            ClientEvent::Disconnected { reconnecting: false, .. } => {
+                self.status = Status::Disconnected;
+                terminate.store(true, Ordering::Relaxed);
+            },
+            ClientEvent::Disconnected { reason, .. } => {
+                self.status = Status::Reconnecting;
+                self.push(0, "", &format!("{}, reconnecting...", reason), error_style(), true);
+            },
+            ClientEvent::Reconnected => {
+                self.status = Status::Connected;
+                self.push(0, "", "Reconnected to server", notice_style(), true);
+                self.refresh_members(connection);
+            },
+        }
+    }
+
+    /// Asks for the current chat's members, without showing the answer.
+    fn refresh_members(&mut self, connection: &mut ChatConnection) {
+        self.refreshed = Instant::now();
+        if let Status::Connected = self.status {
+            self.names.refresh_members(connection);
+        }
+    }
+
+    fn draw(&mut self, frame: &mut Frame, connection: &ChatConnection) {
+        let [main, input, status] = Layout::vertical([Constraint::Min(3),
+                                                      Constraint::Length(3),
+                                                      Constraint::Length(1)])
+            .areas(frame.area());
+        let [side, body, members] = Layout::horizontal([Constraint::Length(SIDE_WIDTH),
+                                                        Constraint::Min(10),
+                                                        Constraint::Length(SIDE_WIDTH)])
+            .areas(main);
+
+        let current = Target::Room(connection.chat_name.clone());
+        let chats: Vec<ListItem> = self.conversations.iter().enumerate().map(|(i, c)| {
+            let mut label = c.target.title();
+            let mut style = Style::default();
+            if c.target == current {
+                style = style.fg(Color::Green);
+            }
+            if c.unread > 0 {
+                label = format!("{} ({})", label, c.unread);
+                style = style.add_modifier(Modifier::BOLD);
+            }
+            if i == self.selected {
+                style = style.add_modifier(Modifier::REVERSED);
+            }
+            ListItem::new(label).style(style)
+        }).collect();
+        frame.render_widget(List::new(chats).block(Block::bordered().title("Chats")), side);
+
+        let conversation = &self.conversations[self.selected];
+        let block = Block::bordered().title(conversation.target.title());
+        let inner = block.inner(body);
+        let lines = conversation.render(inner.width as usize);
+        let height = inner.height as usize;
+        self.scroll = self.scroll.min(lines.len().saturating_sub(height));
+        let end = lines.len() - self.scroll;
+        let shown: Vec<Line> = lines.into_iter().take(end).skip(end.saturating_sub(height)).collect();
+        frame.render_widget(Paragraph::new(shown).block(block), body);
+
+        let names: Vec<ListItem> = self.names.members().iter()
+            .map(|m| ListItem::new(m.as_str()))
+            .collect();
+        frame.render_widget(List::new(names).block(Block::bordered().title("Members")), members);
+
+        // Scrolled sideways so the cursor stays in sight.
+        let width = input.width.saturating_sub(2).max(1) as usize;
+        let offset = (self.input.cursor + 1).saturating_sub(width);
+        let typed: String = self.input.chars[offset..].iter().collect();
+        frame.render_widget(Paragraph::new(typed).block(Block::bordered()), input);
+        frame.set_cursor_position(Position::new(input.x + 1 + (self.input.cursor - offset) as u16,
+                                                input.y + 1));
+
+        let (state, color) = match self.status {
+            Status::Connected => ("connected", Color::Green),
+            Status::Reconnecting => ("reconnecting", Color::Yellow),
+            Status::Disconnected => ("disconnected", Color::Red),
+        };
+        let room = match connection.chat_name.is_empty() {
+            true => "no chat".to_owned(),
+            false => format!("#{}", connection.chat_name),
+        };
+        let bar = Line::from(vec![
+            Span::styled(format!(" {} ", state), Style::default().fg(Color::Black).bg(color)),
+            Span::raw(format!(" {} in {} ", connection.username(), room)),
+            Span::styled("| Ctrl-N/Ctrl-P switch, PgUp/PgDn scroll, /quit quits",
+                         Style::default().fg(Color::DarkGray)),
+        ]);
+        frame.render_widget(Paragraph::new(bar), status);
+    }
+}
+
+/// Runs the client full screen until the user quits or the server ends
+/// the connection. `sender` is where the terminal's keys go.
+pub fn run(mut connection: ChatConnection,
+           events: mpsc::Receiver<Event>,
+           sender: mpsc::Sender<Event>,
+           terminate: Arc<AtomicBool>,
+           history: History) -> io::Result<()> {
+    // Not joined on exit: it spends its life blocked reading the terminal.
+    thread::spawn(move || read_terminal(sender));
+    let mut terminal = ratatui::init();
+    let mut app = App::new(history);
+    app.note("Type /help for the list of commands, /quit to quit", notice_style());
+    let result = app.run(&mut terminal, &mut connection, events, &terminate);
+    ratatui::restore();
+    if let Status::Disconnected = app.status {
+        println!("Connection with server terminated!!");
+    }
+    result
+}
+
+fn read_terminal(sender: mpsc::Sender<Event>) {
+    while let Ok(event) = event::read() {
+        if sender.send(Event::Terminal(event)).is_err() {
+            return;
+        }
+    }
+    let _ = sender.send(Event::Shutdown);
+}
@@ -1,9 +1,15 @@
+extern crate mio;
+extern crate rhai;
+extern crate ring;
+extern crate rustls;
 #[macro_use]
-extern crate lazy_static;
+extern crate serde_json;
 
 pub mod chatserver;
 pub mod chatclient;
 pub mod message;
+pub mod tls;
+pub mod transport;
 
 pub fn test_me() {
     println!("testme!");
@@ -1,11 +1,17 @@
-use std::io::Result;
+use std::io::{Error, ErrorKind, Result};
+use serde_json::Value;
+
+/// The most a field can hold, in bytes, as its length goes in one byte.
+pub const MAX_FIELD: usize = 255;
 
 #[derive(Clone, Debug)]
 pub enum Message {
     InitUser(String),
     Login(String,String),
     Joined(String),
-    Failure(String),
+    /// What was refused and why, in words, and the id of the `Request`
+    /// that asked for it (empty if it wasn't one).
+    Failure(FailureCode,String,String),
     ListGroups(String),
     ListUsers(String, String),
     ChatMessage(String,String,String),
@@ -15,6 +21,125 @@ pub enum Message {
     KickUser(String,String,String),
     ConnectionTermination(String),
     TerminateProgram,
+    Session(String),
+    Resume(String,String),
+    /// A slash command, without the slash, from a user in a chat (empty
+    /// when in none). The server parses and answers it.
+    Command(String,String,String),
+    /// Any other message, tagged with an id the server echoes back in a
+    /// `Done` or `Failure`.
+    Request(String,Box<Message>),
+    /// The request with this id succeeded.
+    Done(String),
+}
+
+/// Why the server refused something. On the wire each is sent by name,
+/// next to an English description clients may show instead of their own.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum FailureCode {
+    NoSuchChat,
+    AlreadyInChat,
+    /// The user must be in a chat for that.
+    NotInChat,
+    ChatExists,
+    NoSuchUser,
+    /// The user isn't in the chat.
+    NoSuchMember,
+    NotAdmin,
+    NoSuchCommand,
+    /// The command's arguments were wrong; the description has its usage.
+    BadUsage,
+    RateLimited,
+    /// Muted for flooding; the description says for how long.
+    Muted,
+    ChatBusy,
+    Banned,
+    /// The username is in use on a linked server.
+    NameTaken,
+    ServerFull,
+    TooManyPending,
+    TooManyFromAddress,
+    /// The server couldn't read what was sent.
+    InvalidMessage,
+    /// A plugin on the server wouldn't allow it.
+    Blocked,
+    /// The chat name has spaces, commas, colons or control characters,
+    /// which IRC users couldn't type.
+    InvalidName,
+    /// Anything else: a custom command's own failure, or a code this
+    /// version doesn't know.
+    Other,
+}
+
+impl FailureCode {
+    /// Its name on the wire.
+    pub fn name(&self) -> &'static str {
+        use self::FailureCode::*;
+        match *self {
+            NoSuchChat => "no_such_chat",
+            AlreadyInChat => "already_in_chat",
+            NotInChat => "not_in_chat",
+            ChatExists => "chat_exists",
+            NoSuchUser => "no_such_user",
+            NoSuchMember => "no_such_member",
+            NotAdmin => "not_admin",
+            NoSuchCommand => "no_such_command",
+            BadUsage => "bad_usage",
+            RateLimited => "rate_limited",
+            Muted => "muted",
+            ChatBusy => "chat_busy",
+            Banned => "banned",
+            NameTaken => "name_taken",
+            ServerFull => "server_full",
+            TooManyPending => "too_many_pending",
+            TooManyFromAddress => "too_many_from_address",
+            InvalidMessage => "invalid_message",
+            Blocked => "blocked",
+            InvalidName => "invalid_name",
+            Other => "other",
+        }
+    }
+
+    /// The code named `name`, or `Other` if there is none.
+    pub fn from_name(name: &str) -> FailureCode {
+        use self::FailureCode::*;
+        [NoSuchChat, AlreadyInChat, NotInChat, ChatExists, NoSuchUser, NoSuchMember,
+         NotAdmin, NoSuchCommand, BadUsage, RateLimited, Muted, ChatBusy, Banned,
+         NameTaken, ServerFull, TooManyPending, TooManyFromAddress, InvalidMessage,
+         Blocked, InvalidName]
+            .iter()
+            .find(|c| c.name() == name)
+            .cloned()
+            .unwrap_or(Other)
+    }
+
+    /// What the server says when there is nothing more specific to add.
+    pub fn text(&self) -> &'static str {
+        use self::FailureCode::*;
+        match *self {
+            NoSuchChat => "No such chat",
+            AlreadyInChat => "Already in this chat",
+            NotInChat => "Must join a chat",
+            ChatExists => "Chat already exists",
+            NoSuchUser => "No such user",
+            NoSuchMember => "No such user in this chat",
+            NotAdmin => "Not admin in this chat",
+            NoSuchCommand => "No such command, see /help",
+            BadUsage => "Wrong arguments",
+            RateLimited => "Too many messages, slow down",
+            Muted => "Muted for flooding",
+            ChatBusy => "This chat is too busy, slow down",
+            Banned => "Banned from this server",
+            NameTaken => "Username taken on a linked server",
+            ServerFull => "Server is full",
+            TooManyPending => "Too many connections waiting, try again later",
+            TooManyFromAddress => "Too many connections from your address",
+            InvalidMessage => "Invalid message",
+            Blocked => "Not allowed on this server",
+            InvalidName => "Chat names can't have spaces, commas or colons",
+            Other => "Refused",
+        }
+    }
 }
 
 impl Message {
@@ -89,19 +214,54 @@ impl Message {
         Message::ConnectionTermination(username.into())
     }
 
-    pub fn failure<S: Into<String>>(contents: S) -> Message {
-        let contents = contents.into();
-        Message::Failure(contents)
+    pub fn resume<S: Into<String>>(username: S, token: S) -> Message {
+        Message::Resume(username.into(), token.into())
+    }
+
+    pub fn command<S: Into<String>>(username: S, chat_name: S, line: S) -> Message {
+        Message::Command(username.into(), chat_name.into(), line.into())
     }
 
-    pub fn into_bytes(&self) -> Vec<u8> {
+    pub fn failure<S: Into<String>>(code: FailureCode, contents: S) -> Message {
+        Message::Failure(code, contents.into(), String::new())
+    }
+
+    pub fn request<S: Into<String>>(id: S, message: Message) -> Message {
+        Message::Request(id.into(), Box::new(message))
+    }
+
+    /// The user the message claims to come from, for those that name one.
+    pub fn sender(&self) -> Option<&str> {
+        use message::Message::*;
+        match *self {
+            InitUser(ref u) | Login(ref u, _) | ListGroups(ref u) | ListUsers(ref u, _) |
+            ChatMessage(ref u, _, _) | PrivateMessage(ref u, _, _) | Logout(ref u, _) |
+            NewChat(ref u, _) | KickUser(ref u, _, _) | ConnectionTermination(ref u) |
+            Resume(ref u, _) | Command(ref u, _, _) => Some(u),
+            Request(_, ref inner) => inner.sender(),
+            Joined(_) | Failure(_, _, _) | TerminateProgram | Session(_) | Done(_) => None,
+        }
+    }
+
+    /// The message as a frame. Fails, rather than cut anything short, if
+    /// a field is longer than `MAX_FIELD` or, for the messages whose only
+    /// field runs to the end of the frame, holds a newline.
+    pub fn into_bytes(&self) -> Result<Vec<u8>> {
         use message::Message::*;
         let mut buffer: Vec<u8> = Vec::new();        
+        if let Request(ref id, ref inner) = *self {
+            // The inner message keeps its own framing.
+            buffer.push(0x11);
+            push_fields(&mut buffer, &[id])?;
+            buffer.extend(inner.into_bytes()?);
+            buffer.push(b'\n');
+            return Ok(buffer);
+        }
         buffer.push(match *self {
             InitUser(_) => 0x00,
             Login(_,_) => 0x01,
             Joined(_) => 0x02,
-            Failure(_) => 0x03,
+            Failure(_,_,_) => 0x03,
             ListGroups(_) => 0x04,
             ListUsers(_,_) => 0x05,
             ChatMessage(_,_,_) => 0x06,
@@ -111,102 +271,235 @@ impl Message {
             KickUser(_,_,_) => 0x0B,
             ConnectionTermination(_) => 0x0C,
             TerminateProgram => 0x0D,
+            Session(_) => 0x0E,
+            Resume(_,_) => 0x0F,
+            Command(_,_,_) => 0x10,
+            Request(_,_) => 0x11,
+            Done(_) => 0x12,
         });
         match *self {
-            InitUser(ref s)   | Joined(ref s) | Failure(ref s) |
-            ListGroups(ref s) | ConnectionTermination(ref s)
-                => buffer.extend_from_slice(s.as_bytes()),
-            Login(ref a, ref b)  | ListUsers(ref a, ref b) |
-            Logout(ref a, ref b) | NewChat(ref a, ref b)   => {
-                let a = a.as_bytes();
-                buffer.push(a.len() as u8);
-                buffer.extend_from_slice(a);
-                let b = b.as_bytes();
-                buffer.push(b.len() as u8);
-                buffer.extend_from_slice(b);
+            InitUser(ref s)   | Joined(ref s) |
+            ListGroups(ref s) | ConnectionTermination(ref s) |
+            Session(ref s) | Done(ref s) => {
+                if s.len() > MAX_FIELD || s.contains('\n') {
+                    return Err(Error::new(ErrorKind::InvalidInput,
+                                          "field too long or holding a newline"));
+                }
+                buffer.extend_from_slice(s.as_bytes())
             },
+            Login(ref a, ref b)  | ListUsers(ref a, ref b) |
+            Logout(ref a, ref b) | NewChat(ref a, ref b)   |
+            Resume(ref a, ref b) => push_fields(&mut buffer, &[a, b])?,
             ChatMessage(ref a, ref b, ref c) |
             PrivateMessage(ref a, ref b, ref c) |
-            KickUser(ref a, ref b, ref c) => {
-                let a = a.as_bytes();
-                buffer.push(a.len() as u8);
-                buffer.extend_from_slice(a);
-                let b = b.as_bytes();
-                buffer.push(b.len() as u8);
-                buffer.extend_from_slice(b);
-                let c = c.as_bytes();
-                buffer.push(c.len() as u8);
-                buffer.extend_from_slice(c);
-            }
-            TerminateProgram => (),
+            KickUser(ref a, ref b, ref c) | Command(ref a, ref b, ref c) =>
+                push_fields(&mut buffer, &[a, b, c])?,
+            Failure(code, ref b, ref c) => push_fields(&mut buffer, &[code.name(), b, c])?,
+            TerminateProgram | Request(_,_) => (),
         }
         buffer.push(b'\n');
-        buffer
+        Ok(buffer)
+    }
+
+    /// Fails if the message couldn't be sent to a native client, for
+    /// gateways to check what they take in; see `into_bytes`.
+    pub fn check_fields(&self) -> Result<()> {
+        self.into_bytes().map(|_| ())
     }
 
     pub fn from_bytes(bytes: &[u8]) -> Result<Message> {
-        if bytes.len() == 0 {
-            return Err(::std::io::Error::from(::std::io::ErrorKind::InvalidData));
-        };
         use message::Message::*;
-        let(a,b,c) = Message::get_parameters(bytes);
-        //println!("{:?} {:?} {:?}", a,b,c,);
-        let message_type = bytes[0];
+        let message_type = *bytes.first().ok_or_else(|| invalid("empty message"))?;
+        if message_type == 0x11 {
+            let id_len = *bytes.get(1).ok_or_else(|| invalid("truncated request"))? as usize;
+            let id = bytes.get(2..2 + id_len).ok_or_else(|| invalid("truncated request"))?;
+            let inner = bytes.get(2 + id_len..bytes.len() - 1)
+                .filter(|inner| inner.first() != Some(&0x11))
+                .ok_or_else(|| invalid("invalid request"))?;
+            return Ok(Request(text(id)?, Box::new(Message::from_bytes(inner)?)));
+        }
+        let mut fields = Message::get_parameters(bytes)?.into_iter();
+        let mut next = || fields.next().unwrap_or_default();
         match message_type {
-            0x00 => Ok(InitUser(a.unwrap())),
-            0x01 => Ok(Login(a.unwrap(),b.unwrap())),
-            0x02 => Ok(Joined(a.unwrap())),
-            0x03 => Ok(Failure(a.unwrap())),
-            0x04 => Ok(ListGroups(a.unwrap())),
-            0x05 => Ok(ListUsers(a.unwrap(),b.unwrap())),
-            0x06 => Ok(ChatMessage(a.unwrap(),b.unwrap(),c.unwrap())),
-            0x07 => Ok(PrivateMessage(a.unwrap(),b.unwrap(),c.unwrap())),
-            0x08 => Ok(Logout(a.unwrap(),b.unwrap())),
-            0x09 => Ok(NewChat(a.unwrap(),b.unwrap())),
-            0x0B => Ok(KickUser(a.unwrap(),b.unwrap(),c.unwrap())),
-            0x0C => Ok(ConnectionTermination(a.unwrap())),
+            0x00 => Ok(InitUser(next())),
+            0x01 => Ok(Login(next(), next())),
+            0x02 => Ok(Joined(next())),
+            0x03 => Ok(Failure(FailureCode::from_name(&next()), next(), next())),
+            0x04 => Ok(ListGroups(next())),
+            0x05 => Ok(ListUsers(next(), next())),
+            0x06 => Ok(ChatMessage(next(), next(), next())),
+            0x07 => Ok(PrivateMessage(next(), next(), next())),
+            0x08 => Ok(Logout(next(), next())),
+            0x09 => Ok(NewChat(next(), next())),
+            0x0B => Ok(KickUser(next(), next(), next())),
+            0x0C => Ok(ConnectionTermination(next())),
             0x0D => Ok(TerminateProgram),
-            _ => Err(::std::io::Error::from(::std::io::ErrorKind::InvalidData)),
+            0x0E => Ok(Session(next())),
+            0x0F => Ok(Resume(next(), next())),
+            0x10 => Ok(Command(next(), next(), next())),
+            0x12 => Ok(Done(next())),
+            _ => Err(invalid("unknown message type")),
         }
     }
 
-    fn get_parameters(bytes: &[u8])
-                      -> (Option<String>,Option<String>,Option<String>) {
-        let message_type = bytes[0];
-        match message_type {
-            0x00 | 0x02 | 0x03 | 0x04 | 0x0C => {
-                let len = bytes[1..].len();
-                (Some(String::from_utf8(bytes[1..len].to_vec()).unwrap()),
-                 None,
-                 None)
-            },
-            0x01 | 0x05 | 0x08 | 0x09 => {
-                let a_len = bytes[1]  as usize;
-                let pos = 2+a_len;
-                let a = &bytes[2..pos];
-                let b_len = bytes[pos]  as usize;
-                let pos = pos + 1;
-                let b = &bytes[pos..pos+b_len];
-                (Some(String::from_utf8(a.to_vec()).unwrap()),
-                 Some(String::from_utf8(b.to_vec()).unwrap()),
-                 None)
+    /// The same message as a JSON object, for clients that can't speak the
+    /// binary protocol. `type` names the variant; the other fields are
+    /// named after what they hold.
+    pub fn to_json(&self) -> String {
+        self.to_value().to_string()
+    }
+
+    fn to_value(&self) -> Value {
+        use message::Message::*;
+        match *self {
+            InitUser(ref u) => json!({"type": "init_user", "username": u}),
+            Login(ref u, ref c) => json!({"type": "login", "username": u, "chat": c}),
+            Joined(ref c) => json!({"type": "joined", "chat": c}),
+            Failure(code, ref r, ref id) =>
+                json!({"type": "failure", "code": code.name(), "reason": r, "request": id}),
+            ListGroups(ref u) => json!({"type": "list_groups", "username": u}),
+            ListUsers(ref u, ref c) => json!({"type": "list_users", "username": u, "chat": c}),
+            ChatMessage(ref u, ref c, ref m) =>
+                json!({"type": "chat_message", "username": u, "chat": c, "contents": m}),
+            PrivateMessage(ref f, ref t, ref m) =>
+                json!({"type": "private_message", "from": f, "to": t, "contents": m}),
+            Logout(ref u, ref c) => json!({"type": "logout", "username": u, "chat": c}),
+            NewChat(ref u, ref c) => json!({"type": "new_chat", "username": u, "chat": c}),
+            KickUser(ref u, ref c, ref t) =>
+                json!({"type": "kick_user", "username": u, "chat": c, "target": t}),
+            ConnectionTermination(ref u) =>
+                json!({"type": "connection_termination", "username": u}),
+            TerminateProgram => json!({"type": "terminate_program"}),
+            Session(ref t) => json!({"type": "session", "token": t}),
+            Resume(ref u, ref t) => json!({"type": "resume", "username": u, "token": t}),
+            Command(ref u, ref c, ref l) =>
+                json!({"type": "command", "username": u, "chat": c, "line": l}),
+            Request(ref id, ref m) => json!({"type": "request", "id": id, "message": m.to_value()}),
+            Done(ref id) => json!({"type": "done", "id": id}),
+        }
+    }
+
+    pub fn from_json(text: &str) -> Result<Message> {
+        let value: Value = ::serde_json::from_str(text)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+        Message::from_value(&value)
+    }
+
+    fn from_value(value: &Value) -> Result<Message> {
+        use message::Message::*;
+        let field = |name: &str| -> Result<String> {
+            match value.get(name).and_then(Value::as_str) {
+                Some(s) => Ok(s.to_owned()),
+                None => Err(Error::new(ErrorKind::InvalidData,
+                                    format!("missing string field `{}`", name))),
+            }
+        };
+        let kind = field("type")?;
+        Ok(match kind.as_str() {
+            "init_user" => InitUser(field("username")?),
+            "login" => Login(field("username")?, field("chat")?),
+            "joined" => Joined(field("chat")?),
+            "failure" => Failure(FailureCode::from_name(&field("code").unwrap_or_default()),
+                                 field("reason")?,
+                                 field("request").unwrap_or_default()),
+            "list_groups" => ListGroups(field("username")?),
+            "list_users" => ListUsers(field("username")?, field("chat")?),
+            "chat_message" => ChatMessage(field("username")?, field("chat")?, field("contents")?),
+            "private_message" => PrivateMessage(field("from")?, field("to")?, field("contents")?),
+            "logout" => Logout(field("username")?, field("chat")?),
+            "new_chat" => NewChat(field("username")?, field("chat")?),
+            "kick_user" => KickUser(field("username")?, field("chat")?, field("target")?),
+            "connection_termination" => ConnectionTermination(field("username")?),
+            "terminate_program" => TerminateProgram,
+            "session" => Session(field("token")?),
+            "resume" => Resume(field("username")?, field("token")?),
+            "command" => Command(field("username")?, field("chat")?, field("line")?),
+            "request" => match value.get("message").map(Message::from_value) {
+                Some(Ok(Request(_,_))) | None =>
+                    return Err(Error::new(ErrorKind::InvalidData, "invalid request")),
+                Some(inner) => Request(field("id")?, Box::new(inner?)),
             },
-            0x06 | 0x07 | 0x0B => {
-                let a_len = bytes[1] as usize;
-                let pos = 2+a_len;
-                let a = &bytes[2..pos];
-                let b_len = bytes[pos] as usize;
-                let pos = pos + 1;
-                let b = &bytes[pos..pos+b_len];
-                let pos = pos + b_len;
-                let c_len = bytes[pos] as usize;
-                let pos = pos + 1;
-                let c = &bytes[pos..pos+c_len];
-                (Some(String::from_utf8(a.to_vec()).unwrap()),
-                 Some(String::from_utf8(b.to_vec()).unwrap()),
-                 Some(String::from_utf8(c.to_vec()).unwrap()))               
+            "done" => Done(field("id")?),
+            _ => return Err(Error::new(ErrorKind::InvalidData,
+                                       format!("unknown message type `{}`", kind))),
+        })
+    }
+
+    /// Length of the first complete frame in `bytes`, newline included, or
+    /// `None` if it hasn't all arrived yet. Length-prefixed fields may well
+    /// contain a newline byte, so those are skipped rather than scanned.
+    pub fn frame_len(bytes: &[u8]) -> Option<usize> {
+        let fields = match *bytes.first()? {
+            0x11 => {
+                let pos = 2 + *bytes.get(1)? as usize;
+                let pos = pos + Message::frame_len(bytes.get(pos..)?)?;
+                return match pos < bytes.len() {
+                    true => Some(pos + 1),
+                    false => None,
+                };
             },
-            _ => (None, None, None)
+            0x01 | 0x05 | 0x08 | 0x09 | 0x0F => 2,
+            0x03 | 0x06 | 0x07 | 0x0B | 0x10 => 3,
+            0x0D => 0,
+            _ => return bytes.iter().position(|&b| b == b'\n').map(|p| p + 1),
+        };
+        let mut pos = 1;
+        for _ in 0..fields {
+            pos += 1 + *bytes.get(pos)? as usize;
+        }
+        if pos < bytes.len() {
+            Some(pos + 1)
+        } else {
+            None
+        }
+    }
+
+    /// The fields of a whole frame, newline included: the rest of the
+    /// frame for single field messages, each with its length in front
+    /// otherwise.
+    fn get_parameters(bytes: &[u8]) -> Result<Vec<String>> {
+        let body = bytes.get(1..bytes.len().saturating_sub(1))
+            .ok_or_else(|| invalid("truncated message"))?;
+        let count = match bytes[0] {
+            0x00 | 0x02 | 0x04 | 0x0C | 0x0E | 0x12 => return Ok(vec![text(body)?]),
+            0x01 | 0x05 | 0x08 | 0x09 | 0x0F => 2,
+            0x03 | 0x06 | 0x07 | 0x0B | 0x10 => 3,
+            _ => 0,
+        };
+        let mut rest = body;
+        let mut fields = Vec::with_capacity(count);
+        for _ in 0..count {
+            let (&len, after) = rest.split_first().ok_or_else(|| invalid("truncated message"))?;
+            if after.len() < len as usize {
+                return Err(invalid("truncated message"));
+            }
+            let (field, after) = after.split_at(len as usize);
+            fields.push(text(field)?);
+            rest = after;
+        }
+        Ok(fields)
+    }
+}
+
+fn invalid(reason: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, reason)
+}
+
+/// `bytes` as a string, if they are valid UTF-8.
+fn text(bytes: &[u8]) -> Result<String> {
+    String::from_utf8(bytes.to_vec()).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+}
+
+/// Writes each of `fields` with its length in front, failing on one too
+/// long for that.
+fn push_fields(buffer: &mut Vec<u8>, fields: &[&str]) -> Result<()> {
+    for field in fields {
+        if field.len() > MAX_FIELD {
+            return Err(Error::new(ErrorKind::InvalidInput,
+                                  format!("field longer than {} bytes", MAX_FIELD)));
         }
+        buffer.push(field.len() as u8);
+        buffer.extend_from_slice(field.as_bytes());
     }
+    Ok(())
 }
@@ -1,5 +1,94 @@
 extern crate chat_np1;
 
+use chat_np1::chatserver::{Scripts, ServerBuilder};
+use chat_np1::tls;
+
+fn usage() -> ! {
+    eprintln!("usage: server [ADDR] [--unix PATH] [--websocket ADDR] [--irc ADDR] [--admin ADDR] [--webhook [ROOM=]URL]... [--name NAME] [--link-listen ADDR] [--link ADDR]... [--scripts DIR] [--tls-cert FILE --tls-key FILE]");
+    std::process::exit(2);
+}
+
 fn main() {
-    chat_np1::chatserver::start_server("127.0.0.1:8080");
+    let mut addr = "127.0.0.1:8080".to_owned();
+    let mut cert = None;
+    let mut key = None;
+    let mut unix = None;
+    let mut websocket = None;
+    let mut irc = None;
+    let mut admin = None;
+    let mut webhooks = Vec::new();
+    let mut name = None;
+    let mut link_listen = None;
+    let mut links = Vec::new();
+    let mut scripts = None;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--tls-cert" => cert = Some(args.next().unwrap_or_else(|| usage())),
+            "--tls-key" => key = Some(args.next().unwrap_or_else(|| usage())),
+            "--unix" => unix = Some(args.next().unwrap_or_else(|| usage())),
+            "--websocket" => websocket = Some(args.next().unwrap_or_else(|| usage())),
+            "--irc" => irc = Some(args.next().unwrap_or_else(|| usage())),
+            "--admin" => admin = Some(args.next().unwrap_or_else(|| usage())),
+            "--webhook" => webhooks.push(args.next().unwrap_or_else(|| usage())),
+            "--name" => name = Some(args.next().unwrap_or_else(|| usage())),
+            "--link-listen" => link_listen = Some(args.next().unwrap_or_else(|| usage())),
+            "--link" => links.push(args.next().unwrap_or_else(|| usage())),
+            "--scripts" => scripts = Some(args.next().unwrap_or_else(|| usage())),
+            a if a.starts_with("--") => usage(),
+            _ => addr = arg,
+        }
+    }
+
+    let mut builder = ServerBuilder::new();
+    match (cert, key) {
+        (Some(cert), Some(key)) => {
+            let config = tls::server_config(cert, key)
+                .expect("Falha ao carregar certificado TLS");
+            builder = builder.tls(config);
+        },
+        (None, None) => (),
+        _ => usage(),
+    }
+    // Kept out of the arguments so it doesn't show up in `ps`.
+    if let Ok(token) = std::env::var("CHAT_ADMIN_TOKEN") {
+        builder = builder.admin_token(token);
+    }
+    if let Ok(secret) = std::env::var("CHAT_LINK_SECRET") {
+        builder = builder.link_secret(secret);
+    }
+    if let Some(name) = name {
+        builder = builder.server_name(name);
+    }
+    for addr in links {
+        builder = builder.link(addr);
+    }
+    for hook in webhooks {
+        // Without a room, the webhook gets the events of every room.
+        builder = match hook.split_once('=') {
+            Some((room, url)) if !hook.starts_with("http://") => builder.webhook(Some(room), url),
+            _ => builder.webhook(None, hook),
+        };
+    }
+    if let Some(dir) = scripts {
+        builder = builder.plugin(Scripts::load(dir).expect("Falha ao carregar scripts"));
+    }
+    let mut server = builder.build().expect("Falha ao iniciar o servidor");
+    server.listen(addr).expect("Falha ao criar listener nesse endereço");
+    if let Some(path) = unix {
+        server.listen_unix(path).expect("Falha ao criar socket Unix");
+    }
+    if let Some(addr) = websocket {
+        server.listen_websocket(addr).expect("Falha ao criar listener WebSocket");
+    }
+    if let Some(addr) = irc {
+        server.listen_irc(addr).expect("Falha ao criar listener IRC");
+    }
+    if let Some(addr) = admin {
+        server.listen_admin(addr).expect("Falha ao criar listener de administração");
+    }
+    if let Some(addr) = link_listen {
+        server.listen_links(addr).expect("Falha ao criar listener de servidores");
+    }
+    server.run().expect("Event loop failed");
 }
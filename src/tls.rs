@@ -0,0 +1,246 @@
+//! Optional TLS transport, shared by the server (certificate and key) and
+//! the client (a CA to trust or a pinned certificate fingerprint).
+
+use std::convert::TryFrom;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use ring::digest;
+use rustls::{self, ClientConfig, ClientConnection, DigitallySignedStruct, RootCertStore,
+             ServerConfig, SignatureScheme};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{self, CryptoProvider};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::pki_types::pem::PemObject;
+use message::Message;
+use transport::{Frames, Transport};
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(crypto::ring::default_provider())
+}
+
+/// A certificate, key or setting that can't be used.
+fn tls_error<E: ::std::fmt::Display>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, e.to_string())
+}
+
+/// A failed handshake or a broken session, which ends the connection.
+/// Never `InvalidData`: `Transport::recv` keeps that for a frame that
+/// doesn't parse, after which reading goes on.
+fn session_error<E: ::std::fmt::Display>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::ConnectionAborted, e.to_string())
+}
+
+/// Loads a PEM certificate chain and private key for the server.
+pub fn server_config<P: AsRef<Path>>(cert_file: P, key_file: P) -> io::Result<Arc<ServerConfig>> {
+    let certs = CertificateDer::pem_file_iter(cert_file)
+        .map_err(tls_error)?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(tls_error)?;
+    let key = PrivateKeyDer::from_pem_file(key_file).map_err(tls_error)?;
+    let config = ServerConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()
+        .map_err(tls_error)?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(tls_error)?;
+    Ok(Arc::new(config))
+}
+
+/// How the client decides to trust the server.
+#[derive(Clone, Debug)]
+pub enum ServerTrust {
+    /// Certificates in this PEM file are trusted as roots.
+    CaFile(String),
+    /// Only a certificate whose SHA-256 matches this hex fingerprint is
+    /// accepted, regardless of who signed it or which name it carries.
+    Fingerprint(String),
+}
+
+/// Everything the client needs to open TLS connections to one server.
+#[derive(Clone, Debug)]
+pub struct ClientTls {
+    config: Arc<ClientConfig>,
+    server_name: ServerName<'static>,
+}
+
+impl ClientTls {
+    /// `server_name` is checked against the certificate when trusting a CA.
+    pub fn new(trust: &ServerTrust, server_name: &str) -> io::Result<ClientTls> {
+        let builder = ClientConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()
+            .map_err(tls_error)?;
+        let config = match *trust {
+            ServerTrust::CaFile(ref path) => {
+                let mut roots = RootCertStore::empty();
+                for cert in CertificateDer::pem_file_iter(path).map_err(tls_error)? {
+                    roots.add(cert.map_err(tls_error)?).map_err(tls_error)?;
+                }
+                builder.with_root_certificates(roots).with_no_client_auth()
+            },
+            ServerTrust::Fingerprint(ref hex) => {
+                let verifier = PinnedCertificate::new(hex)?;
+                builder.dangerous()
+                    .with_custom_certificate_verifier(Arc::new(verifier))
+                    .with_no_client_auth()
+            },
+        };
+        let server_name = ServerName::try_from(server_name.to_owned())
+            .map_err(tls_error)?;
+        Ok(ClientTls {
+            config: Arc::new(config),
+            server_name,
+        })
+    }
+
+    /// Wraps a connected socket. The handshake happens on the first send
+    /// or receive.
+    pub fn wrap(&self, socket: TcpStream) -> io::Result<TlsTransport> {
+        let conn = ClientConnection::new(self.config.clone(), self.server_name.clone())
+            .map_err(tls_error)?;
+        Ok(TlsTransport {
+            conn: Arc::new(Mutex::new(conn)),
+            socket,
+            frames: Default::default(),
+        })
+    }
+}
+
+/// Hex SHA-256 of a DER certificate, as accepted by `ServerTrust::Fingerprint`.
+pub fn fingerprint(cert: &[u8]) -> String {
+    digest::digest(&digest::SHA256, cert).as_ref().iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+#[derive(Debug)]
+struct PinnedCertificate {
+    fingerprint: String,
+    provider: Arc<CryptoProvider>,
+}
+
+impl PinnedCertificate {
+    fn new(hex: &str) -> io::Result<PinnedCertificate> {
+        let fingerprint: String = hex.chars()
+            .filter(|c| *c != ':')
+            .collect::<String>()
+            .to_lowercase();
+        if fingerprint.len() != 64 || !fingerprint.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(tls_error("fingerprint must be a hex SHA-256"));
+        }
+        Ok(PinnedCertificate {
+            fingerprint,
+            provider: provider(),
+        })
+    }
+}
+
+impl ServerCertVerifier for PinnedCertificate {
+    fn verify_server_cert(&self,
+                          end_entity: &CertificateDer,
+                          _intermediates: &[CertificateDer],
+                          _server_name: &ServerName,
+                          _ocsp_response: &[u8],
+                          _now: UnixTime) -> Result<ServerCertVerified, rustls::Error> {
+        if fingerprint(end_entity) == self.fingerprint {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::General("certificate fingerprint mismatch".into()))
+        }
+    }
+
+    fn verify_tls12_signature(&self,
+                              message: &[u8],
+                              cert: &CertificateDer,
+                              dss: &DigitallySignedStruct)
+                              -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls12_signature(message, cert, dss,
+                                       &self.provider.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(&self,
+                              message: &[u8],
+                              cert: &CertificateDer,
+                              dss: &DigitallySignedStruct)
+                              -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls13_signature(message, cert, dss,
+                                       &self.provider.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+/// Sends out whatever TLS records the connection has pending.
+fn write_records(conn: &mut ClientConnection, socket: &mut TcpStream) -> io::Result<()> {
+    while conn.wants_write() {
+        conn.write_tls(socket)?;
+    }
+    Ok(())
+}
+
+/// A client TLS stream. Handles share the TLS state, but receiving blocks
+/// on the socket without holding it, so sending stays possible meanwhile.
+pub struct TlsTransport {
+    conn: Arc<Mutex<ClientConnection>>,
+    socket: TcpStream,
+    frames: Frames,
+}
+
+impl Transport for TlsTransport {
+    fn send(&mut self, m: &Message) -> io::Result<()> {
+        let bytes = m.into_bytes()?;
+        let mut conn = self.conn.lock().unwrap();
+        conn.writer().write_all(&bytes).map_err(session_error)?;
+        write_records(&mut conn, &mut self.socket)
+    }
+
+    fn recv(&mut self) -> io::Result<Option<Message>> {
+        let mut plain = [0u8; 4096];
+        let mut raw = [0u8; 4096];
+        loop {
+            if let Some(m) = self.frames.next_message() {
+                return m.map(Some);
+            }
+            {
+                let mut conn = self.conn.lock().unwrap();
+                match conn.reader().read(&mut plain) {
+                    Ok(0) => return Ok(None),
+                    Ok(n) => {
+                        self.frames.extend(&plain[..n]);
+                        continue;
+                    },
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => (),
+                    Err(e) => return Err(session_error(e)),
+                }
+            }
+            let n = match self.socket.read(&mut raw) {
+                Ok(0) => return Ok(None),
+                Ok(n) => n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            let mut conn = self.conn.lock().unwrap();
+            let mut records = &raw[..n];
+            while !records.is_empty() {
+                conn.read_tls(&mut records).map_err(session_error)?;
+                conn.process_new_packets().map_err(session_error)?;
+            }
+            write_records(&mut conn, &mut self.socket)?;
+        }
+    }
+
+    fn try_clone(&self) -> io::Result<Box<dyn Transport>> {
+        Ok(Box::new(TlsTransport {
+            conn: self.conn.clone(),
+            socket: self.socket.try_clone()?,
+            frames: Default::default(),
+        }))
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        self.socket.shutdown(Shutdown::Both)
+    }
+}
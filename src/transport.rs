@@ -0,0 +1,339 @@
+//! Framed, bidirectional message streams, independent of what carries the
+//! bytes. The client talks to the server through a `Transport`; the server
+//! side of each kind lives in `chatserver::stream`, since it has to fit in
+//! the non-blocking event loop.
+
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream, ToSocketAddrs};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex};
+use message::Message;
+use tls::ClientTls;
+
+/// One end of a message stream.
+pub trait Transport: Send {
+    fn send(&mut self, m: &Message) -> io::Result<()>;
+
+    /// Blocks until the next message arrives. `None` means the other end
+    /// hung up; a frame that doesn't parse is an `InvalidData` error and
+    /// the stream stays usable. Any other error ends the stream.
+    fn recv(&mut self) -> io::Result<Option<Message>>;
+
+    /// Another handle to the same stream, so one thread can block on
+    /// `recv` while others send. Only one of them should receive.
+    fn try_clone(&self) -> io::Result<Box<dyn Transport>>;
+
+    /// Closes the stream both ways, waking up anyone blocked on `recv`.
+    fn shutdown(&self) -> io::Result<()>;
+}
+
+/// Opens new transports to the same place, for the first connection and
+/// every reconnection after it.
+pub trait Connector: Send {
+    fn connect(&self) -> io::Result<Box<dyn Transport>>;
+}
+
+/// More than any frame takes: three fields of 255 bytes, and a request's
+/// id around them.
+const MAX_FRAME: usize = 2048;
+
+/// Bytes received but not yet split into messages.
+#[derive(Default)]
+pub struct Frames {
+    buf: Vec<u8>,
+}
+
+impl Frames {
+    pub fn extend(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    /// Takes the next complete message out of the buffer, if there is one.
+    /// A frame that doesn't parse is an `InvalidData` error. Once more has
+    /// come than a frame could hold without one ending, nothing after it
+    /// can be told apart: it is thrown away, as an `InvalidInput` error.
+    pub fn next_message(&mut self) -> Option<io::Result<Message>> {
+        let len = match Message::frame_len(&self.buf) {
+            Some(len) => len,
+            None if self.buf.len() > MAX_FRAME => {
+                self.buf.clear();
+                return Some(Err(io::Error::new(io::ErrorKind::InvalidInput, "frame too long")));
+            },
+            None => return None,
+        };
+        let frame: Vec<u8> = self.buf.drain(..len).collect();
+        Some(Message::from_bytes(&frame))
+    }
+}
+
+/// A byte stream a `StreamTransport` can run over.
+pub trait Socket: Read + Write + Send + Sized + 'static {
+    fn try_clone(&self) -> io::Result<Self>;
+    fn shutdown(&self) -> io::Result<()>;
+}
+
+impl Socket for TcpStream {
+    fn try_clone(&self) -> io::Result<TcpStream> {
+        TcpStream::try_clone(self)
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        TcpStream::shutdown(self, Shutdown::Both)
+    }
+}
+
+#[cfg(unix)]
+impl Socket for UnixStream {
+    fn try_clone(&self) -> io::Result<UnixStream> {
+        UnixStream::try_clone(self)
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        UnixStream::shutdown(self, Shutdown::Both)
+    }
+}
+
+/// Messages written straight onto a blocking socket.
+pub struct StreamTransport<S> {
+    socket: S,
+    frames: Frames,
+}
+
+pub type TcpTransport = StreamTransport<TcpStream>;
+#[cfg(unix)]
+pub type UnixTransport = StreamTransport<UnixStream>;
+
+impl<S: Socket> StreamTransport<S> {
+    pub fn new(socket: S) -> StreamTransport<S> {
+        StreamTransport {
+            socket,
+            frames: Default::default(),
+        }
+    }
+}
+
+impl<S: Socket> Transport for StreamTransport<S> {
+    fn send(&mut self, m: &Message) -> io::Result<()> {
+        self.socket.write_all(&m.into_bytes()?)?;
+        self.socket.flush()
+    }
+
+    fn recv(&mut self) -> io::Result<Option<Message>> {
+        let mut chunk = [0u8; 4096];
+        loop {
+            if let Some(m) = self.frames.next_message() {
+                return m.map(Some);
+            }
+            match self.socket.read(&mut chunk) {
+                Ok(0) => return Ok(None),
+                Ok(n) => self.frames.extend(&chunk[..n]),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn try_clone(&self) -> io::Result<Box<dyn Transport>> {
+        Ok(Box::new(StreamTransport::new(self.socket.try_clone()?)))
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        self.socket.shutdown()
+    }
+}
+
+/// Connects over TCP, optionally wrapped in TLS.
+pub struct TcpConnector {
+    addrs: Vec<SocketAddr>,
+    tls: Option<ClientTls>,
+}
+
+impl TcpConnector {
+    pub fn new<A: ToSocketAddrs>(addr: A, tls: Option<ClientTls>) -> io::Result<TcpConnector> {
+        Ok(TcpConnector {
+            addrs: addr.to_socket_addrs()?.collect(),
+            tls,
+        })
+    }
+}
+
+impl Connector for TcpConnector {
+    fn connect(&self) -> io::Result<Box<dyn Transport>> {
+        let socket = TcpStream::connect(&self.addrs[..])?;
+        Ok(match self.tls {
+            Some(ref tls) => Box::new(tls.wrap(socket)?),
+            None => Box::new(TcpTransport::new(socket)),
+        })
+    }
+}
+
+/// Connects to a server listening on a Unix domain socket.
+#[cfg(unix)]
+pub struct UnixConnector {
+    path: PathBuf,
+}
+
+#[cfg(unix)]
+impl UnixConnector {
+    pub fn new<P: AsRef<Path>>(path: P) -> UnixConnector {
+        UnixConnector {
+            path: path.as_ref().to_owned(),
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Connector for UnixConnector {
+    fn connect(&self) -> io::Result<Box<dyn Transport>> {
+        Ok(Box::new(UnixTransport::new(UnixStream::connect(&self.path)?)))
+    }
+}
+
+/// Bytes going one way between the two ends of a channel pair.
+#[derive(Default)]
+struct Pipe {
+    state: Mutex<PipeState>,
+    readable: Condvar,
+}
+
+#[derive(Default)]
+struct PipeState {
+    data: VecDeque<u8>,
+    closed: bool,
+    /// Called whenever there is something new to read.
+    notify: Option<Box<dyn Fn() + Send>>,
+}
+
+impl Pipe {
+    fn write(&self, bytes: &[u8]) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        state.data.extend(bytes);
+        self.wake(&state);
+        Ok(())
+    }
+
+    fn read(&self, out: &mut [u8], block: bool) -> io::Result<usize> {
+        let mut state = self.state.lock().unwrap();
+        while state.data.is_empty() && !state.closed {
+            if !block {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            state = self.readable.wait(state).unwrap();
+        }
+        let n = ::std::cmp::min(out.len(), state.data.len());
+        for (o, b) in out.iter_mut().zip(state.data.drain(..n)) {
+            *o = b;
+        }
+        Ok(n)
+    }
+
+    fn close(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.closed = true;
+        self.wake(&state);
+    }
+
+    fn wake(&self, state: &PipeState) {
+        self.readable.notify_all();
+        if let Some(ref notify) = state.notify {
+            notify();
+        }
+    }
+}
+
+/// Both pipes of one end. Hanging up happens when the last handle to the
+/// end goes away.
+struct End {
+    incoming: Arc<Pipe>,
+    outgoing: Arc<Pipe>,
+}
+
+impl Drop for End {
+    fn drop(&mut self) {
+        self.incoming.close();
+        self.outgoing.close();
+    }
+}
+
+/// One end of an in-process channel pair. See `pair`.
+pub struct ChannelTransport {
+    end: Arc<End>,
+    frames: Frames,
+}
+
+/// Two connected in-process transports: whatever one sends, the other
+/// receives. No sockets involved.
+pub fn pair() -> (ChannelTransport, ChannelTransport) {
+    let a: Arc<Pipe> = Default::default();
+    let b: Arc<Pipe> = Default::default();
+    let end = |incoming: &Arc<Pipe>, outgoing: &Arc<Pipe>| ChannelTransport {
+        end: Arc::new(End {
+            incoming: incoming.clone(),
+            outgoing: outgoing.clone(),
+        }),
+        frames: Default::default(),
+    };
+    (end(&a, &b), end(&b, &a))
+}
+
+impl ChannelTransport {
+    /// Calls `notify` whenever there is something new to read (or the other
+    /// end hung up), and right away if that is already the case. For the
+    /// server's event loop, which can't block on `recv`.
+    pub fn on_readable<F: Fn() + Send + 'static>(&self, notify: F) {
+        let mut state = self.end.incoming.state.lock().unwrap();
+        if !state.data.is_empty() || state.closed {
+            notify();
+        }
+        state.notify = Some(Box::new(notify));
+    }
+
+    /// Reads raw bytes without blocking; `WouldBlock` when there are none.
+    pub fn try_read(&self, out: &mut [u8]) -> io::Result<usize> {
+        self.end.incoming.read(out, false)
+    }
+
+    /// Writes raw bytes; never blocks.
+    pub fn write_bytes(&self, bytes: &[u8]) -> io::Result<()> {
+        self.end.outgoing.write(bytes)
+    }
+}
+
+impl Transport for ChannelTransport {
+    fn send(&mut self, m: &Message) -> io::Result<()> {
+        self.write_bytes(&m.into_bytes()?)
+    }
+
+    fn recv(&mut self) -> io::Result<Option<Message>> {
+        let mut chunk = [0u8; 4096];
+        loop {
+            if let Some(m) = self.frames.next_message() {
+                return m.map(Some);
+            }
+            match self.end.incoming.read(&mut chunk, true)? {
+                0 => return Ok(None),
+                n => self.frames.extend(&chunk[..n]),
+            }
+        }
+    }
+
+    fn try_clone(&self) -> io::Result<Box<dyn Transport>> {
+        Ok(Box::new(ChannelTransport {
+            end: self.end.clone(),
+            frames: Default::default(),
+        }))
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        self.end.incoming.close();
+        self.end.outgoing.close();
+        Ok(())
+    }
+}
@@ -0,0 +1,256 @@
+//! The server end to end, over in-process connections: no ports, no
+//! sockets.
+
+extern crate chat_np1;
+
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+use chat_np1::chatserver::{Action, Context, LocalConnector, Plugin, ServerBuilder};
+use chat_np1::message::{FailureCode, Message};
+use chat_np1::transport::{Connector, Transport};
+
+/// How long to wait for an answer before calling the test failed.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Runs a server on a thread of its own until the test ends.
+fn start(builder: ServerBuilder) -> LocalConnector {
+    let (sender, connector) = mpsc::channel();
+    thread::spawn(move || {
+        let mut server = builder.build().expect("server failed to start");
+        sender.send(server.local_connector()).unwrap();
+        server.run().expect("server failed");
+    });
+    connector.recv().unwrap()
+}
+
+/// A native client, reading on a thread of its own.
+struct Client {
+    transport: Box<dyn Transport>,
+    inbox: Receiver<Message>,
+}
+
+impl Client {
+    fn open(transport: Box<dyn Transport>) -> Client {
+        let mut reader = transport.try_clone().unwrap();
+        let (sender, inbox) = mpsc::channel();
+        thread::spawn(move || {
+            while let Ok(Some(m)) = reader.recv() {
+                if sender.send(m).is_err() {
+                    break;
+                }
+            }
+        });
+        Client { transport, inbox }
+    }
+
+    /// Connects and logs in as `username`.
+    fn login(connector: &LocalConnector, username: &str) -> Client {
+        let mut client = Client::open(connector.connect().unwrap());
+        client.send(Message::init_user(username));
+        client.expect("a session", |m| matches!(*m, Message::Session(_)));
+        client
+    }
+
+    fn send(&mut self, m: Message) {
+        self.transport.send(&m).unwrap();
+    }
+
+    /// Joins `chat` and waits until it's done.
+    fn join(&mut self, username: &str, chat: &str) {
+        self.send(Message::login(username, chat));
+        self.expect("to join", |m| matches!(*m, Message::Joined(ref c) if c == chat));
+    }
+
+    /// Waits for a message `wanted` accepts, skipping any other.
+    fn expect<F: Fn(&Message) -> bool>(&self, what: &str, wanted: F) -> Message {
+        let deadline = Instant::now() + TIMEOUT;
+        loop {
+            match self.inbox.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                Ok(m) if wanted(&m) => return m,
+                Ok(_) => (),
+                Err(_) => panic!("expected {}", what),
+            }
+        }
+    }
+
+    /// Waits for the server to hang up.
+    fn expect_closed(&self) {
+        let deadline = Instant::now() + TIMEOUT;
+        loop {
+            match self.inbox.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                Ok(_) => (),
+                Err(RecvTimeoutError::Disconnected) => return,
+                Err(RecvTimeoutError::Timeout) => panic!("expected the connection to close"),
+            }
+        }
+    }
+}
+
+fn failure(code: FailureCode, request: &str) -> impl Fn(&Message) -> bool + '_ {
+    move |m| matches!(*m, Message::Failure(c, _, ref id) if c == code && id == request)
+}
+
+#[test]
+fn chat_between_users() {
+    let server = start(ServerBuilder::new());
+    let mut ana = Client::login(&server, "ana");
+    let mut bob = Client::login(&server, "bob");
+    ana.join("ana", "Chat1");
+    bob.join("bob", "Chat1");
+    ana.send(Message::chat_message("ana", "Chat1", "hi bob"));
+    bob.expect("ana's message", |m| {
+        matches!(*m, Message::ChatMessage(ref u, ref c, ref t)
+                 if u == "ana" && c == "Chat1" && t == "hi bob")
+    });
+    bob.send(Message::private_message("bob", "ana", "psst"));
+    ana.expect("bob's private message", |m| {
+        matches!(*m, Message::PrivateMessage(ref f, _, ref t) if f == "bob" && t == "psst")
+    });
+}
+
+#[test]
+fn requests_get_done_or_failure() {
+    let server = start(ServerBuilder::new());
+    let mut ana = Client::login(&server, "ana");
+    ana.send(Message::request("1", Message::new_chat("ana", "Room")));
+    ana.expect("done", |m| matches!(*m, Message::Done(ref id) if id == "1"));
+    ana.send(Message::request("2", Message::new_chat("ana", "Room")));
+    ana.expect("chat_exists", failure(FailureCode::ChatExists, "2"));
+    ana.send(Message::request("3", Message::login("ana", "Nowhere")));
+    ana.expect("no_such_chat", failure(FailureCode::NoSuchChat, "3"));
+    ana.send(Message::request("4", Message::command("ana", "Room", "nonsense")));
+    ana.expect("no_such_command", failure(FailureCode::NoSuchCommand, "4"));
+    ana.send(Message::request("5", Message::command("ana", "Room", "help JOIN")));
+    ana.expect("done", |m| matches!(*m, Message::Done(ref id) if id == "5"));
+    ana.send(Message::request("6", Message::new_chat("ana", "a\r\nPRIVMSG #Room :hi")));
+    ana.expect("invalid_name", failure(FailureCode::InvalidName, "6"));
+}
+
+#[test]
+fn malformed_frames_get_a_failure() {
+    let server = start(ServerBuilder::new());
+    let raw = server.connect_channel().unwrap();
+    let mut client = Client::open(raw.try_clone().unwrap());
+    // An InitUser whose name isn't UTF-8.
+    raw.write_bytes(b"\x00\xff\xfe\n").unwrap();
+    client.expect("invalid_message", failure(FailureCode::InvalidMessage, ""));
+    // The connection, and the server, are still fine.
+    client.send(Message::init_user("ana"));
+    client.expect("a session", |m| matches!(*m, Message::Session(_)));
+    let mut bob = Client::login(&server, "bob");
+    bob.join("bob", "Chat1");
+}
+
+#[test]
+fn endless_frame_closes_only_that_connection() {
+    let server = start(ServerBuilder::new());
+    let mut ana = Client::login(&server, "ana");
+    let raw = server.connect_channel().unwrap();
+    let client = Client::open(raw.try_clone().unwrap());
+    let mut endless = vec![0x00];
+    endless.extend(vec![b'x'; 4096]);
+    raw.write_bytes(&endless).unwrap();
+    client.expect("invalid_message", failure(FailureCode::InvalidMessage, ""));
+    client.expect_closed();
+    ana.join("ana", "Chat1");
+}
+
+#[test]
+fn messages_naming_someone_else_are_refused() {
+    let server = start(ServerBuilder::new());
+    let mut admin = Client::login(&server, "admin");
+    let mut eve = Client::login(&server, "eve");
+    admin.send(Message::new_chat("admin", "Room"));
+    admin.expect("to join", |m| matches!(*m, Message::Joined(_)));
+    eve.join("eve", "Room");
+    eve.send(Message::request("1", Message::command("admin", "Room", "kick eve")));
+    eve.expect("invalid_message", failure(FailureCode::InvalidMessage, "1"));
+    eve.send(Message::chat_message("eve", "Room", "still here"));
+    admin.expect("eve's message", |m| {
+        matches!(*m, Message::ChatMessage(ref u, _, _) if u == "eve")
+    });
+}
+
+#[test]
+fn muted_users_cant_message_through_commands() {
+    let server = start(ServerBuilder::new());
+    let _bob = Client::login(&server, "bob");
+    let mut eve = Client::login(&server, "eve");
+    eve.join("eve", "Chat1");
+    for i in 0..15 {
+        eve.send(Message::chat_message("eve", "Chat1", &format!("flood {}", i)));
+    }
+    eve.expect("to be muted", |m| matches!(*m, Message::Failure(FailureCode::Muted, _, _)));
+    eve.send(Message::request("1", Message::command("eve", "Chat1", "msg bob hi")));
+    eve.expect("muted", failure(FailureCode::Muted, "1"));
+}
+
+#[test]
+fn reconnecting_doesnt_lift_a_mute() {
+    let server = start(ServerBuilder::new());
+    let mut eve = Client::login(&server, "eve");
+    eve.join("eve", "Chat1");
+    for i in 0..15 {
+        eve.send(Message::chat_message("eve", "Chat1", &format!("flood {}", i)));
+    }
+    eve.expect("to be muted", |m| matches!(*m, Message::Failure(FailureCode::Muted, _, _)));
+    drop(eve);
+    // A new session, still in the old one's chat.
+    let mut eve = Client::login(&server, "eve");
+    eve.send(Message::request("1", Message::chat_message("eve", "Chat1", "I'm back")));
+    eve.expect("still muted", failure(FailureCode::Muted, "1"));
+}
+
+/// Keeps everyone but bob out of the back rooms, and talk of them out of
+/// the chats.
+struct Guard;
+
+impl Plugin for Guard {
+    fn on_join(&mut self, _ctx: &mut Context, user: &str, room: &str) -> Action {
+        match room.starts_with("Back") && user != "bob" {
+            true => Action::Block,
+            false => Action::Continue,
+        }
+    }
+
+    fn on_chat(&mut self, _ctx: &mut Context, _user: &str, _room: &str,
+               contents: &mut String) -> Action {
+        match contents.contains("Back") {
+            true => Action::Block,
+            false => Action::Continue,
+        }
+    }
+}
+
+#[test]
+fn blocked_requests_fail_and_change_nothing() {
+    let server = start(ServerBuilder::new().plugin(Guard));
+    let mut ana = Client::login(&server, "ana");
+    let mut bob = Client::login(&server, "bob");
+    ana.join("ana", "Chat1");
+    bob.send(Message::new_chat("bob", "Back"));
+    bob.expect("to join", |m| matches!(*m, Message::Joined(ref c) if c == "Back"));
+    ana.send(Message::request("1", Message::login("ana", "Back")));
+    ana.expect("blocked", failure(FailureCode::Blocked, "1"));
+    ana.send(Message::request("2", Message::new_chat("ana", "Backroom")));
+    ana.expect("blocked", failure(FailureCode::Blocked, "2"));
+    ana.send(Message::request("3", Message::command("ana", "Chat1", "join Back")));
+    ana.expect("blocked", failure(FailureCode::Blocked, "3"));
+    bob.join("bob", "Chat1");
+    ana.send(Message::request("4", Message::chat_message("ana", "Chat1", "to the Back")));
+    ana.expect("blocked", failure(FailureCode::Blocked, "4"));
+    // Without a request, a plugin blocking is no failure: a bot's answer
+    // may be all there is to it.
+    ana.send(Message::chat_message("ana", "Chat1", "to the Back"));
+    ana.send(Message::request("5", Message::ListGroups("ana".into())));
+    let answer = ana.expect("an answer", |m| {
+        matches!(*m, Message::Done(_) | Message::Failure(..))
+    });
+    assert!(matches!(answer, Message::Done(ref id) if id == "5"), "got {:?}", answer);
+    // Still in the chat the blocked joins would have taken ana out of.
+    bob.send(Message::chat_message("bob", "Chat1", "still there?"));
+    ana.expect("bob's message", |m| {
+        matches!(*m, Message::ChatMessage(ref u, _, ref t) if u == "bob" && t == "still there?")
+    });
+}